@@ -1,14 +1,16 @@
 use anyhow::Result;
+use clap::{Command, arg, value_parser};
 use hex;
-use std::env;
 use std::fs;
 use std::fs::File;
 use std::io::{self, BufRead, Read, Write};
 use std::mem;
+use std::path::PathBuf;
 
 // ECDSA imports
-use p384::ecdsa::signature::hazmat::PrehashSigner;
-use p384::ecdsa::{Signature, SigningKey};
+use p384::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p384::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p384::pkcs8::DecodePublicKey;
 use sec1::DecodeEcPrivateKey;
 
 // LMS imports
@@ -17,11 +19,31 @@ use caliptra_image_crypto::OsslCrypto;
 use caliptra_image_gen::ImageGeneratorCrypto;
 
 use caliptra_image_types::{
-    ImageDigest, ImageLmsPrivKey, ImageLmsSignature, SHA384_DIGEST_WORD_SIZE,
+    ImageDigest, ImageLmsPrivKey, ImageLmsPublicKey, ImageLmsSignature, SHA384_DIGEST_WORD_SIZE,
 };
+use zerocopy::{FromBytes, IntoBytes};
+
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+
+/// Where the signing key lives
+enum KeySource {
+    File(String),
+    #[cfg(feature = "pkcs11")]
+    Pkcs11(pkcs11::TokenKey),
+}
+
+/// ECDSA: sign a SHA384 digest with the file or token backend.
+fn ecc_sign_digest(digest: &[u8], key: &KeySource) -> Result<Signature> {
+    match key {
+        KeySource::File(key_path) => ecc_sign_digest_by_pem(digest, key_path),
+        #[cfg(feature = "pkcs11")]
+        KeySource::Pkcs11(token_key) => pkcs11::ecc_sign_digest(digest, token_key),
+    }
+}
 
 /// ECDSA: sign a SHA384 digest using an ECDSA-P384 private key.
-fn ecc_sign_digest(digest: &[u8], key_path: &str) -> Result<Signature> {
+fn ecc_sign_digest_by_pem(digest: &[u8], key_path: &str) -> Result<Signature> {
     let pem = fs::read(key_path)?;
     let signing_key = SigningKey::from_sec1_pem(std::str::from_utf8(&pem)?)?;
     let sig = signing_key
@@ -45,21 +67,15 @@ fn read_lms_privkey_from_file(path: &str) -> anyhow::Result<ImageLmsPrivKey> {
         );
     }
 
-    // use unsafe directly reinterpret bytes to struct
-    let priv_key: ImageLmsPrivKey =
-        unsafe { std::ptr::read(buf.as_ptr() as *const ImageLmsPrivKey) };
+    // reinterpret bytes to struct, zerocopy handles the alignment
+    let priv_key = ImageLmsPrivKey::read_from_bytes(&buf)
+        .map_err(|_| anyhow::anyhow!("Failed to parse LMS private key: {}", path))?;
 
     Ok(priv_key)
 }
 
-/// LMS: sign a digest using LMS private key
-fn lms_sign_digest(digest: &[u8], key_path: &str) -> Result<ImageLmsSignature> {
-    // load LMS private key (binary format)
-    // eprintln!("Loading LMS private key from: {}", key_path);
-
-    let priv_key = read_lms_privkey_from_file(key_path)?;
-
-    // convert digest bytes to [u32; 12] (corresponding to SHA384 digest)
+/// Convert digest bytes to [u32; 12] (corresponding to SHA384 digest)
+fn digest_words(digest: &[u8]) -> Result<ImageDigest> {
     if digest.len() != SHA384_DIGEST_WORD_SIZE * 4 {
         anyhow::bail!("Invalid digest length: expected 48 bytes");
     }
@@ -68,6 +84,20 @@ fn lms_sign_digest(digest: &[u8], key_path: &str) -> Result<ImageLmsSignature> {
     for (i, chunk) in digest.chunks_exact(4).enumerate() {
         digest_arr[i] = u32::from_be_bytes(chunk.try_into().unwrap());
     }
+    Ok(digest_arr)
+}
+
+/// LMS: sign a digest using LMS private key
+fn lms_sign_digest(digest: &[u8], key: &KeySource) -> Result<ImageLmsSignature> {
+    // load LMS private key (binary format)
+    // eprintln!("Loading LMS private key from: {}", key_path);
+    #[allow(irrefutable_let_patterns)]
+    let KeySource::File(key_path) = key else {
+        anyhow::bail!("LMS signing is only supported by the file backend");
+    };
+
+    let priv_key = read_lms_privkey_from_file(key_path)?;
+    let digest_arr = digest_words(digest)?;
 
     // establish OpenSSL Crypto backend
     let crypto = OsslCrypto {};
@@ -79,8 +109,26 @@ fn lms_sign_digest(digest: &[u8], key_path: &str) -> Result<ImageLmsSignature> {
     Ok(sig)
 }
 
-/// Sign by file (overwrite digest file with signature)
-fn sign_by_file(algo: &str, key_path: &str, input_path: &str) -> Result<()> {
+/// Write through a temp file in the same directory and rename it into place,
+/// so a crash never leaves a half-written signature behind.
+fn write_atomic(path: &str, data: &[u8]) -> Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Sign by file (write signature to output, or overwrite the digest file)
+fn sign_by_file(
+    algo: &str,
+    key: &KeySource,
+    input_path: &str,
+    output_path: Option<&String>,
+) -> Result<()> {
     let mut digest = Vec::new();
     {
         let mut f = File::open(input_path)?;
@@ -89,23 +137,28 @@ fn sign_by_file(algo: &str, key_path: &str, input_path: &str) -> Result<()> {
 
     eprintln!("[FILE MODE] Signing digest from file: {}", input_path);
 
+    let output_path = match output_path {
+        Some(path) => path.as_str(),
+        None => {
+            eprintln!(
+                "Warning: no --output given, overwriting digest file {} with the signature",
+                input_path
+            );
+            input_path
+        }
+    };
+
     match algo {
         "ecc" => {
-            let sig = ecc_sign_digest(&digest, key_path)?;
+            let sig = ecc_sign_digest(&digest, key)?;
             let der_bytes = sig.to_der();
-            let mut f = File::create(input_path)?;
-            f.write_all(der_bytes.as_bytes())?;
-            eprintln!("ECC signature written to file: {}", input_path);
+            write_atomic(output_path, der_bytes.as_bytes())?;
+            eprintln!("ECC signature written to file: {}", output_path);
         }
         "lms" => {
-            let sig = lms_sign_digest(&digest, key_path)?;
-            let sig_ptr = &sig as *const _ as *const u8;
-            let sig_bytes =
-                unsafe { std::slice::from_raw_parts(sig_ptr, std::mem::size_of_val(&sig)) };
-
-            let mut f = File::create(input_path)?;
-            f.write_all(sig_bytes)?;
-            eprintln!("LMS signature written to file: {}", input_path);
+            let sig = lms_sign_digest(&digest, key)?;
+            write_atomic(output_path, sig.as_bytes())?;
+            eprintln!("LMS signature written to file: {}", output_path);
         }
         _ => anyhow::bail!("Unsupported algorithm: {}", algo),
     }
@@ -113,13 +166,35 @@ fn sign_by_file(algo: &str, key_path: &str, input_path: &str) -> Result<()> {
     Ok(())
 }
 
+/// Read exactly one binary SHA384 digest
+fn read_raw_digest(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut digest = vec![0u8; SHA384_DIGEST_WORD_SIZE * 4];
+    reader.read_exact(&mut digest).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => anyhow::anyhow!(
+            "Expected a {}-byte raw digest on stdin but the input ended early, \
+             use --stdin-format hex for hex text",
+            digest.len()
+        ),
+        _ => e.into(),
+    })?;
+    Ok(digest)
+}
+
+fn read_stdin_digest(stdin_format: &str) -> Result<Vec<u8>> {
+    let digest = match stdin_format {
+        "raw" => read_raw_digest(&mut io::stdin().lock())?,
+        _ => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            hex::decode(line.trim())?
+        }
+    };
+    Ok(digest)
+}
+
 /// STDIN/STDOUT mode
-fn sign_by_stdin(algo: &str, key_path: &str) -> Result<()> {
-    let stdin = io::stdin();
-    let mut line = String::new();
-    stdin.lock().read_line(&mut line)?;
-    let digest_hex = line.trim();
-    let digest = hex::decode(digest_hex)?;
+fn sign_by_stdin(algo: &str, key: &KeySource, stdin_format: &str) -> Result<()> {
+    let digest = read_stdin_digest(stdin_format)?;
 
     // Show only the first 16 bytes of the digest for preview
     let preview_len = digest.len().min(16);
@@ -131,7 +206,7 @@ fn sign_by_stdin(algo: &str, key_path: &str) -> Result<()> {
 
     match algo {
         "ecc" => {
-            let sig = ecc_sign_digest(&digest, key_path)?;
+            let sig = ecc_sign_digest(&digest, key)?;
             let der_bytes = sig.to_der();
             eprintln!(
                 // "ECC signature generated (DER hex): {}",
@@ -141,16 +216,14 @@ fn sign_by_stdin(algo: &str, key_path: &str) -> Result<()> {
             println!("{}", hex::encode(&der_bytes));
         }
         "lms" => {
-            let sig = lms_sign_digest(&digest, key_path)?;
-            let sig_ptr = &sig as *const _ as *const u8;
-            let sig_bytes =
-                unsafe { std::slice::from_raw_parts(sig_ptr, std::mem::size_of_val(&sig)) };
+            let sig = lms_sign_digest(&digest, key)?;
+            let sig_bytes = sig.as_bytes();
 
             eprintln!(
                 "LMS signature generated (binary len={} bytes)",
                 sig_bytes.len()
             );
-            println!("{}", hex::encode(&sig_bytes));
+            println!("{}", hex::encode(sig_bytes));
         }
         _ => anyhow::bail!("Unsupported algorithm: {}", algo),
     }
@@ -158,68 +231,244 @@ fn sign_by_stdin(algo: &str, key_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+/// ECDSA: check a DER signature against a PEM public key
+fn ecc_verify_digest(digest: &[u8], pem: &str, sig: &[u8]) -> Result<bool> {
+    let verifying_key = VerifyingKey::from_public_key_pem(pem)
+        .map_err(|e| anyhow::anyhow!("Invalid ECC public key: {}", e))?;
+    let sig = Signature::from_der(sig).map_err(|_| anyhow::anyhow!("Invalid DER signature"))?;
+    Ok(verifying_key.verify_prehash(digest, &sig).is_ok())
+}
 
-    // Example:
-    // ./rust_sign_helper --algo ecc --key fw
-    // ./rust_sign_helper --algo lms --key man --by-file --input digest.bin
-    let mut algo = "";
-    let mut key_type = "";
-    let mut by_file = false;
-    let mut input_path = String::new();
-
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--algo" => {
-                algo = args.get(i + 1).map(|s| s.as_str()).unwrap_or("");
-                i += 1;
-            }
-            "--key" => {
-                key_type = args.get(i + 1).map(|s| s.as_str()).unwrap_or("");
-                i += 1;
-            }
-            "--by-file" => {
-                by_file = true;
-            }
-            "--input" => {
-                input_path = args.get(i + 1).cloned().unwrap_or_default();
-                i += 1;
-            }
-            _ => {}
+/// LMS: check a binary signature against a binary public key
+fn lms_verify_digest(digest: &[u8], pub_key: &[u8], sig: &[u8]) -> Result<bool> {
+    let pub_key = ImageLmsPublicKey::read_from_bytes(pub_key).map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid LMS public key size: expected {} bytes, got {}",
+            mem::size_of::<ImageLmsPublicKey>(),
+            pub_key.len()
+        )
+    })?;
+    let sig = ImageLmsSignature::read_from_bytes(sig).map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid LMS signature size: expected {} bytes, got {}",
+            mem::size_of::<ImageLmsSignature>(),
+            sig.len()
+        )
+    })?;
+    let digest_arr = digest_words(digest)?;
+
+    // same OpenSSL backend the signing path uses
+    let crypto = OsslCrypto {};
+    crypto.lms_verify(&digest_arr, &pub_key, &sig)
+}
+
+/// Verify mode: check a DER (ECC) or binary (LMS) signature against a public key
+fn verify_signature(algo: &str, pub_key_path: &str, sig_path: &str, digest: &[u8]) -> Result<bool> {
+    let sig = fs::read(sig_path)?;
+
+    match algo {
+        "ecc" => {
+            let pem = fs::read_to_string(pub_key_path)?;
+            ecc_verify_digest(digest, &pem, &sig)
+                .map_err(|e| anyhow::anyhow!("{} ({}, {})", e, pub_key_path, sig_path))
         }
-        i += 1;
+        "lms" => {
+            let pub_key = fs::read(pub_key_path)?;
+            lms_verify_digest(digest, &pub_key, &sig)
+                .map_err(|e| anyhow::anyhow!("{} ({}, {})", e, pub_key_path, sig_path))
+        }
+        _ => anyhow::bail!("Unsupported algorithm: {}", algo),
     }
+}
 
-    if algo.is_empty() || key_type.is_empty() {
-        eprintln!(
-            "Usage: rust_sign_helper --algo <ecc|lms> --key <fw|man> [--by-file --input <path>]"
-        );
+const DEFAULT_KEY_DIR: &str = "key/ast2700a1-default";
+
+fn main() -> Result<()> {
+    // Example:
+    // ./rust_sign_helper --algo ecc --key fw
+    // ./rust_sign_helper --algo lms --key man --by-file --input digest.bin --output sig.bin
+    // ./rust_sign_helper --algo ecc --key fw --key-dir key/my-project
+    // ./rust_sign_helper --algo ecc --verify --pubkey own-fw-ecc-pubk.pem --signature sig.der --input digest.bin
+    // cat digest.bin | ./rust_sign_helper --algo lms --key fw --stdin-format raw
+    // PKCS11_PIN=1234 ./rust_sign_helper --algo ecc --key man --backend pkcs11 \
+    //     --pkcs11-module /usr/lib/softhsm/libsofthsm2.so --pkcs11-slot 0 --pkcs11-label man-ecc
+    let args = Command::new("rust_sign_helper")
+        .about("Example signing helper for cptra-imgtool")
+        .arg(
+            arg!(--"algo" <ALGO> "Signing algorithm")
+                .required(true)
+                .value_parser(["ecc", "lms"]),
+        )
+        .arg(
+            arg!(--"key" <KEY> "Key type")
+                .required_unless_present("verify")
+                .value_parser(["fw", "man"]),
+        )
+        .arg(
+            arg!(--"key-path" <FILE> "Private key file, overrides the default key lookup")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"key-dir" <DIR> "Base directory of the default key files")
+                .default_value(DEFAULT_KEY_DIR)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"backend" <BACKEND> "ECC signing backend")
+                .default_value("file")
+                .value_parser(["file", "pkcs11"]),
+        )
+        .arg(
+            arg!(--"pkcs11-module" <FILE> "PKCS#11 module library")
+                .required_if_eq("backend", "pkcs11")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"pkcs11-slot" <ID> "PKCS#11 slot id of the token")
+                .required_if_eq("backend", "pkcs11")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"pkcs11-label" <LABEL> "Label of the private key in the token")
+                .required_if_eq("backend", "pkcs11")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--"stdin-format" <FORMAT> "Digest encoding on stdin")
+                .default_value("hex")
+                .value_parser(["raw", "hex"]),
+        )
+        .arg(arg!(--"verify" "Verify --signature against --pubkey instead of signing"))
+        .arg(
+            arg!(--"pubkey" <FILE> "Public key used in --verify mode")
+                .required_if_eq("verify", "true")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--"signature" <FILE> "Signature checked in --verify mode")
+                .required_if_eq("verify", "true")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(arg!(--"by-file" "Read the digest from --input and write the signature back"))
+        .arg(
+            arg!(--"input" <FILE> "Digest file used in --by-file or --verify mode")
+                .required_if_eq("by-file", "true")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(--"output" <FILE> "Signature file in --by-file mode, defaults to overwriting --input")
+                .requires("by-file")
+                .value_parser(value_parser!(String)),
+        )
+        .get_matches();
+
+    let algo = args.get_one::<String>("algo").unwrap().as_str();
+
+    if args.get_flag("verify") {
+        let digest = match args.get_one::<String>("input") {
+            Some(input_path) => fs::read(input_path)?,
+            None => read_stdin_digest(args.get_one::<String>("stdin-format").unwrap())?,
+        };
+        let pub_key_path = args.get_one::<String>("pubkey").unwrap();
+        let sig_path = args.get_one::<String>("signature").unwrap();
+
+        if verify_signature(algo, pub_key_path, sig_path, &digest)? {
+            eprintln!("Signature verification OK");
+            return Ok(());
+        }
+        eprintln!("Signature verification FAILED");
         std::process::exit(1);
     }
 
-    // Select key path
-    let key_path = match (algo, key_type) {
-        ("ecc", "fw") => "key/ast2700a1-default/own-fw-ecc-prvk.pem",
-        ("ecc", "man") => "key/ast2700a1-default/own-man-ecc-prvk.pem",
-        ("lms", "fw") => "key/ast2700a1-default/own-fw-lms-prvk.pem",
-        ("lms", "man") => "key/ast2700a1-default/own-man-lms-prvk.pem",
-        _ => {
-            eprintln!("Unknown key type or algorithm: {algo}:{key_type}");
-            std::process::exit(1);
+    let key_type = args.get_one::<String>("key").unwrap().as_str();
+
+    // Select key path, an explicit --key-path wins over the default names
+    let key_path = match args.get_one::<PathBuf>("key-path") {
+        Some(path) => path.clone(),
+        None => {
+            let key_name = match (algo, key_type) {
+                ("ecc", "fw") => "own-fw-ecc-prvk.pem",
+                ("ecc", "man") => "own-man-ecc-prvk.pem",
+                ("lms", "fw") => "own-fw-lms-prvk.pem",
+                ("lms", "man") => "own-man-lms-prvk.pem",
+                _ => unreachable!(),
+            };
+            args.get_one::<PathBuf>("key-dir").unwrap().join(key_name)
         }
     };
+    let key_path = key_path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid key path: {}", key_path.display()))?;
+
+    let key = match args.get_one::<String>("backend").unwrap().as_str() {
+        "file" => KeySource::File(key_path.to_string()),
+        #[cfg(feature = "pkcs11")]
+        "pkcs11" => KeySource::Pkcs11(pkcs11::TokenKey {
+            module: args.get_one::<PathBuf>("pkcs11-module").unwrap().clone(),
+            slot: *args.get_one::<u64>("pkcs11-slot").unwrap(),
+            label: args.get_one::<String>("pkcs11-label").unwrap().clone(),
+        }),
+        backend => anyhow::bail!("Backend {} is not enabled in this build", backend),
+    };
 
-    if by_file {
-        if input_path.is_empty() {
-            eprintln!("Error: --input <path> required for --by-file mode");
-            std::process::exit(1);
-        }
-        sign_by_file(algo, key_path, &input_path)?;
+    if args.get_flag("by-file") {
+        let input_path = args.get_one::<String>("input").unwrap();
+        let output_path = args.get_one::<String>("output");
+        sign_by_file(algo, &key, input_path, output_path)?;
     } else {
-        sign_by_stdin(algo, key_path)?;
+        let stdin_format = args.get_one::<String>("stdin-format").unwrap();
+        sign_by_stdin(algo, &key, stdin_format)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::pkcs8::{EncodePublicKey, LineEnding};
+
+    const LMS_PRIV_KEY: &[u8] = include_bytes!("../../../key/ast2700-default/vnd-fw-lms-prvk.pem");
+    const LMS_PUB_KEY: &[u8] = include_bytes!("../../../key/ast2700-default/vnd-fw-lms-pubk.pem");
+
+    fn digest() -> Vec<u8> {
+        (0..48).collect()
+    }
+
+    #[test]
+    fn ecc_signature_verifies_and_corruption_is_detected() {
+        let key = SigningKey::from_slice(&[0x11; 48]).unwrap();
+        let pem = key
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let sig: Signature = key.sign_prehash(&digest()).unwrap();
+        let mut der = sig.to_der().as_bytes().to_vec();
+        assert!(ecc_verify_digest(&digest(), &pem, &der).unwrap());
+
+        /* flip a bit in the last byte of s, the DER framing stays valid */
+        *der.last_mut().unwrap() ^= 1;
+        assert!(!ecc_verify_digest(&digest(), &pem, &der).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs the caliptra-sw OsslCrypto LMS backend, run with --ignored"]
+    fn lms_signature_verifies_and_corruption_is_detected() {
+        let priv_key = ImageLmsPrivKey::read_from_bytes(LMS_PRIV_KEY).unwrap();
+        let sig = OsslCrypto {}
+            .lms_sign(&digest_words(&digest()).unwrap(), &priv_key)
+            .unwrap();
+        let mut sig = sig.as_bytes().to_vec();
+        assert!(lms_verify_digest(&digest(), LMS_PUB_KEY, &sig).unwrap());
+
+        /* corrupt the first OTS chain value, past q and the OTS type */
+        sig[8] ^= 1;
+        assert!(!lms_verify_digest(&digest(), LMS_PUB_KEY, &sig).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs the caliptra-sw OsslCrypto LMS backend, run with --ignored"]
+    fn lms_signature_of_the_wrong_size_is_an_error() {
+        assert!(lms_verify_digest(&digest(), LMS_PUB_KEY, &[0u8; 16]).is_err());
+    }
+}
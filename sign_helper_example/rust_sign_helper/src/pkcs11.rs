@@ -0,0 +1,50 @@
+use anyhow::Result;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, ObjectClass};
+use cryptoki::session::UserType;
+use cryptoki::types::AuthPin;
+use p384::ecdsa::Signature;
+use std::path::PathBuf;
+
+/// User PIN of the token, read from the environment so it never shows up in `ps`
+const PKCS11_PIN_ENV: &str = "PKCS11_PIN";
+
+/// ECC-P384 private key stored in a PKCS#11 token
+pub struct TokenKey {
+    pub module: PathBuf,
+    pub slot: u64,
+    pub label: String,
+}
+
+/// PKCS#11: sign a SHA384 digest with CKM_ECDSA inside the token.
+pub fn ecc_sign_digest(digest: &[u8], key: &TokenKey) -> Result<Signature> {
+    let pkcs11 = Pkcs11::new(&key.module)?;
+    pkcs11.initialize(CInitializeArgs::OsThreads)?;
+
+    let slot = pkcs11
+        .get_slots_with_token()?
+        .into_iter()
+        .find(|s| s.id() == key.slot)
+        .ok_or_else(|| anyhow::anyhow!("No token found in PKCS#11 slot {}", key.slot))?;
+
+    let session = pkcs11.open_ro_session(slot)?;
+    if let Ok(pin) = std::env::var(PKCS11_PIN_ENV) {
+        session.login(UserType::User, Some(&AuthPin::new(pin)))?;
+    }
+
+    let template = [
+        Attribute::Class(ObjectClass::PRIVATE_KEY),
+        Attribute::Label(key.label.as_bytes().to_vec()),
+    ];
+    let handle = *session
+        .find_objects(&template)?
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("Private key '{}' not found in token", key.label))?;
+
+    // CKM_ECDSA returns the raw r || s pair, 48 bytes each for P-384
+    let raw = session.sign(&Mechanism::Ecdsa, handle, digest)?;
+    let sig = Signature::from_slice(&raw)
+        .map_err(|_| anyhow::anyhow!("Invalid ECDSA signature from token ({} bytes)", raw.len()))?;
+    Ok(sig)
+}
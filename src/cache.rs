@@ -0,0 +1,235 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   cache.rs
+
+Abstract:
+
+    File contains the image digest cache shared between repeated builds
+
+--*/
+
+use crate::error::{IoContext, Result};
+use crate::tool::ToolRunner;
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha384};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const CACHE_FILE_NAME: &str = "digest-cache.toml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct CachedDigest {
+    size: u64,
+
+    mtime_secs: u64,
+
+    mtime_nanos: u32,
+
+    /* (offset, len) of the hashed part of the image */
+    #[serde(default)]
+    range: Option<(u64, u64)>,
+
+    digest: String,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+struct CacheFile {
+    /* `--version` of the caliptra manifest tool the digests were computed for,
+     * or "sha384:<digest>" of its binary when it has no version
+     */
+    #[serde(default)]
+    tool_version: Option<String>,
+
+    /* Keyed by the canonical image path */
+    images: BTreeMap<String, CachedDigest>,
+}
+
+/// A digest missing from the cache, with the image state `insert` records.
+#[derive(Default, Debug)]
+pub(crate) struct CacheMiss {
+    entry: Option<(String, CachedDigest)>,
+}
+
+/// Image digests keyed by path, size and modification time. Without a cache
+/// directory every digest is computed from scratch. The whole cache is dropped
+/// when the caliptra manifest tool version, or the tool binary when it can't
+/// report one, changed since it was written.
+#[derive(Default, Debug)]
+pub(crate) struct DigestCache {
+    file: Option<PathBuf>,
+    entries: CacheFile,
+    dirty: bool,
+}
+
+/* SHA-384 of the tool binary, the cache key of a tool without `--version` */
+fn tool_binary_digest(tool: &Path) -> Option<String> {
+    let mut file = fs::File::open(tool).ok()?;
+    let mut hasher = Sha384::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(format!("sha384:{}", hex::encode(hasher.finalize())))
+}
+
+impl DigestCache {
+    pub(crate) fn open(
+        cache_dir: Option<&PathBuf>,
+        runner: &dyn ToolRunner,
+        tool: &Path,
+    ) -> Result<Self> {
+        let Some(dir) = cache_dir else {
+            return Ok(Self::default());
+        };
+
+        fs::create_dir_all(dir)
+            .io_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+        let file = dir.join(CACHE_FILE_NAME);
+
+        /* A cache that can't be parsed is dropped rather than failing the build */
+        let mut entries = fs::read_to_string(&file)
+            .ok()
+            .and_then(|s| toml::from_str::<CacheFile>(&s).ok())
+            .unwrap_or_default();
+        debug!(
+            "Loaded {} cached digests from {}",
+            entries.images.len(),
+            file.display()
+        );
+
+        /* A tool that can't report its version is identified by its binary, one
+         * that can't be read either never matches a cached one
+         */
+        let tool_version = match crate::tool::query_version(runner, tool) {
+            Ok(version) => Some(version.trim().to_string()),
+            Err(_) => tool_binary_digest(tool),
+        };
+        let dirty = tool_version.is_none() || entries.tool_version != tool_version;
+        if dirty {
+            debug!(
+                "Tool version changed from {:?} to {:?}, dropping the digest cache",
+                entries.tool_version, tool_version
+            );
+            entries = CacheFile {
+                tool_version,
+                ..Default::default()
+            };
+        }
+
+        Ok(Self {
+            file: Some(file),
+            entries,
+            dirty,
+        })
+    }
+
+    /// Return the cached digest of the `range` of `image`, or the miss to pass to
+    /// `insert` when the image is new or its size, modification time or range changed.
+    pub(crate) fn lookup(
+        &self,
+        image: &Path,
+        range: (u64, u64),
+    ) -> Result<std::result::Result<String, CacheMiss>> {
+        if self.file.is_none() {
+            return Ok(Err(CacheMiss::default()));
+        }
+
+        /* Stat before hashing, so a change while hashing invalidates the entry */
+        let meta = fs::metadata(image)
+            .io_context(|| format!("Failed to stat image {}", image.display()))?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let key = fs::canonicalize(image)
+            .unwrap_or_else(|_| image.to_path_buf())
+            .to_string_lossy()
+            .into_owned();
+        let entry = CachedDigest {
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            range: Some(range),
+            digest: String::new(),
+        };
+
+        if let Some(hit) = self.entries.images.get(&key) {
+            if hit.size == entry.size
+                && hit.mtime_secs == entry.mtime_secs
+                && hit.mtime_nanos == entry.mtime_nanos
+                && hit.range == entry.range
+            {
+                debug!("Digest cache hit: {}", key);
+                return Ok(Ok(hit.digest.clone()));
+            }
+        }
+
+        debug!("Digest cache miss: {}", key);
+        Ok(Err(CacheMiss {
+            entry: Some((key, entry)),
+        }))
+    }
+
+    /// Record the digest computed for a `lookup` miss.
+    pub(crate) fn insert(&mut self, miss: CacheMiss, digest: &str) {
+        if let Some((key, entry)) = miss.entry {
+            self.entries.images.insert(
+                key,
+                CachedDigest {
+                    digest: digest.to_string(),
+                    ..entry
+                },
+            );
+            self.dirty = true;
+        }
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let Some(file) = self.file.as_ref().filter(|_| self.dirty) else {
+            return Ok(());
+        };
+
+        fs::write(file, toml::to_string(&self.entries)?)
+            .io_context(|| format!("Failed to write digest cache {}", file.display()))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tool::RealToolRunner;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn tool_without_version_is_keyed_by_its_binary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache_dir = tmp.path().join("cache");
+        let image = tmp.path().join("image.bin");
+        fs::write(&image, b"image").unwrap();
+        let tool = tmp.path().join("tool");
+        fs::write(&tool, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let runner = RealToolRunner::default();
+        let open = || DigestCache::open(Some(&cache_dir), &runner, &tool).unwrap();
+        let mut cache = open();
+        let miss = cache.lookup(&image, (0, 5)).unwrap().unwrap_err();
+        cache.insert(miss, "digest");
+        cache.save().unwrap();
+
+        /* The same binary keeps the cache */
+        assert_eq!(
+            open().lookup(&image, (0, 5)).unwrap().ok().unwrap(),
+            "digest"
+        );
+
+        /* A changed binary drops it */
+        fs::write(&tool, "#!/bin/sh\nexit 2\n").unwrap();
+        assert!(open().lookup(&image, (0, 5)).unwrap().is_err());
+    }
+}
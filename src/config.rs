@@ -12,31 +12,86 @@ Abstract:
 
 --*/
 
-use anyhow::{anyhow, Context, Result};
+use bitflags::Flags;
 use clap::ArgMatches;
-use hex;
-use log::debug;
-use once_cell::sync::Lazy;
+use log::{debug, info};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::de::Error as _;
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha384};
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tempfile::TempDir;
 use toml::Value;
 
-use crate::utility::PathBufExt;
+use crate::cache::{CacheMiss, DigestCache};
+use crate::error::{ImgtoolError, IoContext, Result};
+use crate::layout::{AUTH_MANIFEST_MAGIC, IMAGE_METADATA_MAX_COUNT, SHA384_DIGEST_SIZE};
+use crate::progress::{Progress, PROGRESS_CHUNK_SIZE};
+use crate::soc_man;
+use crate::tool::ToolRunner;
+use crate::utility::{filesystem_id, is_special_file, parallel_map, print_warning, PathBufExt};
 
 static GLOBAL_TMP_DIR: Lazy<TempDir> =
     Lazy::new(|| TempDir::new().expect("Failed to create global temp directory"));
 
+/* Copy of a config read from stdin with `--cfg -`, stdin can only be read once */
+static STDIN_CFG: OnceCell<PathBuf> = OnceCell::new();
+
+/* Config merged from `--cfg` and `--cfg-overlay`, and the `--cfg` it is based on */
+static MERGED_CFG: OnceCell<(PathBuf, PathBuf)> = OnceCell::new();
+
+/* Files created in GLOBAL_TMP_DIR, the only ones remove_tmp_folder deletes */
+static GLOBAL_TMP_FILES: Lazy<Mutex<Vec<PathBuf>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
 static GLOBAL_DUMMY_PATH: Lazy<PathBuf> = Lazy::new(|| {
-    let path: PathBuf = GLOBAL_TMP_DIR.path().join("dummy.bin");
+    let path: PathBuf = tmp_file_path("dummy.bin");
     std::fs::File::create(&path).expect("Failed to create dummy.bin");
     path
 });
 
+/* Default tool directory when --tool-dir is not given */
+const TOOL_DIR_ENV: &str = "CPTRA_IMGTOOL_TOOL_DIR";
+const AUTH_MANIFEST_TOOL: &str = "caliptra-auth-manifest-app";
+const FLASH_TOOL: &str = "xtask";
+/* Tool name overrides, they win over [tool_names] in the config */
+const AUTH_MANIFEST_TOOL_ENV: &str = "CPTRA_IMGTOOL_AUTH_MANIFEST_TOOL";
+const FLASH_TOOL_ENV: &str = "CPTRA_IMGTOOL_FLASH_TOOL";
+const SIGN_HELPER_NAME: &str = "rust_sign_helper";
+
+/* Project name used for the default directories when the config has no prj_name */
+const DEFAULT_PROJECT_NAME: &str = "default_project";
+
+/* fw_id of the MCU runtime image when the config doesn't set mcu_runtime_fw_id */
+const DEFAULT_MCU_RUNTIME_FW_ID: u32 = 1;
+
+/* Load stages the loader knows, load_stage is written to the manifest as is */
+const LOAD_STAGES: std::ops::RangeInclusive<u32> = 0..=2;
+
+bitflags::bitflags! {
+    /// Manifest `flags`, the caliptra `AuthManifestFlags` bits. Only bits caliptra
+    /// defines can be set, the flags are signed into the manifest.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) struct ManifestFlags: u32 {
+        /* Caliptra: images must also be signed with the vendor keys */
+        const VENDOR_SIGNATURE_REQUIRED = 1 << 0;
+    }
+}
+
+bitflags::bitflags! {
+    /// Per-image metadata `flags`, the caliptra `ImageMetadataFlags` bits that have
+    /// a name. The image source in bits 0-1 is given by `source`.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) struct ImageMetadataFlags: u32 {
+        /* Caliptra: the image digest is not checked */
+        const IGNORE_AUTH_CHECK = 1 << 2;
+    }
+}
+
 /*  Caliptra defined configuration toml file  */
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct AuthManifestKeyConfigFromFile {
@@ -78,6 +133,7 @@ pub(crate) struct AuthManifestConfigFromFile {
 }
 
 /* Aspeed defined configuration toml file */
+#[allow(dead_code)]
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct AspeedManifestToolDependencies {
     pub caliptra_sw_auth: String,
@@ -89,9 +145,39 @@ pub(crate) struct AspeedManifestToolDependencies {
 pub(crate) struct AspeedAuthManifestGeneralConfigFromFile {
     pub version: u32,
 
-    pub flags: u32,
+    #[serde(
+        deserialize_with = "deserialize_flags",
+        serialize_with = "serialize_flags"
+    )]
+    pub flags: ManifestFlags,
 
     pub security_version: u32,
+
+    pub prj_name: Option<String>,
+
+    /* Override the prebuilt/<prj_name>/ and key/<prj_name>/ conventions */
+    pub prebuilt_dir: Option<String>,
+
+    pub key_dir: Option<String>,
+
+    /* Resolve the relative paths in the config against its own directory */
+    pub paths_relative_to_config: Option<bool>,
+
+    pub mcu_runtime_fw_id: Option<u32>,
+
+    pub strict_images: Option<bool>,
+
+    /* Inclusive range of caliptra-auth-manifest-app versions the config is written for */
+    pub min_caliptra_version: Option<String>,
+
+    pub max_caliptra_version: Option<String>,
+
+    /* Size of the target flash, the flash image must fit in it */
+    pub flash_size: Option<u64>,
+
+    /* Manifest magic of a forked manifest format, the caliptra magic when not set */
+    #[serde(default, deserialize_with = "deserialize_magic")]
+    pub expected_magic: Option<u32>,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -101,17 +187,337 @@ pub(crate) struct AspeedImageRuntimeConfigFromFile {
     pub mcu_file: String,
 }
 
+/* Config values stored as a number in the manifest that may also be given by name */
+trait NamedValue: Copy + 'static {
+    const FIELD: &'static str;
+    const ALL: &'static [Self];
+
+    fn value(self) -> u32;
+
+    fn name(self) -> &'static str;
+
+    fn valid_values() -> String {
+        Self::ALL
+            .iter()
+            .map(|v| format!("{} (\"{}\")", v.value(), v.name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/* Where caliptra takes the image hash from, the values of caliptra's ImageHashSource */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImageSource {
+    InRequest = 1,
+    ShaAcc = 2,
+}
+
+impl NamedValue for ImageSource {
+    const FIELD: &'static str = "source";
+    const ALL: &'static [Self] = &[ImageSource::InRequest, ImageSource::ShaAcc];
+
+    fn value(self) -> u32 {
+        self as u32
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ImageSource::InRequest => "in_request",
+            ImageSource::ShaAcc => "sha_acc",
+        }
+    }
+}
+
+/* Accept flags as a raw number or a list of flag names, and reject unknown bits */
+fn deserialize_flags<'de, F: Flags<Bits = u32>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<F, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawFlags {
+        Number(u32),
+        Names(Vec<String>),
+    }
+
+    let valid_names = || {
+        F::all()
+            .iter_names()
+            .map(|(name, _)| format!("\"{}\"", name.to_lowercase()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    match RawFlags::deserialize(deserializer)? {
+        RawFlags::Number(n) => F::from_bits(n).ok_or_else(|| {
+            D::Error::custom(format!(
+                "invalid flags 0x{:08x}, unknown bits 0x{:08x}, valid flags are {}",
+                n,
+                n & !F::all().bits(),
+                valid_names()
+            ))
+        }),
+        RawFlags::Names(names) => names.iter().try_fold(F::empty(), |acc, name| {
+            F::from_name(&name.to_uppercase())
+                .map(|flag| acc.union(flag))
+                .ok_or_else(|| {
+                    D::Error::custom(format!(
+                        "invalid flag \"{}\", valid flags are {}",
+                        name,
+                        valid_names()
+                    ))
+                })
+        }),
+    }
+}
+
+/* Write flags as the list of flag names */
+fn serialize_flags<F: Flags, S: serde::Serializer>(
+    flags: &F,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(flags.iter_names().map(|(name, _)| name.to_lowercase()))
+}
+
+/* Accept a NamedValue field as a number or a name, and reject unknown values */
+fn deserialize_named<'de, T: NamedValue, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawValue {
+        Number(u32),
+        Name(String),
+    }
+
+    let raw = RawValue::deserialize(deserializer)?;
+    let found = T::ALL.iter().find(|v| match &raw {
+        RawValue::Number(n) => v.value() == *n,
+        RawValue::Name(name) => v.name() == name,
+    });
+
+    match found {
+        Some(v) => Ok(v.value()),
+        None => {
+            let value = match raw {
+                RawValue::Number(n) => n.to_string(),
+                RawValue::Name(name) => format!("\"{}\"", name),
+            };
+            Err(D::Error::custom(format!(
+                "invalid {} {}, valid values are {}",
+                T::FIELD,
+                value,
+                T::valid_values()
+            )))
+        }
+    }
+}
+
+/// Magic given as 4 ASCII characters, "ATMN" is 0x41544d4e.
+pub(crate) fn magic_from_text(text: &str) -> Option<u32> {
+    <[u8; 4]>::try_from(text.as_bytes())
+        .ok()
+        .filter(|b| b.is_ascii())
+        .map(u32::from_be_bytes)
+}
+
+/* Accept the magic as a number or 4 characters, "ATMN" is 0x41544d4e */
+fn deserialize_magic<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMagic {
+        Number(u32),
+        Text(String),
+    }
+
+    match RawMagic::deserialize(deserializer)? {
+        RawMagic::Number(n) => Ok(Some(n)),
+        RawMagic::Text(text) => magic_from_text(&text).map(Some).ok_or_else(|| {
+            D::Error::custom(format!(
+                "invalid expected_magic \"{}\", expected a u32 or 4 ASCII characters",
+                text
+            ))
+        }),
+    }
+}
+
+/* Reject a load_stage outside LOAD_STAGES, the image would never be loaded */
+fn deserialize_load_stage<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u32, D::Error> {
+    let stage = u32::deserialize(deserializer)?;
+    if !LOAD_STAGES.contains(&stage) {
+        return Err(D::Error::custom(format!(
+            "invalid load_stage {}, valid values are {} to {}",
+            stage,
+            LOAD_STAGES.start(),
+            LOAD_STAGES.end()
+        )));
+    }
+
+    Ok(stage)
+}
+
+fn deserialize_source<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u32, D::Error> {
+    deserialize_named::<ImageSource, D>(deserializer)
+}
+
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct AspeedImageMetadataConfigFromFile {
+    /* May be omitted when `digest` is given */
+    #[serde(default)]
     pub file: String,
 
+    #[serde(deserialize_with = "deserialize_source")]
     pub source: u32,
 
     pub fw_id: u32,
 
+    /* Human-readable name shown next to the fw_id, not written to the manifest */
+    pub name: Option<String>,
+
+    #[serde(default)]
     pub ignore_auth_check: bool,
 
+    /* Named metadata flags, "ignore_auth_check" is the same as the field above */
+    #[serde(
+        default,
+        deserialize_with = "deserialize_flags",
+        serialize_with = "serialize_flags"
+    )]
+    pub flags: ImageMetadataFlags,
+
+    #[serde(deserialize_with = "deserialize_load_stage")]
     pub load_stage: u32,
+
+    /* Only hash file[digest_offset..digest_offset + digest_len], e.g. to skip a
+     * header patched at flash time. Defaults to the whole file.
+     */
+    pub digest_offset: Option<u64>,
+
+    pub digest_len: Option<u64>,
+
+    /* Trusted SHA-384 digest as hex, used instead of hashing `file` */
+    pub digest: Option<String>,
+}
+
+/// "fw_id 3", or "fw_id 3 \"u-boot\"" for an image with a name.
+pub(crate) fn image_label(fw_id: u32, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("fw_id {} \"{}\"", fw_id, name),
+        None => format!("fw_id {}", fw_id),
+    }
+}
+
+impl AspeedImageMetadataConfigFromFile {
+    /// How messages refer to the image, see `image_label`.
+    pub(crate) fn label(&self) -> String {
+        image_label(self.fw_id, self.name.as_deref())
+    }
+
+    /// Image given only by its digest, there is no file to hash or to put in
+    /// the flash image.
+    pub(crate) fn digest_only(&self) -> bool {
+        self.file.is_empty() && self.digest.is_some()
+    }
+
+    /// The `digest` from the config as lowercase hex, checked to be a SHA-384 digest.
+    pub(crate) fn provided_digest(&self) -> Result<Option<String>> {
+        let Some(digest) = self.digest.as_ref() else {
+            return Ok(None);
+        };
+
+        match hex::decode(digest) {
+            Ok(bytes) if bytes.len() == SHA384_DIGEST_SIZE => Ok(Some(hex::encode(bytes))),
+            _ => Err(ImgtoolError::Config(format!(
+                "Invalid digest of {}: expected {} hex characters, got \"{}\"",
+                self.label(),
+                SHA384_DIGEST_SIZE * 2,
+                digest
+            ))),
+        }
+    }
+
+    /// Byte range of the file covered by the digest, as (offset, len).
+    pub(crate) fn digest_range(&self) -> Result<(u64, u64)> {
+        let size = fs::metadata(&self.file)
+            .io_context(|| format!("Failed to stat image {}", self.file))?
+            .len();
+        let offset = self.digest_offset.unwrap_or(0);
+        let len = self
+            .digest_len
+            .unwrap_or_else(|| size.saturating_sub(offset));
+
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            return Err(ImgtoolError::Config(format!(
+                "Digest range of {} is out of the image: offset {} + len {} > file size {} of {}",
+                self.label(),
+                offset,
+                len,
+                size,
+                self.file
+            )));
+        }
+
+        Ok((offset, len))
+    }
+}
+
+/* Board specific blob written into the flash image after the caliptra tool regions */
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ExtraFlashRegionConfigFromFile {
+    pub file: String,
+
+    pub offset: u32,
+}
+
+/* Program run once create-auth-man or create-auth-flash succeeded */
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PostBuildHookConfigFromFile {
+    pub program: String,
+
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/* Executable names of the external tools, for renamed or wrapped builds */
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ToolNamesConfigFromFile {
+    pub auth_manifest: Option<String>,
+
+    pub flash: Option<String>,
+}
+
+impl PostBuildHookConfigFromFile {
+    /// Check the arguments only use the placeholders the subcommand fills in,
+    /// `{flash}` is only available with create-auth-flash.
+    pub(crate) fn validate_args(&self, flash: bool) -> Result<()> {
+        match self.args.iter().find(|arg| arg.contains("{flash}")) {
+            Some(arg) if !flash => Err(ImgtoolError::Config(format!(
+                "post_build_hook argument \"{}\" uses {{flash}}, only available with create-auth-flash",
+                arg
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hook arguments with `{manifest}` and `{flash}` replaced by the output
+    /// paths. `flash` is None for create-auth-man, see `validate_args`.
+    pub(crate) fn expand_args(&self, manifest: &Path, flash: Option<&Path>) -> Vec<String> {
+        self.args
+            .iter()
+            .map(|arg| {
+                let mut arg = arg.replace("{manifest}", &manifest.to_path_buf().to_string());
+                if let Some(flash) = flash {
+                    arg = arg.replace("{flash}", &flash.to_path_buf().to_string());
+                }
+                arg
+            })
+            .collect()
+    }
 }
 
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
@@ -135,42 +541,362 @@ pub(crate) struct AspeedAuthManifestConfigFromFile {
 
     pub owner_man_key_config: Option<AuthManifestKeyConfigFromFile>,
 
+    /* Owner manifest key slots for key rotation, selected with --owner-key-index */
+    pub owner_man_key_configs: Option<Vec<AuthManifestKeyConfigFromFile>>,
+
     pub image_runtime_list: AspeedImageRuntimeConfigFromFile,
 
+    /* May be empty, the manifest then authorizes no image */
+    #[serde(default)]
     pub image_metadata_list: Vec<AspeedImageMetadataConfigFromFile>,
 
+    pub extra_flash_regions: Option<Vec<ExtraFlashRegionConfigFromFile>>,
+
     pub sign_helper: Option<AspeedAuthManifestSignHelper>,
+
+    pub post_build_hook: Option<PostBuildHookConfigFromFile>,
+
+    pub tool_names: Option<ToolNamesConfigFromFile>,
 }
 
+/* Tables written commented out in the config template, they name files that
+ * don't exist in a fresh project.
+ */
+const TEMPLATE_EXAMPLE_TABLES: &[&str] = &["extra_flash_regions", "post_build_hook"];
+
+/* Comments written above each field of the config template, as (table, key, comment).
+ * An empty key comments the table header.
+ */
+const TEMPLATE_COMMENTS: &[(&str, &str, &str)] = &[
+    ("manifest_config", "", "General manifest settings"),
+    ("manifest_config", "version", "Manifest format version, keep unchanged unless the platform requires it"),
+    ("manifest_config", "flags", "Manifest flags, a number or a list of \"vendor_signature_required\""),
+    ("manifest_config", "security_version", "Anti-rollback version, devices reject a lower value than the stored one"),
+    ("manifest_config", "prj_name", "Optional, selects key/<prj_name>/, prebuilt/<prj_name>/ and the output file names"),
+    ("manifest_config", "paths_relative_to_config", "Optional, resolve prebuilt_dir, key_dir, their defaults and the post_build_hook program against the config file directory instead of the working directory, defaults to false"),
+    ("manifest_config", "mcu_runtime_fw_id", "Optional, fw_id of the MCU runtime image, defaults to 1"),
+    ("manifest_config", "strict_images", "Optional, reject an empty image file instead of using an empty dummy image, defaults to false"),
+    ("vendor_fw_key_config", "", "Vendor firmware signing keys, file names relative to --key-dir. Remove a key to leave it unused"),
+    ("vendor_man_key_config", "", "Vendor manifest signing keys"),
+    ("owner_fw_key_config", "", "Optional, owner firmware signing keys"),
+    ("owner_man_key_config", "", "Optional, owner manifest signing keys. Use [[owner_man_key_configs]] instead for key rotation"),
+    ("image_runtime_list", "", "Runtime images of the flash image, file names relative to --prebuilt-dir"),
+    ("image_runtime_list", "caliptra_file", "Caliptra firmware"),
+    ("image_runtime_list", "mcu_file", "MCU runtime firmware"),
+    ("image_metadata_list", "", "One entry per image authorized by the manifest, at most 127"),
+    ("image_metadata_list", "file", "Image file relative to --prebuilt-dir, empty for a placeholder"),
+    ("image_metadata_list", "source", "Image hash source, 1 (\"in_request\") or 2 (\"sha_acc\")"),
+    ("image_metadata_list", "fw_id", "Unique firmware id"),
+    ("image_metadata_list", "name", "Optional, name shown next to the fw_id in messages and listings, not written to the manifest"),
+    ("image_metadata_list", "ignore_auth_check", "Skip the digest check of this image"),
+    ("image_metadata_list", "flags", "Optional, metadata flags, a number or a list of \"ignore_auth_check\""),
+    ("image_metadata_list", "load_stage", "0, 1 or 2"),
+    ("extra_flash_regions", "", "Optional, raw blobs written into the flash image after the caliptra tool regions, uncomment to use"),
+    ("extra_flash_regions", "file", "Blob file relative to --prebuilt-dir"),
+    ("extra_flash_regions", "offset", "Byte offset in the flash image, past every image region, gaps are filled with 0xff"),
+    ("post_build_hook", "", "Optional, program run after a successful build, e.g. to upload the outputs"),
+    ("post_build_hook", "program", "Program name or path"),
+    ("post_build_hook", "args", "Arguments, {manifest} and {flash} are replaced by the output paths"),
+    ("tool_names", "", "Optional, executable names of the external tools in the tool directory"),
+    ("tool_names", "auth_manifest", "Manifest tool, defaults to \"caliptra-auth-manifest-app\", overridden by CPTRA_IMGTOOL_AUTH_MANIFEST_TOOL"),
+    ("tool_names", "flash", "Flash image tool, defaults to \"xtask\", overridden by CPTRA_IMGTOOL_FLASH_TOOL"),
+];
+
 fn pad_to_aligned(mut data: Vec<u8>, pad: u8, aligned: usize) -> Vec<u8> {
     let pad_len = (aligned - (data.len() % aligned)) % aligned;
     data.extend(vec![pad; pad_len]);
     data
 }
 
+/* Hash the (offset, len) range of a file zero-padded to `aligned` bytes in chunks,
+ * so the image never has to fit in memory. The digest is the same as hashing
+ * pad_to_aligned() of the range. `on_chunk` gets the number of bytes hashed so far.
+ */
+fn digest_aligned_file(
+    path: &Path,
+    (offset, len): (u64, u64),
+    aligned: usize,
+    mut on_chunk: impl FnMut(u64),
+) -> Result<Vec<u8>> {
+    let mut file =
+        fs::File::open(path).io_context(|| format!("Failed to open image {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset))
+        .io_context(|| format!("Failed to seek image {}", path.display()))?;
+    let mut file = file.take(len);
+    let mut hasher = Sha384::new();
+    let mut buf = Vec::with_capacity(PROGRESS_CHUNK_SIZE);
+    let mut done = 0;
+
+    /* Full chunks are a multiple of `aligned`, only the last one needs padding */
+    loop {
+        buf.clear();
+        (&mut file)
+            .take(PROGRESS_CHUNK_SIZE as u64)
+            .read_to_end(&mut buf)
+            .io_context(|| format!("Failed to read image {}", path.display()))?;
+        done += buf.len() as u64;
+        on_chunk(done);
+
+        if buf.len() < PROGRESS_CHUNK_SIZE {
+            hasher.update(pad_to_aligned(buf, 0, aligned));
+            break;
+        }
+        hasher.update(&buf);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/* Feed a labeled file into a fingerprint, a missing file hashes as a marker */
+fn fingerprint_file(hasher: &mut Sha384, label: &str, file: &Path) -> Result<()> {
+    hasher.update(label.as_bytes());
+    match fs::File::open(file) {
+        Ok(mut f) => {
+            let len = f.metadata().map(|m| m.len()).unwrap_or_default();
+            hasher.update(len.to_le_bytes());
+            std::io::copy(&mut f, hasher)
+                .io_context(|| format!("Failed to read {}", file.display()))?;
+        }
+        Err(_) => hasher.update(b"\0missing"),
+    }
+    Ok(())
+}
+
+/* Executable file name of an external tool, with .exe on Windows */
+fn tool_file_name(tool: &str) -> String {
+    format!("{}{}", tool, env::consts::EXE_SUFFIX)
+}
+
+/// Locate the example sign helper: `--helper` wins, then the tool directories,
+/// then the helper's own build directory.
+pub(crate) fn sign_helper_path(args: &ArgMatches) -> Result<PathBuf> {
+    if let Ok(Some(helper)) = args.try_get_one::<PathBuf>("helper") {
+        check_file_exists(helper)?;
+        return Ok(helper.clone());
+    }
+
+    let cur_exe = env::current_exe()
+        .io_context(|| "Failed to locate the running executable")?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let paths = args
+        .try_get_one::<PathBuf>("tool-dir")
+        .ok()
+        .flatten()
+        .cloned()
+        .into_iter()
+        .chain(env::var_os(TOOL_DIR_ENV).map(PathBuf::from))
+        .chain([
+            PathBuf::from("./target/release"),
+            PathBuf::from("./target/debug"),
+            PathBuf::from("./sign_helper_example/rust_sign_helper/target/release"),
+            cur_exe,
+        ])
+        .collect::<Vec<_>>();
+
+    let file_name = tool_file_name(SIGN_HELPER_NAME);
+    paths
+        .iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|helper| helper.is_file())
+        .ok_or_else(|| {
+            ImgtoolError::ToolNotFound(format!(
+                "{} not found, build sign_helper_example/rust_sign_helper or use --helper. Searched:\n{}",
+                file_name,
+                paths
+                    .iter()
+                    .map(|p| format!("  {}", p.display()))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ))
+        })
+}
+
+/* Metadata of `path`. Unlike Path::exists() a path behind a directory without
+ * search permission is reported as such, not as missing.
+ */
+fn path_metadata(path: &Path) -> Result<fs::Metadata> {
+    fs::metadata(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound | ErrorKind::NotADirectory => {
+            ImgtoolError::MissingPath(path.to_path_buf())
+        }
+        ErrorKind::PermissionDenied => ImgtoolError::PermissionDenied(path.to_path_buf()),
+        _ => ImgtoolError::Io {
+            context: format!("Failed to access {}", path.display()),
+            source: e,
+        },
+    })
+}
+
+/* What an existing path is, for errors about a path of the wrong type */
+fn path_kind(meta: &fs::Metadata) -> &'static str {
+    if meta.is_file() {
+        "file"
+    } else if meta.is_dir() {
+        "directory"
+    } else {
+        "special file"
+    }
+}
+
 pub fn check_path_exists<P: AsRef<Path>>(path: P) -> Result<()> {
-    let path_ref = path.as_ref();
+    path_metadata(path.as_ref()).map(|_| ())
+}
 
-    if !path_ref.exists() {
-        let msg = format!(
-            "\x1b[31;1mError: Path or file not found: {:?}\x1b[0m",
-            path_ref
-        );
-        Err(anyhow!(msg))
+/// Like `check_path_exists`, the path must also be a regular file.
+pub fn check_file_exists<P: AsRef<Path>>(path: P) -> Result<()> {
+    let meta = path_metadata(path.as_ref())?;
+    if !meta.is_file() {
+        return Err(ImgtoolError::WrongPathType {
+            path: path.as_ref().to_path_buf(),
+            expected: "file",
+            found: path_kind(&meta),
+        });
+    }
+    Ok(())
+}
+
+/// Like `check_path_exists`, the path must also be a directory.
+pub fn check_dir_exists<P: AsRef<Path>>(path: P) -> Result<()> {
+    let meta = path_metadata(path.as_ref())?;
+    if !meta.is_dir() {
+        return Err(ImgtoolError::WrongPathType {
+            path: path.as_ref().to_path_buf(),
+            expected: "directory",
+            found: path_kind(&meta),
+        });
+    }
+    Ok(())
+}
+
+/// Decompress a `.gz` or `.zst` prebuilt image into the temp directory and return
+/// the decompressed copy, so digests and the flash image use the real firmware.
+/// Other files are returned unchanged. `tag` keeps the copies of images with the
+/// same file name apart.
+pub(crate) fn decompress_img_if_needed(path: PathBuf, tag: &str) -> Result<PathBuf> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    if ext != "gz" && ext != "zst" {
+        return Ok(path);
+    }
+
+    let file = fs::File::open(&path)
+        .io_context(|| format!("Failed to open compressed image {}", path.display()))?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let out = tmp_file_path(&format!("{}-{}", tag, stem));
+    let mut writer = std::io::BufWriter::new(
+        fs::File::create(&out).io_context(|| format!("Failed to create {}", out.display()))?,
+    );
+
+    /* Stream through the decoder, a large image is never held in memory */
+    if ext == "gz" {
+        std::io::copy(&mut flate2::read::GzDecoder::new(file), &mut writer)
     } else {
-        Ok(())
+        zstd::stream::read::Decoder::new(file).and_then(|mut d| std::io::copy(&mut d, &mut writer))
+    }
+    .and_then(|_| writer.flush())
+    .io_context(|| format!("Failed to decompress image {}", path.display()))?;
+    debug!("Decompressed {:?} to {:?}", path, out);
+
+    Ok(out)
+}
+
+/* Free space needed on top of the flash image, for the manifest and the flash
+ * image tool's own intermediate files.
+ */
+const FREE_SPACE_HEADROOM: u64 = 16 * 1024 * 1024;
+
+/// Fail before building when the temp dir or `output_dir` has less free space
+/// than `required` bytes plus headroom. A file system holding both needs room
+/// for both.
+pub(crate) fn check_free_space(output_dir: Option<&Path>, required: u64) -> Result<()> {
+    let required = required
+        .saturating_add(required / 10)
+        .saturating_add(FREE_SPACE_HEADROOM);
+    let mut dirs = vec![(
+        GLOBAL_TMP_DIR.path(),
+        "Set TMPDIR to a directory on a larger file system.",
+    )];
+    if let Some(dir) = output_dir {
+        dirs.push((
+            dir,
+            "Pass --flash and --out-dir paths on a larger file system.",
+        ));
+    }
+
+    let mut checked = Vec::new();
+    for (dir, hint) in dirs {
+        let fs_id = filesystem_id(dir);
+        let sharing = checked
+            .iter()
+            .filter(|id| fs_id.is_some() && **id == fs_id)
+            .count() as u64;
+        checked.push(fs_id);
+
+        let needed = required.saturating_mul(sharing + 1);
+        let available = fs2::available_space(dir)
+            .io_context(|| format!("Failed to query free space of {}", dir.display()))?;
+        debug!(
+            "Free space in {}: {} bytes, {} needed",
+            dir.display(),
+            available,
+            needed
+        );
+        if available < needed {
+            return Err(ImgtoolError::NoSpace {
+                dir: dir.to_path_buf(),
+                available,
+                required: needed,
+                hint,
+            });
+        }
     }
+
+    Ok(())
+}
+
+/* Path of a new file in the temp directory, tracked for remove_tmp_folder */
+pub(crate) fn tmp_file_path(name: &str) -> PathBuf {
+    let path = GLOBAL_TMP_DIR.path().join(name);
+    GLOBAL_TMP_FILES
+        .lock()
+        .expect("Temp file list poisoned")
+        .push(path.clone());
+    path
 }
 
+/// Remove the files imgtool created in the temp directory, then the directory
+/// itself. A directory still holding other files is left in place instead of
+/// being removed recursively.
 pub fn remove_tmp_folder() -> Result<()> {
-    let tmp_path = GLOBAL_TMP_DIR.path();
+    let files = std::mem::take(&mut *GLOBAL_TMP_FILES.lock().expect("Temp file list poisoned"));
+    remove_tmp_files(GLOBAL_TMP_DIR.path(), files)
+}
 
+fn remove_tmp_files(tmp_path: &Path, files: Vec<PathBuf>) -> Result<()> {
     if !tmp_path.exists() {
         return Ok(());
     }
 
-    fs::remove_dir_all(tmp_path)
-        .map_err(|e| anyhow!("Failed to remove temp dir {:?}: {}", tmp_path, e))?;
+    for file in files {
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ImgtoolError::Io {
+                    context: format!("Failed to remove temp file {:?}", file),
+                    source: e,
+                });
+            }
+            _ => debug!("Removed temporary file: {:?}", file),
+        }
+    }
+
+    if let Err(e) = fs::remove_dir(tmp_path) {
+        debug!("Keeping temporary directory {:?}: {}", tmp_path, e);
+        return Ok(());
+    }
 
     debug!("Removed temporary directory: {:?}", tmp_path);
     Ok(())
@@ -183,29 +909,107 @@ impl AuthManifestKeyConfigFromFile {
             || self.lms_pub_key.is_some()
             || self.lms_priv_key.is_some()
     }
+
+    pub fn has_ecc_key(&self) -> bool {
+        self.ecc_pub_key.is_some() || self.ecc_priv_key.is_some()
+    }
+
+    pub fn has_lms_key(&self) -> bool {
+        self.lms_pub_key.is_some() || self.lms_priv_key.is_some()
+    }
+
+    /// Key fields missing for the algorithms this config uses, an algorithm is
+    /// used when any of its keys is set. Signing locally needs the public and
+    /// the private key, signing with a sign helper (`*_helper`) only the
+    /// public key.
+    pub(crate) fn missing_key_fields(
+        &self,
+        ecc_helper: bool,
+        lms_helper: bool,
+    ) -> Vec<&'static str> {
+        let algorithms = [
+            (
+                ("ecc_pub_key", &self.ecc_pub_key),
+                ("ecc_priv_key", &self.ecc_priv_key),
+                ecc_helper,
+            ),
+            (
+                ("lms_pub_key", &self.lms_pub_key),
+                ("lms_priv_key", &self.lms_priv_key),
+                lms_helper,
+            ),
+        ];
+
+        let mut missing = Vec::new();
+        for ((pub_name, pub_key), (priv_name, priv_key), helper) in algorithms {
+            if pub_key.is_none() && priv_key.is_none() {
+                continue;
+            }
+            if pub_key.is_none() {
+                missing.push(pub_name);
+            }
+            if priv_key.is_none() && !helper {
+                missing.push(priv_name);
+            }
+        }
+        missing
+    }
+
+    /// Configured key files as (field name, file name) pairs.
+    pub fn key_files(&self) -> Vec<(&'static str, &String)> {
+        [
+            ("ecc_pub_key", &self.ecc_pub_key),
+            ("ecc_priv_key", &self.ecc_priv_key),
+            ("lms_pub_key", &self.lms_pub_key),
+            ("lms_priv_key", &self.lms_priv_key),
+        ]
+        .into_iter()
+        .filter_map(|(name, file)| file.as_ref().map(|f| (name, f)))
+        .collect()
+    }
 }
 
 impl AspeedAuthManifestConfigFromFile {
-    fn find_prebuilt_img_path(&mut self, path: &AspeedManifestCreationPath) -> Result<()> {
-        let dummy_path = GLOBAL_DUMMY_PATH.clone();
+    /// Path used for an image entry with an empty `file`. Strict configs treat
+    /// the empty entry as a mistake instead of substituting the dummy image.
+    fn empty_img_path(&self, entry: &str) -> Result<PathBuf> {
+        if self.manifest_config.strict_images.unwrap_or(false) {
+            return Err(ImgtoolError::Config(format!(
+                "Empty file for {} is not allowed when strict_images is set",
+                entry
+            )));
+        }
 
+        Ok(GLOBAL_DUMMY_PATH.clone())
+    }
+
+    fn find_prebuilt_img_path(&mut self, path: &AspeedManifestCreationPath) -> Result<()> {
         self.image_metadata_list = self
             .image_metadata_list
             .iter()
-            .map(|img| -> anyhow::Result<AspeedImageMetadataConfigFromFile> {
+            .map(|img| -> Result<AspeedImageMetadataConfigFromFile> {
+                img.provided_digest()?;
+                if img.digest_only() {
+                    debug!("Image {} is given by its digest only", img.label());
+                    return Ok(img.clone());
+                }
+
                 let new_file = if !img.file.is_empty() {
                     path.prebuilt_dir.join(&img.file)
                 } else {
-                    dummy_path.clone()
+                    self.empty_img_path(&format!("image {}", img.label()))?
                 };
                 debug!("New file path: {:?}", new_file);
-                check_path_exists(&new_file)?;
-                Ok(AspeedImageMetadataConfigFromFile {
+                check_file_exists(&new_file)?;
+                let new_file = decompress_img_if_needed(new_file, &format!("fw{}", img.fw_id))?;
+                let img = AspeedImageMetadataConfigFromFile {
                     file: new_file.to_string(),
                     ..(*img).clone()
-                })
+                };
+                img.digest_range()?;
+                Ok(img)
             })
-            .collect::<anyhow::Result<Vec<_>>>()?;
+            .collect::<Result<Vec<_>>>()?;
 
         if !self.image_runtime_list.caliptra_file.is_empty() {
             self.image_runtime_list.caliptra_file = path
@@ -213,9 +1017,15 @@ impl AspeedAuthManifestConfigFromFile {
                 .join(&self.image_runtime_list.caliptra_file)
                 .to_string();
         } else {
-            self.image_runtime_list.caliptra_file = dummy_path.to_string();
+            self.image_runtime_list.caliptra_file =
+                self.empty_img_path("caliptra_file")?.to_string();
         }
-        check_path_exists(&self.image_runtime_list.caliptra_file)?;
+        check_file_exists(&self.image_runtime_list.caliptra_file)?;
+        self.image_runtime_list.caliptra_file = decompress_img_if_needed(
+            PathBuf::from(&self.image_runtime_list.caliptra_file),
+            "caliptra",
+        )?
+        .to_string();
 
         if !self.image_runtime_list.mcu_file.is_empty() {
             self.image_runtime_list.mcu_file = path
@@ -223,36 +1033,171 @@ impl AspeedAuthManifestConfigFromFile {
                 .join(&self.image_runtime_list.mcu_file)
                 .to_string();
         } else {
-            self.image_runtime_list.mcu_file = dummy_path.to_string();
+            self.image_runtime_list.mcu_file = self.empty_img_path("mcu_file")?.to_string();
+        }
+        check_file_exists(&self.image_runtime_list.mcu_file)?;
+        self.image_runtime_list.mcu_file =
+            decompress_img_if_needed(PathBuf::from(&self.image_runtime_list.mcu_file), "mcu")?
+                .to_string();
+
+        for region in self.extra_flash_regions.iter_mut().flatten() {
+            region.file = path.prebuilt_dir.join(&region.file).to_string();
+            check_file_exists(&region.file)?;
         }
-        check_path_exists(&self.image_runtime_list.mcu_file)?;
 
         Ok(())
     }
 
-    pub(crate) fn new(
-        path: &AspeedManifestCreationPath,
-    ) -> Result<AspeedAuthManifestConfigFromFile> {
-        let config_str = std::fs::read_to_string(&path.aspeed_cfg).with_context(|| {
-            format!(
-                "Failed to read the config file {}",
-                path.aspeed_cfg.display()
-            )
-        })?;
+    /// Parse the config file only, without resolving any image or key path.
+    pub(crate) fn parse(aspeed_cfg: &Path) -> Result<AspeedAuthManifestConfigFromFile> {
+        let config_str = std::fs::read_to_string(aspeed_cfg)
+            .io_context(|| format!("Failed to read the config file {}", aspeed_cfg.display()))?;
 
-        let mut config: AspeedAuthManifestConfigFromFile = toml::from_str(&config_str)
-            .with_context(|| {
-                format!(
-                    "Failed to parse the config file {}",
-                    path.aspeed_cfg.display()
-                )
+        let mut config: AspeedAuthManifestConfigFromFile =
+            toml::from_str(&config_str).map_err(|source| ImgtoolError::ConfigParse {
+                path: aspeed_cfg.to_path_buf(),
+                source,
             })?;
 
+        /* The caliptra config only has the field, the tool sets the flag bit from it */
+        for img in config.image_metadata_list.iter_mut() {
+            if img.flags.contains(ImageMetadataFlags::IGNORE_AUTH_CHECK) {
+                img.ignore_auth_check = true;
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub(crate) fn new(
+        path: &AspeedManifestCreationPath,
+    ) -> Result<AspeedAuthManifestConfigFromFile> {
+        let mut config = Self::parse(&path.aspeed_cfg)?;
+        /* Before hashing anything, the metadata array can't hold more */
+        config.validate_image_count(None)?;
+        config.caliptra_version_range()?;
         config.find_prebuilt_img_path(path)?;
+        /* A bare program name is still looked up in PATH */
+        if let Some(hook) = config.post_build_hook.as_mut() {
+            let program = Path::new(&hook.program);
+            if program.is_relative() && program.components().count() > 1 {
+                hook.program = AspeedManifestCreationPath::config_base_dir(&path.aspeed_cfg)?
+                    .join(program)
+                    .to_string();
+            }
+        }
+        /* Same project name as the default paths were derived from */
+        if config.manifest_config.prj_name.is_none() {
+            config.manifest_config.prj_name = Some(AspeedManifestCreationPath::get_project_name(
+                &path.aspeed_cfg,
+            )?);
+        }
 
         Ok(config)
     }
 
+    /* Placeholder config with every field set, used for the config template */
+    fn template() -> Self {
+        let keys = |prefix: &str| AuthManifestKeyConfigFromFile {
+            ecc_pub_key: Some(format!("{}-ecc-pubk.pem", prefix)),
+            ecc_priv_key: Some(format!("{}-ecc-prvk.pem", prefix)),
+            lms_pub_key: Some(format!("{}-lms-pubk.pem", prefix)),
+            lms_priv_key: Some(format!("{}-lms-prvk.pem", prefix)),
+        };
+        let image = |file: &str, fw_id: u32, name: &str| AspeedImageMetadataConfigFromFile {
+            file: file.to_string(),
+            source: ImageSource::InRequest as u32,
+            fw_id,
+            name: Some(name.to_string()),
+            ignore_auth_check: false,
+            flags: ImageMetadataFlags::empty(),
+            load_stage: 0,
+            digest_offset: None,
+            digest_len: None,
+            digest: None,
+        };
+
+        Self {
+            manifest_config: AspeedAuthManifestGeneralConfigFromFile {
+                version: 1,
+                flags: ManifestFlags::empty(),
+                security_version: 1,
+                prj_name: Some("my-project".to_string()),
+                prebuilt_dir: None,
+                key_dir: None,
+                paths_relative_to_config: Some(false),
+                mcu_runtime_fw_id: Some(DEFAULT_MCU_RUNTIME_FW_ID),
+                strict_images: Some(false),
+                min_caliptra_version: None,
+                max_caliptra_version: None,
+                flash_size: None,
+                expected_magic: None,
+            },
+            vendor_fw_key_config: keys("vnd-fw"),
+            vendor_man_key_config: keys("vnd-man"),
+            owner_fw_key_config: Some(keys("own-fw")),
+            owner_man_key_config: Some(keys("own-man")),
+            owner_man_key_configs: None,
+            image_runtime_list: AspeedImageRuntimeConfigFromFile {
+                caliptra_file: "caliptra-fw.bin".to_string(),
+                mcu_file: "mcu-runtime.bin".to_string(),
+            },
+            image_metadata_list: vec![
+                image("mcu-runtime.bin", DEFAULT_MCU_RUNTIME_FW_ID, "mcu-runtime"),
+                image("soc-image.bin", 2, "soc-image"),
+            ],
+            extra_flash_regions: Some(vec![ExtraFlashRegionConfigFromFile {
+                file: "board-data.bin".to_string(),
+                offset: 0x0100_0000,
+            }]),
+            sign_helper: None,
+            post_build_hook: Some(PostBuildHookConfigFromFile {
+                program: "./publish.sh".to_string(),
+                args: vec!["{manifest}".to_string(), "{flash}".to_string()],
+            }),
+            tool_names: Some(ToolNamesConfigFromFile {
+                auth_manifest: Some(AUTH_MANIFEST_TOOL.to_string()),
+                flash: Some(FLASH_TOOL.to_string()),
+            }),
+        }
+    }
+
+    /// Commented example config serialized from a placeholder config, so the
+    /// template always follows the config schema.
+    pub(crate) fn template_toml() -> Result<String> {
+        let body = toml::to_string(&Self::template())?;
+
+        let mut out = String::from("# Licensed under the Apache-2.0 license\n");
+        let mut table = "";
+        let mut commented = std::collections::BTreeSet::new();
+        for line in body.lines().filter(|l| !l.is_empty()) {
+            let key = if line.starts_with('[') {
+                table = line.trim_matches(|c| c == '[' || c == ']');
+                out.push('\n');
+                ""
+            } else {
+                line.split(" = ").next().unwrap_or_default()
+            };
+
+            /* Array tables repeat their keys, comment the first entry only */
+            if let Some((t, k, text)) = TEMPLATE_COMMENTS
+                .iter()
+                .find(|(t, k, _)| *t == table && *k == key)
+            {
+                if commented.insert((*t, *k)) {
+                    out.push_str(&format!("# {}\n", text));
+                }
+            }
+            if TEMPLATE_EXAMPLE_TABLES.contains(&table) {
+                out.push_str("# ");
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+
+        Ok(out)
+    }
+
     pub(crate) fn has_any_key(&self) -> bool {
         self.vendor_fw_key_config.has_any_key()
             || self.vendor_man_key_config.has_any_key()
@@ -268,62 +1213,703 @@ impl AspeedAuthManifestConfigFromFile {
                 .unwrap_or(false)
     }
 
-    pub(crate) fn validate_key_dir_if_needed(
-        &self,
-        key_dir: Option<&Path>,
-    ) -> anyhow::Result<PathBuf> {
-        if self.has_any_key() {
-            let dir = key_dir
-                .ok_or_else(|| anyhow!("Key directory is required when keys are specified"))?;
-            check_path_exists(dir)?;
-            Ok(dir.to_path_buf())
-        } else {
-            let tmp_path = GLOBAL_TMP_DIR.path().to_path_buf();
-            Ok(tmp_path.join(""))
+    /// Resolve the owner manifest key slot `index` into `owner_man_key_config`,
+    /// so the caliptra config and the signing steps only see the selected key.
+    /// The single-key form only has slot 0.
+    pub(crate) fn select_owner_man_key(&mut self, index: usize) -> Result<()> {
+        let Some(mut slots) = self.owner_man_key_configs.take() else {
+            if index != 0 && self.owner_man_key_config.is_some() {
+                return Err(ImgtoolError::Config(format!(
+                    "Owner key index {} is out of range, [owner_man_key_config] has a single key",
+                    index
+                )));
+            }
+            if index != 0 {
+                return Err(ImgtoolError::Config(format!(
+                    "Owner key index {} given but no owner manifest key is configured",
+                    index
+                )));
+            }
+            return Ok(());
+        };
+
+        if self.owner_man_key_config.is_some() {
+            return Err(ImgtoolError::Config(
+                "Use either [owner_man_key_config] or [[owner_man_key_configs]], not both"
+                    .to_string(),
+            ));
+        }
+        if index >= slots.len() {
+            return Err(ImgtoolError::Config(format!(
+                "Owner key index {} is out of range, {} owner_man_key_configs configured",
+                index,
+                slots.len()
+            )));
         }
+
+        debug!("Using owner manifest key slot {}", index);
+        self.owner_man_key_config = Some(slots.swap_remove(index));
+        Ok(())
     }
 
-    pub(crate) fn save_caliptra_cfg(&self, path_mngt: &AspeedManifestCreationPath) -> Result<()> {
-        let mut cfg: AuthManifestConfigFromFile = AuthManifestConfigFromFile::default();
+    /// Names of the images that have one, by fw_id.
+    pub(crate) fn image_names(&self) -> std::collections::BTreeMap<u32, String> {
+        self.image_metadata_list
+            .iter()
+            .filter_map(|img| img.name.clone().map(|name| (img.fw_id, name)))
+            .collect()
+    }
 
-        /* Read the configuration from aspeed manifest configuration */
-        cfg.vendor_fw_key_config = self.vendor_fw_key_config.clone();
-        cfg.vendor_man_key_config = self.vendor_man_key_config.clone();
-        cfg.owner_fw_key_config = self.owner_fw_key_config.clone();
-        cfg.owner_man_key_config = self.owner_man_key_config.clone();
-        cfg.image_metadata_list = self
-            .image_metadata_list
+    /// fw_ids of the images whose empty `file` was replaced by the dummy image.
+    pub(crate) fn dummy_fw_ids(&self) -> std::collections::BTreeSet<u32> {
+        let dummy = GLOBAL_DUMMY_PATH.to_string();
+        self.image_metadata_list
             .iter()
-            .map(|img| {
-                let data = std::fs::read(&img.file).unwrap();
-                let data_align = pad_to_aligned(data, 0, 4);
-                let digest = hex::encode(Sha384::digest(&data_align));
-                ImageMetadataConfigFromFile {
-                    digest: digest,
-                    source: img.source,
-                    fw_id: img.fw_id,
-                    ignore_auth_check: img.ignore_auth_check,
-                    load_stage: img.load_stage,
-                }
-            })
-            .collect();
-        cfg.sign_helper = self.sign_helper.clone();
+            .filter(|img| img.file == dummy)
+            .map(|img| img.fw_id)
+            .collect()
+    }
+
+    /// Config fields whose flash image region would be backed by the dummy image.
+    /// The MCU runtime metadata entry isn't a flash region, `mcu_file` is.
+    pub(crate) fn dummy_flash_fields(&self) -> Vec<String> {
+        let dummy = GLOBAL_DUMMY_PATH.to_string();
+        let mcu_runtime_fw_id = self.mcu_runtime_fw_id();
+        let runtime = [
+            (
+                "image_runtime_list.caliptra_file",
+                &self.image_runtime_list.caliptra_file,
+            ),
+            (
+                "image_runtime_list.mcu_file",
+                &self.image_runtime_list.mcu_file,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, file)| **file == dummy)
+        .map(|(name, _)| name.to_string());
+        let images = self
+            .image_metadata_list
+            .iter()
+            .filter(|img| img.fw_id != mcu_runtime_fw_id && img.file == dummy)
+            .map(|img| format!("image_metadata_list {}", img.label()));
+
+        runtime.chain(images).collect()
+    }
+
+    pub(crate) fn owner_keys_absent(&self) -> bool {
+        self.owner_fw_key_config.is_none() && self.owner_man_key_config.is_none()
+    }
+
+    /// Check the image count fits the manifest metadata array, or the lower
+    /// `max_images` cap when given.
+    pub(crate) fn validate_image_count(&self, max_images: Option<usize>) -> Result<()> {
+        let count = self.image_metadata_list.len();
+        let (max, limit) = match max_images {
+            Some(max) => (max, "--max-images"),
+            None => (IMAGE_METADATA_MAX_COUNT, "the manifest metadata capacity"),
+        };
+        if count > max {
+            return Err(ImgtoolError::Config(format!(
+                "image_metadata_list has {} images, the limit is {} ({})",
+                count, max, limit
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check each owner key config sets the key fields its signing needs, see
+    /// `missing_key_fields`. Run after `select_owner_man_key`.
+    pub(crate) fn validate_owner_key_fields(&self) -> Result<()> {
+        let helper = self.sign_helper.clone().unwrap_or_default();
+        let sections = [
+            (
+                "owner_fw_key_config",
+                self.owner_fw_key_config.as_ref(),
+                helper.owner_ecc_fw_key_sign_helper.is_some(),
+                helper.owner_lms_fw_key_sign_helper.is_some(),
+            ),
+            (
+                "owner_man_key_config",
+                self.owner_man_key_config.as_ref(),
+                helper.owner_ecc_man_key_sign_helper.is_some(),
+                helper.owner_lms_man_key_sign_helper.is_some(),
+            ),
+        ];
+
+        let missing = sections
+            .into_iter()
+            .filter_map(|(section, keys, ecc_helper, lms_helper)| {
+                keys.map(|keys| (section, keys.missing_key_fields(ecc_helper, lms_helper)))
+            })
+            .flat_map(|(section, fields)| {
+                fields
+                    .into_iter()
+                    .map(move |field| format!("{}.{}", section, field))
+            })
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            return Err(ImgtoolError::Config(format!(
+                "Incomplete owner key config, missing {}. A key signed locally needs both its \
+                 public and private key, a key signed by a [sign_helper] only its public key",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn validate_fw_ids(&self) -> Result<()> {
+        let mut seen = std::collections::BTreeSet::new();
+        let dups = self
+            .image_metadata_list
+            .iter()
+            .filter(|img| !seen.insert(img.fw_id))
+            .map(|img| img.fw_id.to_string())
+            .collect::<Vec<_>>();
+
+        if !dups.is_empty() {
+            return Err(ImgtoolError::Config(format!(
+                "Duplicate fw_id in image_metadata_list: {}",
+                dups.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Project name selecting the default directories and output file names.
+    pub(crate) fn project_name(&self) -> String {
+        self.manifest_config
+            .prj_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PROJECT_NAME.to_string())
+    }
+
+    /// Magic every SoC manifest built or loaded with this config must start with.
+    pub(crate) fn expected_magic(&self) -> u32 {
+        self.manifest_config
+            .expected_magic
+            .unwrap_or(AUTH_MANIFEST_MAGIC)
+    }
+
+    /// Rough size of the flash image built from this config: the sum of its
+    /// input files, or the configured flash size when larger.
+    pub(crate) fn flash_size_estimate(&self) -> u64 {
+        let inputs = [
+            &self.image_runtime_list.caliptra_file,
+            &self.image_runtime_list.mcu_file,
+        ]
+        .into_iter()
+        .chain(self.image_metadata_list.iter().map(|img| &img.file))
+        .chain(self.extra_flash_regions.iter().flatten().map(|r| &r.file))
+        .filter_map(|file| fs::metadata(file).ok())
+        .map(|m| m.len())
+        .sum::<u64>();
+
+        inputs.max(self.manifest_config.flash_size.unwrap_or(0))
+    }
+
+    pub(crate) fn mcu_runtime_fw_id(&self) -> u32 {
+        self.manifest_config
+            .mcu_runtime_fw_id
+            .unwrap_or(DEFAULT_MCU_RUNTIME_FW_ID)
+    }
+
+    /// Use `file` as the MCU runtime image, in the runtime list and in the
+    /// image_metadata_list entry of the MCU runtime fw_id, whose digest is then
+    /// taken from `file`.
+    pub(crate) fn override_mcu_runtime(&mut self, file: String) {
+        let fw_id = self.mcu_runtime_fw_id();
+        for img in self
+            .image_metadata_list
+            .iter_mut()
+            .filter(|img| img.fw_id == fw_id)
+        {
+            img.file = file.clone();
+            img.digest = None;
+        }
+        self.image_runtime_list.mcu_file = file;
+    }
+
+    /// Check exactly one image carries the MCU runtime fw_id, or none when the
+    /// list is empty. That image is listed in the SoC manifest but not in the
+    /// flash `--soc-images` list.
+    pub(crate) fn validate_mcu_runtime_image(&self) -> Result<()> {
+        let fw_id = self.mcu_runtime_fw_id();
+        let count = self
+            .image_metadata_list
+            .iter()
+            .filter(|img| img.fw_id == fw_id)
+            .count();
+
+        match count {
+            1 => Ok(()),
+            /* A manifest without image metadata has no MCU runtime entry either */
+            0 if self.image_metadata_list.is_empty() => Ok(()),
+            0 => Err(ImgtoolError::Config(format!(
+                "No image in image_metadata_list has the MCU runtime fw_id {}",
+                fw_id
+            ))),
+            _ => Err(ImgtoolError::Config(format!(
+                "{} images in image_metadata_list have the MCU runtime fw_id {}",
+                count, fw_id
+            ))),
+        }
+    }
+
+    /// Order the image metadata list by fw_id for `--reproducible` builds. The
+    /// MCU runtime image stays the first entry.
+    pub(crate) fn sort_images_by_fw_id(&mut self) {
+        let fw_id = self.mcu_runtime_fw_id();
+        self.image_metadata_list
+            .sort_by_key(|img| (img.fw_id != fw_id, img.fw_id));
+    }
+
+    /// Refuse images with `ignore_auth_check` set unless `--allow-unauthenticated`
+    /// was given, so disabling authentication is never a config typo.
+    pub(crate) fn validate_auth_checks(&self, allow_unauthenticated: bool) -> Result<()> {
+        let unauthenticated = self
+            .image_metadata_list
+            .iter()
+            .filter(|img| img.ignore_auth_check)
+            .map(|img| format!("{} ({})", img.label(), img.file))
+            .collect::<Vec<_>>();
+        if unauthenticated.is_empty() {
+            return Ok(());
+        }
+
+        if !allow_unauthenticated {
+            return Err(ImgtoolError::Config(format!(
+                "ignore_auth_check is set for {}. Pass --allow-unauthenticated to build it anyway",
+                unauthenticated.join(", ")
+            )));
+        }
+
+        print_warning(&format!(
+            "Authentication disabled for {}",
+            unauthenticated.join(", ")
+        ));
+        Ok(())
+    }
+
+    /// Parsed `min_caliptra_version`/`max_caliptra_version`, rejecting an empty range.
+    pub(crate) fn caliptra_version_range(&self) -> Result<(VersionBound, VersionBound)> {
+        let general = &self.manifest_config;
+        let bound = |name: &str, v: &Option<String>| -> Result<VersionBound> {
+            v.as_deref()
+                .map(|s| {
+                    parse_version(s).ok_or_else(|| {
+                        ImgtoolError::Config(format!("Invalid {} {:?}, expected x.y.z", name, s))
+                    })
+                })
+                .transpose()
+        };
+        let min = bound("min_caliptra_version", &general.min_caliptra_version)?;
+        let max = bound("max_caliptra_version", &general.max_caliptra_version)?;
+
+        if let (Some(lo), Some(hi)) = (&min, &max) {
+            if lo > hi {
+                return Err(ImgtoolError::Config(format!(
+                    "min_caliptra_version {:?} is greater than max_caliptra_version {:?}",
+                    general.min_caliptra_version.as_deref().unwrap_or_default(),
+                    general.max_caliptra_version.as_deref().unwrap_or_default()
+                )));
+            }
+        }
+        Ok((min, max))
+    }
+
+    /// Check the version reported by the caliptra manifest tool against the
+    /// `min_caliptra_version`/`max_caliptra_version` range of the config.
+    pub(crate) fn check_caliptra_tool_version(
+        &self,
+        runner: &dyn ToolRunner,
+        tool: &Path,
+    ) -> Result<()> {
+        let general = &self.manifest_config;
+        if general.min_caliptra_version.is_none() && general.max_caliptra_version.is_none() {
+            return Ok(());
+        }
+
+        let (min, max) = self.caliptra_version_range()?;
+        let output = crate::tool::query_version(runner, tool)?;
+        let version = output
+            .split_whitespace()
+            .find_map(parse_version)
+            .ok_or_else(|| {
+                ImgtoolError::ToolVersion(format!(
+                    "Can't find a version in the output of {:?} --version: {:?}",
+                    tool,
+                    output.trim()
+                ))
+            })?;
+        debug!("{:?} version {:?}", tool, version);
+
+        if min.as_ref().is_some_and(|min| version < *min)
+            || max.as_ref().is_some_and(|max| version > *max)
+        {
+            return Err(ImgtoolError::ToolVersion(format!(
+                "{:?} version {} is outside the range {}..={} supported by the config",
+                tool,
+                version
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join("."),
+                general.min_caliptra_version.as_deref().unwrap_or("*"),
+                general.max_caliptra_version.as_deref().unwrap_or("*")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Fingerprint of everything the manifest is built from: the config file,
+    /// the image contents, the key files, the sign helpers, the prebuilt vendor
+    /// signatures, the `--prev-man` manifest and the caliptra tool version.
+    /// `extra` holds the command line settings that change the output.
+    pub(crate) fn input_fingerprint(
+        &self,
+        path: &AspeedManifestCreationPath,
+        runner: &dyn ToolRunner,
+        tool: &Path,
+        prev_man: Option<&Path>,
+        extra: &[String],
+    ) -> Result<String> {
+        let mut hasher = Sha384::new();
+        fingerprint_file(&mut hasher, "config", &path.aspeed_cfg)?;
+
+        /* Dummy images live in a per-run temp dir, so hash what identifies the entry, not its path.
+         * A digest-only image is covered by the config file.
+         */
+        for img in self
+            .image_metadata_list
+            .iter()
+            .filter(|img| !img.digest_only())
+        {
+            let label = format!(
+                "image {} {:?} {:?}",
+                img.fw_id, img.digest_offset, img.digest_len
+            );
+            fingerprint_file(&mut hasher, &label, Path::new(&img.file))?;
+        }
+
+        if let Some(key_dir) = path.key_dir.as_ref().filter(|_| self.has_any_key()) {
+            for (section, key_cfg) in self.key_configs() {
+                for (name, file) in key_cfg.key_files() {
+                    let label = format!("key {}.{}", section, name);
+                    fingerprint_file(&mut hasher, &label, &key_dir.join(file))?;
+                }
+            }
+        }
+
+        if let Some(helper) = &self.sign_helper {
+            for (name, program) in [
+                ("owner_ecc_fw_key", &helper.owner_ecc_fw_key_sign_helper),
+                ("owner_ecc_man_key", &helper.owner_ecc_man_key_sign_helper),
+                ("owner_lms_fw_key", &helper.owner_lms_fw_key_sign_helper),
+                ("owner_lms_man_key", &helper.owner_lms_man_key_sign_helper),
+            ] {
+                if let Some(program) = program {
+                    let label = format!("sign helper {} {}", name, program);
+                    fingerprint_file(&mut hasher, &label, Path::new(program))?;
+                }
+            }
+        }
+
+        for (name, sig) in soc_man::prebuilt_vendor_signatures() {
+            hasher.update(format!("prebuilt {} {}\0", name, sig.len()));
+            hasher.update(sig);
+        }
+
+        if let Some(prev_man) = prev_man {
+            fingerprint_file(&mut hasher, "prev-man", prev_man)?;
+        }
+
+        /* Fall back to the tool binary when it can't report a version */
+        match crate::tool::query_version(runner, tool) {
+            Ok(version) => hasher.update(format!("tool version {}", version.trim())),
+            Err(_) => fingerprint_file(&mut hasher, "tool", tool)?,
+        }
+
+        for arg in extra {
+            hasher.update(format!("arg {}\0", arg));
+        }
+
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    pub(crate) fn validate_key_dir_if_needed(&self, key_dir: Option<&Path>) -> Result<PathBuf> {
+        if self.has_any_key() {
+            let dir = key_dir.ok_or_else(|| {
+                ImgtoolError::Config(
+                    "Key directory is required when keys are specified".to_string(),
+                )
+            })?;
+            if !dir.exists() {
+                return Err(ImgtoolError::MissingDir {
+                    kind: "key",
+                    path: dir.to_path_buf(),
+                    prj: self.project_name(),
+                });
+            }
+            self.validate_key_files(dir)?;
+            Ok(dir.to_path_buf())
+        } else {
+            let tmp_path = GLOBAL_TMP_DIR.path().to_path_buf();
+            Ok(tmp_path.join(""))
+        }
+    }
+
+    pub(crate) fn key_configs(&self) -> Vec<(&'static str, &AuthManifestKeyConfigFromFile)> {
+        [
+            ("vendor_fw_key_config", Some(&self.vendor_fw_key_config)),
+            ("vendor_man_key_config", Some(&self.vendor_man_key_config)),
+            ("owner_fw_key_config", self.owner_fw_key_config.as_ref()),
+            ("owner_man_key_config", self.owner_man_key_config.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, cfg)| cfg.map(|c| (name, c)))
+        .collect()
+    }
+
+    /// Check every configured key file exists in the key directory, so a missing
+    /// key is reported before the caliptra tool is invoked.
+    pub(crate) fn validate_key_files(&self, key_dir: &Path) -> Result<()> {
+        for (section, key_cfg) in self.key_configs() {
+            for (name, file) in key_cfg.key_files() {
+                let key_path = key_dir.join(file);
+                debug!("Checking key {}.{}: {:?}", section, name, key_path);
+                if !key_path.exists() {
+                    return Err(ImgtoolError::MissingKey {
+                        section,
+                        name,
+                        path: key_path,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Image metadata as it goes into the caliptra config. A `digest` from the
+    /// config is used as is, unless `verify_digests` asks to check it against
+    /// the freshly computed digest of the file. Other digests are looked up in
+    /// `cache` before hashing the image on up to `threads` workers.
+    pub(crate) fn image_metadata(
+        &self,
+        cache: &mut DigestCache,
+        progress: &Progress,
+        threads: usize,
+        verify_digests: bool,
+    ) -> Result<Vec<ImageMetadataConfigFromFile>> {
+        /* The dummy and decompressed images live in a per-run temp dir,
+         * so never cache them.
+         */
+        let lookups = self
+            .image_metadata_list
+            .iter()
+            .map(|img| {
+                match img.provided_digest()? {
+                    Some(_) if verify_digests && img.digest_only() => {
+                        return Err(ImgtoolError::Config(format!(
+                            "--verify-digests: {} has a digest but no file to verify it against",
+                            img.label()
+                        )));
+                    }
+                    Some(digest) if !verify_digests => return Ok((img, (0, 0), Ok(digest))),
+                    _ => {}
+                }
+
+                let range = img.digest_range()?;
+                /* A digest to verify is compared to a fresh hash, never a cached one */
+                let cached = if Path::new(&img.file).starts_with(GLOBAL_TMP_DIR.path())
+                    || img.digest.is_some()
+                {
+                    Err(CacheMiss::default())
+                } else {
+                    cache.lookup(Path::new(&img.file), range)?
+                };
+                Ok((img, range, cached))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        /* Hash the images missing from the cache on up to `threads` workers */
+        let total = lookups.len();
+        let digests = parallel_map(&lookups, threads, |i, (img, range, cached)| -> Result<_> {
+            if let Ok(digest) = cached {
+                return Ok(digest.clone());
+            }
+
+            let name = Path::new(&img.file)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let len = range.1.max(1);
+            let digest = digest_aligned_file(Path::new(&img.file), *range, 4, |done| {
+                progress.update(&format!(
+                    "[{}/{}] Digesting {} {}%",
+                    i + 1,
+                    total,
+                    name,
+                    done * 100 / len
+                ))
+            })?;
+            Ok(hex::encode(digest))
+        });
+        progress.finish();
+        let digests = digests?;
+
+        if verify_digests {
+            let mismatched = lookups
+                .iter()
+                .zip(digests.iter())
+                .filter_map(|((img, _, _), digest)| match img.provided_digest() {
+                    Ok(Some(provided)) if provided != *digest => Some(format!(
+                        "{} ({}): config {}, computed {}",
+                        img.label(),
+                        img.file,
+                        provided,
+                        digest
+                    )),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if !mismatched.is_empty() {
+                return Err(ImgtoolError::Config(format!(
+                    "--verify-digests: digest mismatch for {}",
+                    mismatched.join("; ")
+                )));
+            }
+        }
+
+        let image_metadata_list = lookups
+            .into_iter()
+            .zip(digests)
+            .map(|((img, _, cached), digest)| {
+                if let Err(miss) = cached {
+                    cache.insert(miss, &digest);
+                }
+                ImageMetadataConfigFromFile {
+                    digest,
+                    source: img.source,
+                    fw_id: img.fw_id,
+                    ignore_auth_check: img.ignore_auth_check,
+                    load_stage: img.load_stage,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(image_metadata_list)
+    }
+
+    /// Write the caliptra manifest config derived from this config to `caliptra_cfg`,
+    /// with the image digests from `image_metadata`.
+    pub(crate) fn save_caliptra_cfg(
+        &self,
+        caliptra_cfg: &Path,
+        cache: &mut DigestCache,
+        progress: &Progress,
+        threads: usize,
+        verify_digests: bool,
+    ) -> Result<()> {
+        if self.owner_keys_absent() {
+            info!("No owner key config, owner key and signature fields are left zero.");
+        }
+
+        let image_metadata_list = self.image_metadata(cache, progress, threads, verify_digests)?;
+
+        /* Read the configuration from aspeed manifest configuration */
+        let cfg = AuthManifestConfigFromFile {
+            vendor_fw_key_config: self.vendor_fw_key_config.clone(),
+            vendor_man_key_config: self.vendor_man_key_config.clone(),
+            owner_fw_key_config: self.owner_fw_key_config.clone(),
+            owner_man_key_config: self.owner_man_key_config.clone(),
+            image_metadata_list,
+            sign_helper: self.sign_helper.clone(),
+        };
 
         /* Create the caliptra manifest read from aspeed manifest config */
-        let caliptra_cfg = &path_mngt.caliptra_cfg.unwrap_or_err();
         let mut out_file = std::fs::OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
             .open(caliptra_cfg)
-            .with_context(|| format!("Failed to create file {}", caliptra_cfg.display()))?;
+            .io_context(|| format!("Failed to create file {}", caliptra_cfg.display()))?;
 
-        out_file.write_all(toml::to_string(&cfg).unwrap().as_bytes())?;
+        out_file
+            .write_all(toml::to_string(&cfg)?.as_bytes())
+            .io_context(|| format!("Failed to write file {}", caliptra_cfg.display()))?;
 
         Ok(())
     }
 }
 
+/* A parsed min/max version of the config, None when unset */
+type VersionBound = Option<Vec<u32>>;
+
+/* Parse "x.y.z", with an optional "v" prefix and pre-release suffix, into its
+ * numeric parts. Missing parts count as zero so "1.2" == "1.2.0".
+ */
+fn parse_version(s: &str) -> Option<Vec<u32>> {
+    let s = s.strip_prefix('v').unwrap_or(s);
+    let s = s.split(['-', '+']).next().unwrap_or_default();
+    let mut parts = s
+        .split('.')
+        .map(|p| p.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.resize(3, 0);
+    Some(parts)
+}
+
+/// Merge an overlay config into `base`: tables merge key by key, other values
+/// replace the base value. `image_metadata_list` entries replace the base entry
+/// with the same `fw_id` field by field, entries with a new `fw_id` are appended.
+pub(crate) fn merge_config_value(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(Value::Array(images)) if key == "image_metadata_list" => {
+                        merge_image_list(images, value)
+                    }
+                    Some(old) => merge_config_value(old, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn merge_image_list(images: &mut Vec<Value>, overlay: Value) {
+    let Value::Array(overlay) = overlay else {
+        /* Not a list, let the config parser report it */
+        *images = vec![overlay];
+        return;
+    };
+
+    for image in overlay {
+        let fw_id = image.get("fw_id").cloned();
+        match images
+            .iter_mut()
+            .find(|old| fw_id.is_some() && old.get("fw_id") == fw_id.as_ref())
+        {
+            Some(old) => merge_config_value(old, image),
+            None => images.push(image),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct AspeedManifestCreationPath {
     pub prebuilt_dir: PathBuf,
@@ -341,72 +1927,241 @@ pub(crate) struct AspeedManifestCreationPath {
     pub flash_image: Option<PathBuf>,
 
     pub svn_sig: Option<PathBuf>,
+
+    pub tool_names: ToolNames,
+}
+
+/// Executable names of the manifest and flash tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ToolNames {
+    pub auth_manifest: String,
+
+    pub flash: String,
+}
+
+impl Default for ToolNames {
+    fn default() -> Self {
+        Self {
+            auth_manifest: AUTH_MANIFEST_TOOL.to_string(),
+            flash: FLASH_TOOL.to_string(),
+        }
+    }
 }
 
 impl AspeedManifestCreationPath {
+    /// Path of the manifest tool in the tool directory.
+    pub(crate) fn auth_manifest_tool(&self) -> PathBuf {
+        self.tool_dir
+            .join(tool_file_name(&self.tool_names.auth_manifest))
+    }
+
+    /// Path of the flash image tool in the tool directory.
+    pub(crate) fn flash_tool(&self) -> PathBuf {
+        self.tool_dir.join(tool_file_name(&self.tool_names.flash))
+    }
+
     fn get_config_value(aspeed_cfg: &PathBuf) -> Result<Value> {
-        let content = fs::read_to_string(aspeed_cfg)
-            .map_err(|e| anyhow::anyhow!("Failed to read file: {}", e))?;
+        let content = fs::read_to_string(aspeed_cfg).io_context(|| "Failed to read file")?;
 
         let value: Value =
-            toml::from_str(&content).map_err(|e| anyhow::anyhow!("Failed to parse TOML: {}", e))?;
+            toml::from_str(&content).map_err(|source| ImgtoolError::ConfigParse {
+                path: aspeed_cfg.clone(),
+                source,
+            })?;
 
         Ok(value)
     }
 
-    fn get_prebuilt_dir_path(args: &ArgMatches, prj: &String) -> Result<PathBuf> {
-        let prebuilt_dir = args
-            .get_one::<PathBuf>("prebuilt-dir")
-            .cloned()
-            .unwrap_or_def(PathBuf::from(format!("prebuilt/{}/", prj)));
-        check_path_exists(&prebuilt_dir)?;
-        Ok(prebuilt_dir)
+    /* The `--cfg` a merged config was built from, it names the project and
+     * holds the relative paths.
+     */
+    fn base_cfg(aspeed_cfg: &PathBuf) -> &PathBuf {
+        match MERGED_CFG.get() {
+            Some((merged, base)) if merged == aspeed_cfg => base,
+            _ => aspeed_cfg,
+        }
+    }
+
+    /// Directory relative paths in the config are resolved against: the config
+    /// file's directory with `paths_relative_to_config`, else the working
+    /// directory (an empty path). A config read from stdin has no directory.
+    pub(crate) fn config_base_dir(aspeed_cfg: &PathBuf) -> Result<PathBuf> {
+        let relative_to_config = Self::get_config_value(aspeed_cfg)?
+            .get("manifest_config")
+            .and_then(|v| v.get("paths_relative_to_config"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let aspeed_cfg = Self::base_cfg(aspeed_cfg);
+        if !relative_to_config || STDIN_CFG.get() == Some(aspeed_cfg) {
+            return Ok(PathBuf::new());
+        }
+
+        Ok(aspeed_cfg
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default())
+    }
+
+    /* prebuilt_dir or key_dir from [manifest_config], else `default`, see config_base_dir */
+    fn get_configured_dir(aspeed_cfg: &PathBuf, key: &str, default: PathBuf) -> Result<PathBuf> {
+        let value = Self::get_config_value(aspeed_cfg)?;
+        let dir = value
+            .get("manifest_config")
+            .and_then(|v| v.get(key))
+            .and_then(|v| v.as_str())
+            .map(PathBuf::from)
+            .unwrap_or(default);
+
+        Ok(Self::config_base_dir(aspeed_cfg)?.join(dir))
+    }
+
+    /* The command line wins, then the config, then prebuilt/<prj>/ */
+    fn get_prebuilt_dir_path(
+        args: &ArgMatches,
+        aspeed_cfg: &PathBuf,
+        prj: &String,
+    ) -> Result<PathBuf> {
+        if let Some(prebuilt_dir) = args.get_one::<PathBuf>("prebuilt-dir") {
+            check_dir_exists(prebuilt_dir)?;
+            return Ok(prebuilt_dir.clone());
+        }
+
+        let prebuilt_dir = Self::get_configured_dir(
+            aspeed_cfg,
+            "prebuilt_dir",
+            Path::new("prebuilt").join(prj).join(""),
+        )?;
+        match check_dir_exists(&prebuilt_dir) {
+            Err(ImgtoolError::MissingPath(_)) => Err(ImgtoolError::MissingDir {
+                kind: "prebuilt",
+                path: prebuilt_dir,
+                prj: prj.clone(),
+            }),
+            result => result.map(|_| prebuilt_dir),
+        }
     }
 
-    fn get_key_dir_path(args: &ArgMatches, prj: &String) -> Result<PathBuf> {
+    /* Same order as the prebuilt directory, existence is checked once a key is needed */
+    fn get_key_dir_path(args: &ArgMatches, aspeed_cfg: &PathBuf, prj: &String) -> Result<PathBuf> {
+        let default =
+            Self::get_configured_dir(aspeed_cfg, "key_dir", Path::new("key").join(prj).join(""))?;
         let key_dir = args
-            .get_one::<PathBuf>("key-dir")
+            .try_get_one::<PathBuf>("key-dir")
+            .ok()
+            .flatten()
             .cloned()
-            .unwrap_or_def(PathBuf::from(format!("key/{}/", prj)));
-        // Check later if a key has been assigned
-        // check_path_exists(&key_dir)?;
+            .unwrap_or_def(default);
         Ok(key_dir)
     }
 
-    fn get_aspeed_cfg_path(config: &String) -> Result<PathBuf> {
+    /* `--cfg`, with every `--cfg-overlay` merged into it in order. The merged
+     * config is written to the temp dir, see merge_config_value.
+     */
+    fn get_aspeed_cfg_path(args: &ArgMatches, config: &String) -> Result<PathBuf> {
+        let base = Self::get_base_cfg_path(config)?;
+        let overlays: Vec<&PathBuf> = match args.try_get_many::<PathBuf>("cfg-overlay") {
+            Ok(Some(overlays)) => overlays.collect(),
+            _ => return Ok(base),
+        };
+
+        MERGED_CFG
+            .get_or_try_init(|| {
+                let mut merged = Self::get_config_value(&base)?;
+                for overlay in overlays {
+                    check_path_exists(overlay)?;
+                    info!("Merging config overlay {}", overlay.display());
+                    merge_config_value(&mut merged, Self::get_config_value(overlay)?);
+                }
+
+                let path = tmp_file_path("merged-manifest.toml");
+                fs::write(&path, toml::to_string(&merged)?)
+                    .io_context(|| format!("Failed to write {}", path.display()))?;
+                Ok((path, base))
+            })
+            .map(|(merged, _)| merged.clone())
+    }
+
+    fn get_base_cfg_path(config: &String) -> Result<PathBuf> {
+        /* `--cfg -` reads the config from stdin into the temp dir, so everything
+         * after this works on a real file as with `--cfg <file>`.
+         */
+        if config == "-" {
+            return STDIN_CFG
+                .get_or_try_init(|| {
+                    let mut content = String::new();
+                    std::io::stdin()
+                        .read_to_string(&mut content)
+                        .io_context(|| "Failed to read the config from stdin")?;
+                    let path = tmp_file_path("stdin-manifest.toml");
+                    fs::write(&path, content)
+                        .io_context(|| format!("Failed to write {}", path.display()))?;
+                    Ok(path)
+                })
+                .cloned();
+        }
+
+        /* Not check_file_exists, `--cfg <(...)` passes a pipe */
         let path = PathBuf::from(config);
         check_path_exists(&path)?;
         Ok(path)
     }
 
-    fn get_out_folder_path(args: &ArgMatches) -> Result<PathBuf> {
-        let dir = if let Ok(Some(manifest_path)) = args.try_get_one::<PathBuf>("man") {
-            manifest_path
+    /// Directory of the intermediate files. `--out-dir` is created when missing,
+    /// otherwise the directory of `--man` or `--flash` is used.
+    /* Every output directory is created on demand, whether it was given on the
+     * command line or derived from another output path.
+     */
+    fn create_out_dir(dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)
+            .io_context(|| format!("Failed to create output directory {:?}", dir))
+    }
+
+    /* Directory of --flash for the derived outputs, not when --flash is a FIFO or
+     * device node, e.g. nothing is written next to /dev/<node>.
+     */
+    fn flash_arg_dir(args: &ArgMatches) -> Option<PathBuf> {
+        let flash = args.try_get_one::<PathBuf>("flash").ok().flatten()?;
+        if is_special_file(flash) {
+            return None;
+        }
+
+        Some(
+            flash
                 .parent()
                 .unwrap_or_else(|| Path::new("out"))
-                .to_path_buf()
-        } else if let Ok(Some(flash_path)) = args.try_get_one::<PathBuf>("flash") {
-            flash_path
+                .to_path_buf(),
+        )
+    }
+
+    fn get_out_folder_path(args: &ArgMatches) -> Result<PathBuf> {
+        let dir = if let Ok(Some(out_dir)) = args.try_get_one::<PathBuf>("out-dir") {
+            out_dir.clone()
+        } else if let Ok(Some(manifest_path)) = args.try_get_one::<PathBuf>("man") {
+            manifest_path
                 .parent()
                 .unwrap_or_else(|| Path::new("out"))
                 .to_path_buf()
+        } else if let Some(flash_dir) = Self::flash_arg_dir(args) {
+            flash_dir
         } else {
             PathBuf::from("out")
         };
 
-        if !dir.exists() {
-            return Err(anyhow::anyhow!(
-                "Output directory does not exist: {:?}",
-                dir
-            ));
-        }
-
+        Self::create_out_dir(&dir)?;
         Ok(dir)
     }
 
-    fn get_caliptra_cfg_path(args: &ArgMatches) -> Result<PathBuf> {
+    fn get_caliptra_cfg_path(args: &ArgMatches, prj: &str) -> Result<PathBuf> {
+        if let Ok(Some(caliptra_cfg_path)) = args.try_get_one::<PathBuf>("caliptra-cfg-out") {
+            if let Some(parent) = caliptra_cfg_path.parent() {
+                Self::create_out_dir(parent)?;
+            }
+            return Ok(caliptra_cfg_path.clone());
+        }
+
+        // Prefix with the project name so several projects can share one output directory
         let dir = Self::get_out_folder_path(args)?;
-        let caliptra_cfg_path = dir.join("caliptra-manifest.toml");
+        let caliptra_cfg_path = dir.join(format!("{}-caliptra-manifest.toml", prj));
         Ok(caliptra_cfg_path)
     }
 
@@ -419,18 +2174,14 @@ impl AspeedManifestCreationPath {
     fn get_manifest_path(args: &ArgMatches, prj: &String) -> Result<PathBuf> {
         let manifest = if let Ok(Some(manifest_path)) = args.try_get_one::<PathBuf>("man") {
             manifest_path.clone()
-        } else if let Ok(Some(flash_path)) = args.try_get_one::<PathBuf>("flash") {
-            let parent = flash_path.parent().unwrap_or_else(|| Path::new("out"));
-            parent.join(format!("{}-auth-manifest.bin", prj))
+        } else if let Some(flash_dir) = Self::flash_arg_dir(args) {
+            flash_dir.join(format!("{}-auth-manifest.bin", prj))
         } else {
-            PathBuf::from(format!("out/{}-auth-manifest.bin", prj))
+            Path::new("out").join(format!("{}-auth-manifest.bin", prj))
         };
 
         if let Some(parent) = manifest.parent() {
-            if !parent.exists() {
-                fs::create_dir_all(parent)
-                    .with_context(|| format!("Failed to create manifest directory {:?}", parent))?;
-            }
+            Self::create_out_dir(parent)?;
         }
 
         Ok(manifest)
@@ -441,95 +2192,1087 @@ impl AspeedManifestCreationPath {
         let flash = args
             .get_one::<PathBuf>("flash")
             .cloned()
-            .unwrap_or_else(|| PathBuf::from(format!("out/{}-flash-image.bin", prj)));
-
-        // Ensure that the parent directory exists; create it if necessary
-        if let Some(parent) = flash.parent() {
-            if !parent.exists() {
-                fs::create_dir_all(parent).map_err(|e| {
-                    anyhow::anyhow!("Failed to create output directory {:?}: {}", parent, e)
-                })?;
-            }
-        }
+            .unwrap_or_else(|| Path::new("out").join(format!("{}-flash-image.bin", prj)));
 
-        // Remove the existing file (if any) to avoid conflicts
-        if flash.is_file() {
-            fs::remove_file(&flash).map_err(|e| {
-                anyhow::anyhow!("Failed to remove existing flash file {:?}: {}", flash, e)
-            })?;
+        // A FIFO or device node is written in place, its directory exists
+        if let Some(parent) = flash.parent().filter(|_| !is_special_file(&flash)) {
+            Self::create_out_dir(parent)?;
         }
 
+        // An existing file is kept until the new image is renamed over it
+
         Ok(flash)
     }
 
-    fn get_tool_path() -> PathBuf {
-        let cur_exe = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    /* The environment wins, then [tool_names], then the default names */
+    fn get_tool_names(aspeed_cfg: &PathBuf) -> Result<ToolNames> {
+        let value = Self::get_config_value(aspeed_cfg)?;
+        let name = |key: &str, env_name: &str, default: &str| {
+            if let Some(name) = env::var(env_name).ok().filter(|name| !name.is_empty()) {
+                debug!("Using {} tool name from {}", key, env_name);
+                return name;
+            }
+            value
+                .get("tool_names")
+                .and_then(|v| v.get(key))
+                .and_then(|v| v.as_str())
+                .unwrap_or(default)
+                .to_string()
+        };
+
+        Ok(ToolNames {
+            auth_manifest: name("auth_manifest", AUTH_MANIFEST_TOOL_ENV, AUTH_MANIFEST_TOOL),
+            flash: name("flash", FLASH_TOOL_ENV, FLASH_TOOL),
+        })
+    }
+
+    fn get_tool_path(args: &ArgMatches, names: &ToolNames) -> Result<PathBuf> {
+        /* --tool-dir wins, then the environment, then the auto-detected directories */
+        if let Ok(Some(tool_dir)) = args.try_get_one::<PathBuf>("tool-dir") {
+            return Ok(tool_dir.clone());
+        }
+        if let Some(tool_dir) = env::var_os(TOOL_DIR_ENV) {
+            debug!("Using tool directory from {}", TOOL_DIR_ENV);
+            return Ok(PathBuf::from(tool_dir));
+        }
+
+        let cur_exe = env::current_exe()
+            .io_context(|| "Failed to locate the running executable")?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
         let paths = [
             PathBuf::from("./target/release"),
             PathBuf::from("./target/debug"),
+            cur_exe,
         ];
 
+        let mut searched = Vec::new();
         for path in paths.iter() {
-            let man_tool = path.join("caliptra-auth-manifest-app");
-            let flash_tool = path.join("xtask");
+            let missing = [names.auth_manifest.as_str(), names.flash.as_str()]
+                .into_iter()
+                .filter(|tool| !path.join(tool_file_name(tool)).is_file())
+                .collect::<Vec<_>>();
 
-            if man_tool.is_file() && flash_tool.is_file() {
-                return path.to_path_buf();
+            if missing.is_empty() {
+                return Ok(path.to_path_buf());
             }
+            searched.push(format!(
+                "  {} (missing {})",
+                path.display(),
+                missing.join(", ")
+            ));
         }
 
-        cur_exe
+        Err(ImgtoolError::ToolNotFound(format!(
+            "Caliptra tools not found, use --tool-dir or {} to locate them. Searched:\n{}",
+            TOOL_DIR_ENV,
+            searched.join("\n")
+        )))
     }
 
-    fn get_project_name(aspeed_cfg: &PathBuf) -> Result<String> {
+    fn get_configured_project_name(aspeed_cfg: &PathBuf) -> Result<Option<String>> {
         let value = Self::get_config_value(aspeed_cfg)?;
-        // try to get "manifest_config" -> "prj_name" else default to "default_project"
         let project_name = value
             .get("manifest_config")
             .and_then(|v| v.get("prj_name"))
             .and_then(|v| v.as_str())
-            .unwrap_or("default_project");
+            .map(|v| v.to_string());
 
-        Ok(project_name.to_string())
+        Ok(project_name)
+    }
+
+    /* prj_name, else the config file name, else "default_project". The file
+     * name of a config read from stdin means nothing to the user.
+     */
+    fn derive_project_name(prj_name: Option<String>, aspeed_cfg: Option<&Path>) -> String {
+        if let Some(prj) = prj_name {
+            debug!("Project name {:?} from prj_name", prj);
+            return prj;
+        }
+
+        let stem = aspeed_cfg
+            .and_then(|cfg| cfg.file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .filter(|stem| !stem.is_empty());
+        match stem {
+            Some(prj) => {
+                info!("No prj_name, using {:?} from the config file name", prj);
+                prj
+            }
+            None => {
+                info!("No prj_name, using {:?}", DEFAULT_PROJECT_NAME);
+                DEFAULT_PROJECT_NAME.to_string()
+            }
+        }
+    }
+
+    fn get_project_name(aspeed_cfg: &PathBuf) -> Result<String> {
+        let base = Self::base_cfg(aspeed_cfg);
+        let from_stdin = STDIN_CFG.get() == Some(base);
+
+        Ok(Self::derive_project_name(
+            Self::get_configured_project_name(aspeed_cfg)?,
+            Some(base.as_path()).filter(|_| !from_stdin),
+        ))
     }
 
     pub(crate) fn new_manifest(args: &ArgMatches) -> Result<AspeedManifestCreationPath> {
         let config: &String = args
             .get_one::<String>("cfg")
-            .with_context(|| "cfg arg not specified")?;
+            .ok_or_else(|| ImgtoolError::Config("cfg arg not specified".to_string()))?;
 
-        let aspeed_cfg = Self::get_aspeed_cfg_path(config)?;
+        let aspeed_cfg = Self::get_aspeed_cfg_path(args, config)?;
         let prj = Self::get_project_name(&aspeed_cfg)?;
+        let caliptra_cfg = Self::get_caliptra_cfg_path(args, &prj)?;
+        let tool_names = Self::get_tool_names(&aspeed_cfg)?;
 
         Ok(AspeedManifestCreationPath {
-            prebuilt_dir: Self::get_prebuilt_dir_path(&args, &prj)?,
-            tool_dir: Self::get_tool_path(),
-            key_dir: Some(Self::get_key_dir_path(&args, &prj)?),
+            prebuilt_dir: Self::get_prebuilt_dir_path(args, &aspeed_cfg, &prj)?,
+            tool_dir: Self::get_tool_path(args, &tool_names)?,
+            key_dir: Some(Self::get_key_dir_path(args, &aspeed_cfg, &prj)?),
             aspeed_cfg,
-            caliptra_cfg: Some(Self::get_caliptra_cfg_path(&args)?),
+            caliptra_cfg: Some(caliptra_cfg),
             manifest: Some(Self::get_manifest_path(args, &prj)?),
             flash_image: None,
             svn_sig: Some(Self::get_svn_sig_path(args)?),
+            tool_names,
+        })
+    }
+
+    pub(crate) fn new_check(args: &ArgMatches) -> Result<AspeedManifestCreationPath> {
+        let config: &String = args
+            .get_one::<String>("cfg")
+            .ok_or_else(|| ImgtoolError::Config("cfg arg not specified".to_string()))?;
+
+        let aspeed_cfg = Self::get_aspeed_cfg_path(args, config)?;
+        let prj = Self::get_project_name(&aspeed_cfg)?;
+
+        Ok(AspeedManifestCreationPath {
+            prebuilt_dir: Self::get_prebuilt_dir_path(args, &aspeed_cfg, &prj)?,
+            /* config-check never runs the external tools */
+            tool_dir: PathBuf::new(),
+            key_dir: Some(Self::get_key_dir_path(args, &aspeed_cfg, &prj)?),
+            aspeed_cfg,
+            caliptra_cfg: None,
+            manifest: None,
+            flash_image: None,
+            svn_sig: None,
+            tool_names: ToolNames::default(),
+        })
+    }
+
+    /* validate-keys only reads the config and the key files */
+    pub(crate) fn new_keys(args: &ArgMatches) -> Result<AspeedManifestCreationPath> {
+        let config: &String = args
+            .get_one::<String>("cfg")
+            .ok_or_else(|| ImgtoolError::Config("cfg arg not specified".to_string()))?;
+
+        let aspeed_cfg = Self::get_aspeed_cfg_path(args, config)?;
+        let prj = Self::get_project_name(&aspeed_cfg)?;
+
+        Ok(AspeedManifestCreationPath {
+            prebuilt_dir: PathBuf::new(),
+            tool_dir: PathBuf::new(),
+            key_dir: Some(Self::get_key_dir_path(args, &aspeed_cfg, &prj)?),
+            aspeed_cfg,
+            caliptra_cfg: None,
+            manifest: None,
+            flash_image: None,
+            svn_sig: None,
+            tool_names: ToolNames::default(),
         })
     }
 
     pub(crate) fn new_flash(args: &ArgMatches) -> Result<AspeedManifestCreationPath> {
         let config: &String = args
             .get_one::<String>("cfg")
-            .with_context(|| "cfg arg not specified")?;
+            .ok_or_else(|| ImgtoolError::Config("cfg arg not specified".to_string()))?;
 
-        let aspeed_cfg = Self::get_aspeed_cfg_path(config)?;
+        let aspeed_cfg = Self::get_aspeed_cfg_path(args, config)?;
         let prj = Self::get_project_name(&aspeed_cfg)?;
+        let tool_names = Self::get_tool_names(&aspeed_cfg)?;
 
         Ok(AspeedManifestCreationPath {
-            prebuilt_dir: Self::get_prebuilt_dir_path(&args, &prj)?,
-            tool_dir: Self::get_tool_path(),
+            prebuilt_dir: Self::get_prebuilt_dir_path(args, &aspeed_cfg, &prj)?,
+            tool_dir: Self::get_tool_path(args, &tool_names)?,
             key_dir: None,
             aspeed_cfg,
             caliptra_cfg: None,
             manifest: Some(Self::get_manifest_path(args, &prj)?),
             flash_image: Some(Self::get_flash_image_path(args, &prj)?),
             svn_sig: None,
+            tool_names,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{arg, value_parser, Command};
+
+    fn write_cfg(dir: &Path, manifest_config: &str) -> PathBuf {
+        let cfg = dir.join("manifest.toml");
+        fs::write(&cfg, format!("[manifest_config]\n{}", manifest_config)).unwrap();
+        cfg
+    }
+
+    fn prebuilt_args(argv: &[&str]) -> ArgMatches {
+        Command::new("test")
+            .arg(
+                arg!(--"prebuilt-dir" <DIR> "prebuilt directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"key-dir" <DIR> "key directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .get_matches_from(std::iter::once("test").chain(argv.iter().copied()))
+    }
+
+    fn out_args(argv: &[&str]) -> ArgMatches {
+        Command::new("test")
+            .arg(
+                arg!(--man <FILE> "manifest")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"caliptra-cfg-out" <FILE> "caliptra config")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .get_matches_from(std::iter::once("test").chain(argv.iter().copied()))
+    }
+
+    #[test]
+    fn caliptra_cfg_is_named_after_the_project() {
+        let tmp = tempfile::tempdir().unwrap();
+        let man = tmp.path().join("auth-manifest.bin");
+        let args = out_args(&["--man", man.to_str().unwrap()]);
+        let caliptra_cfg = |cfg: PathBuf| {
+            let prj = AspeedManifestCreationPath::get_project_name(&cfg).unwrap();
+            AspeedManifestCreationPath::get_caliptra_cfg_path(&args, &prj).unwrap()
+        };
+        let cfg = |prj: &str| {
+            let path = tmp.path().join(format!("{}.toml", prj));
+            fs::write(
+                &path,
+                format!("[manifest_config]\nprj_name = \"{}\"\n", prj),
+            )
+            .unwrap();
+            path
+        };
+
+        assert_eq!(
+            caliptra_cfg(cfg("board-a")),
+            tmp.path().join("board-a-caliptra-manifest.toml")
+        );
+        assert_eq!(
+            caliptra_cfg(cfg("board-b")),
+            tmp.path().join("board-b-caliptra-manifest.toml")
+        );
+
+        /* Without prj_name the project is named after the config file, as for the manifest */
+        let unnamed = tmp.path().join("board-c.toml");
+        fs::write(&unnamed, "[manifest_config]\n").unwrap();
+        assert_eq!(
+            caliptra_cfg(unnamed),
+            tmp.path().join("board-c-caliptra-manifest.toml")
+        );
+
+        let override_path = tmp.path().join("caliptra.toml");
+        let args = out_args(&["--caliptra-cfg-out", override_path.to_str().unwrap()]);
+        let path = AspeedManifestCreationPath::get_caliptra_cfg_path(&args, "board-d").unwrap();
+        assert_eq!(path, override_path);
+    }
+
+    #[test]
+    fn missing_default_prebuilt_dir_suggests_the_flag() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cfg = write_cfg(tmp.path(), "prj_name = \"no-such-project\"\n");
+        let prj = AspeedManifestCreationPath::get_project_name(&cfg).unwrap();
+
+        let err =
+            AspeedManifestCreationPath::get_prebuilt_dir_path(&prebuilt_args(&[]), &cfg, &prj)
+                .unwrap_err();
+        match &err {
+            ImgtoolError::MissingDir { kind, path, prj } => {
+                assert_eq!(*kind, "prebuilt");
+                assert_eq!(
+                    path,
+                    &Path::new("prebuilt").join("no-such-project").join("")
+                );
+                assert_eq!(prj, "no-such-project");
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+        let msg = err.to_string();
+        assert!(msg.contains("--prebuilt-dir"), "{}", msg);
+        assert!(msg.contains("no-such-project"), "{}", msg);
+    }
+
+    #[test]
+    fn missing_default_prebuilt_dir_without_prj_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cfg = write_cfg(tmp.path(), "version = 1\n");
+        let prj = AspeedManifestCreationPath::get_project_name(&cfg).unwrap();
+        assert_eq!(prj, "manifest");
+
+        let err =
+            AspeedManifestCreationPath::get_prebuilt_dir_path(&prebuilt_args(&[]), &cfg, &prj)
+                .unwrap_err();
+        assert!(
+            matches!(err, ImgtoolError::MissingDir { ref prj, .. } if prj == "manifest"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn configured_prebuilt_dir_wins_over_the_default() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prebuilt = tmp.path().join("images");
+        fs::create_dir(&prebuilt).unwrap();
+        let cfg = write_cfg(
+            tmp.path(),
+            &format!(
+                "prj_name = \"no-such-project\"\nprebuilt_dir = {:?}\n",
+                prebuilt.to_str().unwrap()
+            ),
+        );
+        let prj = AspeedManifestCreationPath::get_project_name(&cfg).unwrap();
+
+        let dir =
+            AspeedManifestCreationPath::get_prebuilt_dir_path(&prebuilt_args(&[]), &cfg, &prj)
+                .unwrap();
+        assert_eq!(dir, prebuilt);
+    }
+
+    #[test]
+    fn missing_prebuilt_dir_flag_is_reported_as_is() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cfg = write_cfg(tmp.path(), "prj_name = \"no-such-project\"\n");
+        let missing = tmp.path().join("missing");
+        let args = prebuilt_args(&["--prebuilt-dir", missing.to_str().unwrap()]);
+
+        let err = AspeedManifestCreationPath::get_prebuilt_dir_path(&args, &cfg, &"x".to_string())
+            .unwrap_err();
+        assert!(
+            matches!(err, ImgtoolError::MissingPath(ref p) if *p == missing),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn prebuilt_dir_flag_of_the_wrong_type() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cfg = write_cfg(tmp.path(), "prj_name = \"x\"\n");
+        let args = prebuilt_args(&["--prebuilt-dir", cfg.to_str().unwrap()]);
+
+        let err = AspeedManifestCreationPath::get_prebuilt_dir_path(&args, &cfg, &"x".to_string())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ImgtoolError::WrongPathType {
+                    expected: "directory",
+                    found: "file",
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn prebuilt_dir_flag_wins_over_the_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let configured = tmp.path().join("configured");
+        let flag = tmp.path().join("flag");
+        fs::create_dir(&configured).unwrap();
+        fs::create_dir(&flag).unwrap();
+        let cfg = write_cfg(
+            tmp.path(),
+            &format!("prebuilt_dir = {:?}\n", configured.to_str().unwrap()),
+        );
+        let args = prebuilt_args(&["--prebuilt-dir", flag.to_str().unwrap()]);
+
+        let dir = AspeedManifestCreationPath::get_prebuilt_dir_path(&args, &cfg, &"x".to_string())
+            .unwrap();
+        assert_eq!(dir, flag);
+    }
+
+    #[test]
+    fn key_dir_precedence() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prj = "no-such-project".to_string();
+        let plain = write_cfg(tmp.path(), "version = 1\n");
+        let dir = AspeedManifestCreationPath::get_key_dir_path(&prebuilt_args(&[]), &plain, &prj)
+            .unwrap();
+        assert_eq!(dir, Path::new("key").join(&prj).join(""));
+
+        let configured = write_cfg(tmp.path(), "key_dir = \"cfg-keys\"\n");
+        let dir =
+            AspeedManifestCreationPath::get_key_dir_path(&prebuilt_args(&[]), &configured, &prj)
+                .unwrap();
+        assert_eq!(dir, PathBuf::from("cfg-keys"));
+
+        let args = prebuilt_args(&["--key-dir", "flag-keys"]);
+        let dir = AspeedManifestCreationPath::get_key_dir_path(&args, &configured, &prj).unwrap();
+        assert_eq!(dir, PathBuf::from("flag-keys"));
+    }
+
+    fn parse_image(source: &str) -> std::result::Result<AspeedImageMetadataConfigFromFile, String> {
+        toml::from_str(&format!(
+            "file = \"a.bin\"\nsource = {}\nfw_id = 2\nignore_auth_check = false\nload_stage = 0\n",
+            source
+        ))
+        .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn image_source_accepts_numbers_and_names() {
+        assert_eq!(
+            parse_image("1").unwrap().source,
+            ImageSource::InRequest as u32
+        );
+        assert_eq!(parse_image("2").unwrap().source, ImageSource::ShaAcc as u32);
+        assert_eq!(
+            parse_image("\"sha_acc\"").unwrap().source,
+            ImageSource::ShaAcc as u32
+        );
+    }
+
+    #[test]
+    fn invalid_image_source_lists_the_valid_values() {
+        for source in ["0", "3", "\"flash\""] {
+            let err = parse_image(source).unwrap_err();
+            assert!(err.contains("invalid source"), "{}", err);
+            assert!(
+                err.contains("1 (\"in_request\"), 2 (\"sha_acc\")"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn load_stage_must_be_a_known_stage() {
+        let parse = |stage: &str| {
+            toml::from_str::<AspeedImageMetadataConfigFromFile>(&format!(
+                "file = \"a.bin\"\nsource = 1\nfw_id = 2\nignore_auth_check = false\nload_stage = {}\n",
+                stage
+            ))
+            .map(|img| img.load_stage)
+            .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(parse("0").unwrap(), 0);
+        assert_eq!(parse("2").unwrap(), 2);
+        let err = parse("10").unwrap_err();
+        assert!(
+            err.contains("invalid load_stage 10, valid values are 0 to 2"),
+            "{}",
+            err
+        );
+        assert!(parse("\"runtime\"").is_err());
+    }
+
+    #[test]
+    fn mcu_runtime_override_replaces_its_manifest_entry() {
+        let image = |file: &str, fw_id| AspeedImageMetadataConfigFromFile {
+            file: file.to_string(),
+            fw_id,
+            ..Default::default()
+        };
+        let mut cfg = AspeedAuthManifestConfigFromFile {
+            image_metadata_list: vec![
+                image("mcu-runtime.bin", DEFAULT_MCU_RUNTIME_FW_ID),
+                image("soc-image.bin", 2),
+            ],
+            ..Default::default()
+        };
+        cfg.image_metadata_list[0].digest = Some("00".repeat(48));
+        cfg.override_mcu_runtime("new-mcu-runtime.bin".to_string());
+
+        assert_eq!(cfg.image_runtime_list.mcu_file, "new-mcu-runtime.bin");
+        assert_eq!(cfg.image_metadata_list[0].file, "new-mcu-runtime.bin");
+        assert!(cfg.image_metadata_list[0].digest.is_none());
+        assert_eq!(cfg.image_metadata_list[1].file, "soc-image.bin");
+    }
+
+    #[test]
+    fn compressed_images_decompress_to_the_plain_bytes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let plain = crate::selftest::pattern(64 * 1024, 0);
+        let plain_path = tmp.path().join("fw.bin");
+        fs::write(&plain_path, &plain).unwrap();
+
+        let gz = tmp.path().join("fw.bin.gz");
+        let mut enc =
+            flate2::write::GzEncoder::new(fs::File::create(&gz).unwrap(), Default::default());
+        enc.write_all(&plain).unwrap();
+        enc.finish().unwrap();
+        let zst = tmp.path().join("fw.bin.zst");
+        fs::write(&zst, zstd::stream::encode_all(&plain[..], 0).unwrap()).unwrap();
+
+        for (tag, compressed) in [("gz", gz), ("zst", zst)] {
+            let out = decompress_img_if_needed(compressed.clone(), tag).unwrap();
+            assert_ne!(out, compressed);
+            assert_eq!(fs::read(&out).unwrap(), plain, "{}", tag);
+        }
+
+        /* Other files are used as they are */
+        assert_eq!(
+            decompress_img_if_needed(plain_path.clone(), "plain").unwrap(),
+            plain_path
+        );
+
+        let bad = tmp.path().join("bad.bin.gz");
+        fs::write(&bad, b"not gzip").unwrap();
+        assert!(decompress_img_if_needed(bad, "bad").is_err());
+    }
+
+    #[test]
+    fn tmp_cleanup_keeps_files_it_did_not_create() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ours = tmp.path().join("dummy.bin");
+        let theirs = tmp.path().join("notes.txt");
+        fs::write(&ours, b"").unwrap();
+        fs::write(&theirs, b"keep me").unwrap();
+
+        remove_tmp_files(tmp.path(), vec![ours.clone()]).unwrap();
+        assert!(!ours.exists());
+        assert_eq!(fs::read(&theirs).unwrap(), b"keep me");
+
+        /* Once only imgtool's files were in it, the directory goes too */
+        fs::remove_file(&theirs).unwrap();
+        remove_tmp_files(tmp.path(), vec![ours]).unwrap();
+        assert!(!tmp.path().exists());
+    }
+
+    #[test]
+    fn template_leaves_example_regions_commented_out() {
+        let template = AspeedAuthManifestConfigFromFile::template_toml().unwrap();
+        assert!(
+            template.contains("\n# [[extra_flash_regions]]\n"),
+            "{}",
+            template
+        );
+        assert!(
+            template.contains("\n# file = \"board-data.bin\"\n"),
+            "{}",
+            template
+        );
+
+        assert!(
+            template.contains("\n# [post_build_hook]\n")
+                && template.contains("\n# program = \"./publish.sh\"\n"),
+            "{}",
+            template
+        );
+
+        let cfg: AspeedAuthManifestConfigFromFile = toml::from_str(&template).unwrap();
+        assert!(cfg.extra_flash_regions.is_none());
+        assert!(cfg.post_build_hook.is_none());
+        assert_eq!(cfg.image_metadata_list.len(), 2);
+    }
+
+    #[test]
+    fn post_build_hook_flash_needs_create_auth_flash() {
+        let hook = PostBuildHookConfigFromFile {
+            program: "publish.sh".to_string(),
+            args: vec!["{manifest}".to_string(), "--out={flash}".to_string()],
+        };
+        assert!(hook.validate_args(true).is_ok());
+        let err = hook.validate_args(false).unwrap_err();
+        assert!(err.to_string().contains("--out={flash}"), "{}", err);
+
+        let args = hook.expand_args(Path::new("m.bin"), Some(Path::new("f.bin")));
+        assert_eq!(args, ["m.bin", "--out=f.bin"]);
+    }
+
+    #[test]
+    fn parse_version_forms() {
+        assert_eq!(parse_version("1.2.3"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("v1.2"), Some(vec![1, 2, 0]));
+        assert_eq!(parse_version("2"), Some(vec![2, 0, 0]));
+        assert_eq!(parse_version("1.2.3-rc1"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("1.2.3+build.7"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("1.x"), None);
+        assert_eq!(parse_version(""), None);
+        assert!(parse_version("1.10.0") > parse_version("1.9.9"));
+    }
+
+    #[test]
+    fn caliptra_version_range_must_not_be_empty() {
+        let cfg = |min: &str, max: &str| AspeedAuthManifestConfigFromFile {
+            manifest_config: AspeedAuthManifestGeneralConfigFromFile {
+                min_caliptra_version: Some(min.to_string()),
+                max_caliptra_version: Some(max.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(cfg("1.2.0", "1.2").caliptra_version_range().is_ok());
+        assert!(cfg("1.0", "2.0").caliptra_version_range().is_ok());
+        let err = cfg("2.0", "1.9.9").caliptra_version_range().unwrap_err();
+        assert!(err.to_string().contains("greater than"), "{}", err);
+        let err = cfg("1.0", "latest").caliptra_version_range().unwrap_err();
+        assert!(err.to_string().contains("max_caliptra_version"), "{}", err);
+    }
+
+    #[test]
+    fn manifest_flags_accept_numbers_and_names() {
+        let parse = |flags: &str| {
+            toml::from_str::<AspeedAuthManifestGeneralConfigFromFile>(&format!(
+                "version = 1\nsecurity_version = 1\nflags = {}\n",
+                flags
+            ))
+            .map(|c| c.flags)
+            .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(parse("0").unwrap(), ManifestFlags::empty());
+        assert_eq!(
+            parse("1").unwrap(),
+            ManifestFlags::VENDOR_SIGNATURE_REQUIRED
+        );
+        assert_eq!(parse("[]").unwrap(), ManifestFlags::empty());
+        assert_eq!(
+            parse("[\"vendor_signature_required\"]").unwrap(),
+            ManifestFlags::VENDOR_SIGNATURE_REQUIRED
+        );
+        assert_eq!(
+            parse("[\"VENDOR_SIGNATURE_REQUIRED\"]").unwrap(),
+            ManifestFlags::VENDOR_SIGNATURE_REQUIRED
+        );
+
+        /* Only caliptra's bits have a name or may be set */
+        let err = parse("[\"not_a_flag\"]").unwrap_err();
+        assert!(err.contains("invalid flag \"not_a_flag\""), "{}", err);
+        assert!(err.contains("\"vendor_signature_required\""), "{}", err);
+        for (flags, bits) in [("0x80000000", "0x80000000"), ("3", "0x00000002")] {
+            let err = parse(flags).unwrap_err();
+            assert!(err.contains(&format!("unknown bits {}", bits)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn image_flags_accept_numbers_and_names() {
+        let parse = |flags: &str| {
+            toml::from_str::<AspeedImageMetadataConfigFromFile>(&format!(
+                "file = \"a.bin\"\nsource = 1\nfw_id = 2\nload_stage = 0\n{}",
+                flags
+            ))
+            .map(|img| img.flags)
+            .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(parse("").unwrap(), ImageMetadataFlags::empty());
+        assert_eq!(
+            parse("flags = [\"ignore_auth_check\"]").unwrap(),
+            ImageMetadataFlags::IGNORE_AUTH_CHECK
+        );
+        assert_eq!(
+            parse("flags = 4").unwrap(),
+            ImageMetadataFlags::IGNORE_AUTH_CHECK
+        );
+
+        for flags in ["flags = [\"authenticate\"]", "flags = 1"] {
+            let err = parse(flags).unwrap_err();
+            assert!(err.contains("\"ignore_auth_check\""), "{}", err);
+        }
+    }
+
+    #[test]
+    fn expected_magic_accepts_numbers_and_text() {
+        let parse = |magic: &str| {
+            toml::from_str::<AspeedAuthManifestGeneralConfigFromFile>(&format!(
+                "version = 1\nflags = 0\nsecurity_version = 1\n{}",
+                magic
+            ))
+            .map(|c| c.expected_magic)
+            .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(parse("").unwrap(), None);
+        assert_eq!(
+            parse("expected_magic = 0x4154_4D4E").unwrap(),
+            Some(AUTH_MANIFEST_MAGIC)
+        );
+        assert_eq!(
+            parse("expected_magic = \"ATMN\"").unwrap(),
+            Some(AUTH_MANIFEST_MAGIC)
+        );
+        let err = parse("expected_magic = \"ATM\"").unwrap_err();
+        assert!(err.contains("4 ASCII characters"), "{}", err);
+    }
+
+    #[test]
+    fn pad_to_aligned_keeps_aligned_input() {
+        let data = vec![0xab; 64];
+        assert_eq!(pad_to_aligned(data.clone(), 0, 4), data);
+        assert_eq!(pad_to_aligned(data.clone(), 0, 64), data);
+    }
+
+    #[test]
+    fn pad_to_aligned_empty_input() {
+        assert!(pad_to_aligned(Vec::new(), 0xff, 4).is_empty());
+        assert!(pad_to_aligned(Vec::new(), 0xff, 64).is_empty());
+    }
+
+    #[test]
+    fn pad_to_aligned_to_one_byte() {
+        let data = vec![1, 2, 3];
+        assert_eq!(pad_to_aligned(data.clone(), 0xff, 1), data);
+    }
+
+    #[test]
+    fn pad_to_aligned_uses_the_pad_byte() {
+        for (len, aligned, padded) in [(1, 4, 4), (5, 16, 16), (17, 16, 32), (65, 64, 128)] {
+            let out = pad_to_aligned(vec![0x11; len], 0xee, aligned);
+            assert_eq!(out.len(), padded, "len {} aligned {}", len, aligned);
+            assert!(out[..len].iter().all(|b| *b == 0x11));
+            assert!(out[len..].iter().all(|b| *b == 0xee));
+        }
+    }
+
+    #[test]
+    fn pad_to_aligned_large_input() {
+        let len = PROGRESS_CHUNK_SIZE + 3;
+        let out = pad_to_aligned(vec![0x5a; len], 0, 4);
+        assert_eq!(out.len(), PROGRESS_CHUNK_SIZE + 4);
+        assert_eq!(out[len], 0);
+    }
+
+    /* Every length up to a few blocks, for every alignment the digest code uses */
+    #[test]
+    fn pad_to_aligned_is_a_multiple_and_never_shrinks() {
+        for aligned in [1, 2, 3, 4, 7, 16, 64] {
+            for len in 0..4 * aligned + 1 {
+                let out = pad_to_aligned(vec![0x11; len], 0, aligned);
+                assert_eq!(out.len() % aligned, 0, "len {} aligned {}", len, aligned);
+                assert!(out.len() >= len, "len {} aligned {}", len, aligned);
+                assert!(out.len() < len + aligned, "len {} aligned {}", len, aligned);
+            }
+        }
+    }
+
+    #[test]
+    fn provided_digest_must_be_sha384_hex() {
+        let img = |digest: &str| AspeedImageMetadataConfigFromFile {
+            fw_id: 2,
+            digest: Some(digest.to_string()),
+            ..Default::default()
+        };
+
+        let digest = "AB".repeat(SHA384_DIGEST_SIZE);
+        assert_eq!(
+            img(&digest).provided_digest().unwrap(),
+            Some(digest.to_lowercase())
+        );
+        assert!(img(&digest).digest_only());
+        for bad in ["ab".repeat(32), "zz".repeat(SHA384_DIGEST_SIZE)] {
+            let err = img(&bad).provided_digest().unwrap_err().to_string();
+            assert!(err.contains("fw_id 2"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn free_space_check_fails_early() {
+        let tmp = tempfile::tempdir().unwrap();
+        check_free_space(Some(tmp.path()), 0).unwrap();
+
+        let err = check_free_space(Some(tmp.path()), u64::MAX / 4).unwrap_err();
+        assert!(
+            matches!(err, ImgtoolError::NoSpace { .. }),
+            "unexpected error: {}",
+            err
+        );
+        assert!(err.to_string().contains("TMPDIR"), "{}", err);
+    }
+
+    #[test]
+    fn project_name_from_prj_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cfg = write_cfg(tmp.path(), "prj_name = \"ast2700-default\"\n");
+        assert_eq!(
+            AspeedManifestCreationPath::get_project_name(&cfg).unwrap(),
+            "ast2700-default"
+        );
+    }
+
+    #[test]
+    fn project_name_from_the_config_file_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cfg = tmp.path().join("ast2700.toml");
+        fs::write(&cfg, "[manifest_config]\nversion = 1\n").unwrap();
+        assert_eq!(
+            AspeedManifestCreationPath::get_project_name(&cfg).unwrap(),
+            "ast2700"
+        );
+    }
+
+    #[test]
+    fn project_name_defaults_without_a_file_name() {
+        assert_eq!(
+            AspeedManifestCreationPath::derive_project_name(None, None),
+            DEFAULT_PROJECT_NAME
+        );
+        assert_eq!(
+            AspeedManifestCreationPath::derive_project_name(None, Some(Path::new("/"))),
+            DEFAULT_PROJECT_NAME
+        );
+    }
+
+    #[test]
+    fn overlay_scalars_replace_and_tables_merge() {
+        let mut base: Value = toml::from_str(
+            "[manifest_config]\nversion = 1\nsecurity_version = 2\nprj_name = \"ast2700\"\n",
+        )
+        .unwrap();
+        let overlay: Value =
+            toml::from_str("[manifest_config]\nsecurity_version = 5\nkey_dir = \"keys\"\n")
+                .unwrap();
+        merge_config_value(&mut base, overlay);
+
+        let cfg = &base["manifest_config"];
+        assert_eq!(cfg["version"].as_integer(), Some(1));
+        assert_eq!(cfg["security_version"].as_integer(), Some(5));
+        assert_eq!(cfg["prj_name"].as_str(), Some("ast2700"));
+        assert_eq!(cfg["key_dir"].as_str(), Some("keys"));
+    }
+
+    #[test]
+    fn overlay_images_merge_by_fw_id() {
+        let mut base: Value = toml::from_str(
+            "[post_build_hook]\nprogram = \"sign.sh\"\nargs = [\"{manifest}\"]\n\
+             [[image_metadata_list]]\nfw_id = 1\nfile = \"a.bin\"\n\
+             [[image_metadata_list]]\nfw_id = 2\nfile = \"b.bin\"\n",
+        )
+        .unwrap();
+        let overlay: Value = toml::from_str(
+            "[post_build_hook]\nargs = [\"--flash\", \"{flash}\"]\n\
+             [[image_metadata_list]]\nfw_id = 2\nfile = \"b-debug.bin\"\n\
+             [[image_metadata_list]]\nfw_id = 3\nfile = \"c.bin\"\n",
+        )
+        .unwrap();
+        merge_config_value(&mut base, overlay);
+
+        let images = base["image_metadata_list"].as_array().unwrap();
+        let files: Vec<_> = images.iter().map(|i| i["file"].as_str().unwrap()).collect();
+        assert_eq!(files, ["a.bin", "b-debug.bin", "c.bin"]);
+        assert_eq!(images[1]["fw_id"].as_integer(), Some(2));
+
+        /* Other arrays are replaced, not appended to */
+        let args: Vec<_> = base["post_build_hook"]["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| a.as_str().unwrap())
+            .collect();
+        assert_eq!(args, ["--flash", "{flash}"]);
+        assert_eq!(base["post_build_hook"]["program"].as_str(), Some("sign.sh"));
+    }
+
+    fn owner_keys(fields: &[&str]) -> AuthManifestKeyConfigFromFile {
+        let set = |name: &str| fields.contains(&name).then(|| format!("{}.pem", name));
+        AuthManifestKeyConfigFromFile {
+            ecc_pub_key: set("ecc_pub_key"),
+            ecc_priv_key: set("ecc_priv_key"),
+            lms_pub_key: set("lms_pub_key"),
+            lms_priv_key: set("lms_priv_key"),
+        }
+    }
+
+    #[test]
+    fn complete_owner_key_fields_are_accepted() {
+        let local = [
+            &[][..],
+            &["ecc_pub_key", "ecc_priv_key"],
+            &["ecc_pub_key", "ecc_priv_key", "lms_pub_key", "lms_priv_key"],
+        ];
+        for fields in local {
+            assert!(owner_keys(fields)
+                .missing_key_fields(false, false)
+                .is_empty());
+        }
+
+        /* A sign helper holds the private key */
+        let signed_by_helper = owner_keys(&["ecc_pub_key", "lms_pub_key"]);
+        assert!(signed_by_helper.missing_key_fields(true, true).is_empty());
+    }
+
+    #[test]
+    fn partial_owner_key_fields_are_listed() {
+        assert_eq!(
+            owner_keys(&["ecc_pub_key"]).missing_key_fields(false, false),
+            ["ecc_priv_key"]
+        );
+        assert_eq!(
+            owner_keys(&["ecc_priv_key", "lms_priv_key"]).missing_key_fields(false, false),
+            ["ecc_pub_key", "lms_pub_key"]
+        );
+        /* The helper only stands in for the private key of its algorithm */
+        assert_eq!(
+            owner_keys(&["ecc_pub_key", "lms_pub_key"]).missing_key_fields(true, false),
+            ["lms_priv_key"]
+        );
+        assert_eq!(
+            owner_keys(&["ecc_priv_key"]).missing_key_fields(true, true),
+            ["ecc_pub_key"]
+        );
+
+        let mut cfg = AspeedAuthManifestConfigFromFile {
+            owner_fw_key_config: Some(owner_keys(&["ecc_pub_key", "ecc_priv_key"])),
+            owner_man_key_config: Some(owner_keys(&["lms_pub_key"])),
+            ..Default::default()
+        };
+        let err = cfg.validate_owner_key_fields().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("missing owner_man_key_config.lms_priv_key."),
+            "{}",
+            err
+        );
+
+        cfg.sign_helper = Some(AspeedAuthManifestSignHelper {
+            owner_lms_man_key_sign_helper: Some("helper".to_string()),
+            ..Default::default()
+        });
+        cfg.validate_owner_key_fields().unwrap();
+    }
+
+    #[test]
+    fn shipped_configs_have_complete_owner_keys() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("config");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let cfg = AspeedAuthManifestConfigFromFile::parse(&path).unwrap();
+            assert!(
+                cfg.validate_owner_key_fields().is_ok(),
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn image_label_names_the_image() {
+        let mut img = AspeedImageMetadataConfigFromFile {
+            fw_id: 3,
+            ..Default::default()
+        };
+        assert_eq!(img.label(), "fw_id 3");
+
+        img.name = Some("u-boot".to_string());
+        assert_eq!(img.label(), "fw_id 3 \"u-boot\"");
+
+        img.digest = Some("00".to_string());
+        let err = img.provided_digest().unwrap_err();
+        assert!(
+            err.to_string().contains("of fw_id 3 \"u-boot\":"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn fingerprint_changes_with_every_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for (file, contents) in [
+            ("manifest.toml", "cfg"),
+            ("img.bin", "image"),
+            ("keys/fw-ecc-pubk.pem", "key"),
+            ("helper", "helper"),
+            ("prev.bin", "prev"),
+        ] {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), contents).unwrap();
+        }
+
+        let path = AspeedManifestCreationPath {
+            prebuilt_dir: dir.to_path_buf(),
+            tool_dir: dir.to_path_buf(),
+            key_dir: Some(dir.join("keys")),
+            aspeed_cfg: dir.join("manifest.toml"),
+            caliptra_cfg: None,
+            manifest: None,
+            flash_image: None,
+            svn_sig: None,
+            tool_names: Default::default(),
+        };
+        let mut cfg = AspeedAuthManifestConfigFromFile {
+            image_metadata_list: vec![AspeedImageMetadataConfigFromFile {
+                file: dir.join("img.bin").to_string(),
+                fw_id: 2,
+                ..Default::default()
+            }],
+            sign_helper: Some(AspeedAuthManifestSignHelper {
+                owner_lms_man_key_sign_helper: Some(dir.join("helper").to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        cfg.vendor_fw_key_config.ecc_pub_key = Some("fw-ecc-pubk.pem".to_string());
+
+        let runner = crate::tool::RecordingRunner::default();
+        let tool = dir.join("tool");
+        let prev_man = dir.join("prev.bin");
+        let fingerprint = || {
+            cfg.input_fingerprint(&path, &runner, &tool, Some(&prev_man), &[])
+                .unwrap()
+        };
+
+        let mut seen = vec![fingerprint()];
+        assert_eq!(fingerprint(), seen[0]);
+        for file in [
+            "manifest.toml",
+            "img.bin",
+            "keys/fw-ecc-pubk.pem",
+            "helper",
+            "prev.bin",
+        ] {
+            fs::write(dir.join(file), "changed").unwrap();
+            let changed = fingerprint();
+            assert!(!seen.contains(&changed), "{} is not fingerprinted", file);
+            seen.push(changed);
+        }
+        assert_ne!(
+            cfg.input_fingerprint(&path, &runner, &tool, None, &[])
+                .unwrap(),
+            fingerprint()
+        );
+    }
+
+    #[test]
+    fn sorting_keeps_the_mcu_runtime_image_first() {
+        let mut cfg = AspeedAuthManifestConfigFromFile::default();
+        cfg.manifest_config.mcu_runtime_fw_id = Some(5);
+        cfg.image_metadata_list = [3, 9, 5, 1, 7]
+            .into_iter()
+            .map(|fw_id| AspeedImageMetadataConfigFromFile {
+                fw_id,
+                ..Default::default()
+            })
+            .collect();
+
+        cfg.sort_images_by_fw_id();
+        let order = cfg
+            .image_metadata_list
+            .iter()
+            .map(|img| img.fw_id)
+            .collect::<Vec<_>>();
+        assert_eq!(order, [5, 1, 3, 7, 9]);
+    }
+}
@@ -0,0 +1,135 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   error.rs
+
+Abstract:
+
+    File contains the error type returned by the image tool modules
+
+--*/
+
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// Failure categories of the image tool. The CLI wraps them in `anyhow`, code
+/// calling the modules directly can match on the variant.
+#[derive(Debug, thiserror::Error)]
+pub enum ImgtoolError {
+    #[error("Failed to parse the config file {path:?}: {source}")]
+    ConfigParse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("{0}")]
+    Config(String),
+
+    #[error("Path or file not found: {0:?}")]
+    MissingPath(PathBuf),
+
+    #[error(
+        "Permission denied: {0:?}. Check the access rights of the path and its parent \
+         directories"
+    )]
+    PermissionDenied(PathBuf),
+
+    #[error("{path:?} is a {found}, expected a {expected}")]
+    WrongPathType {
+        path: PathBuf,
+        expected: &'static str,
+        found: &'static str,
+    },
+
+    #[error(
+        "{kind} directory not found: {path:?} (project \"{prj}\"). \
+         Pass --{kind}-dir or set {kind}_dir in [manifest_config]"
+    )]
+    MissingDir {
+        kind: &'static str,
+        path: PathBuf,
+        prj: String,
+    },
+
+    #[error("Missing key file for [{section}] {name}: {path:?}")]
+    MissingKey {
+        section: &'static str,
+        name: &'static str,
+        path: PathBuf,
+    },
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error(
+        "Not enough free space in {dir:?}: {available} bytes available, about {required} bytes needed. {hint}"
+    )]
+    NoSpace {
+        dir: PathBuf,
+        available: u64,
+        required: u64,
+        hint: &'static str,
+    },
+
+    #[error("Failed to serialize TOML: {0}")]
+    TomlSerialize(#[from] toml::ser::Error),
+
+    #[error("{0}")]
+    ToolNotFound(String),
+
+    #[error("{program:?} failed: {status}")]
+    ToolFailed {
+        program: PathBuf,
+        status: ExitStatus,
+    },
+
+    #[error("{0}")]
+    ToolVersion(String),
+
+    #[error("{program:?} timed out after {timeout:?}")]
+    ToolTimeout { program: PathBuf, timeout: Duration },
+
+    #[error("{0}")]
+    SignatureFormat(String),
+
+    #[error("{0}")]
+    Manifest(String),
+}
+
+impl ImgtoolError {
+    /// Errors about a path the user gave, shown in red by the CLI on a terminal.
+    pub fn is_path_error(&self) -> bool {
+        matches!(
+            self,
+            Self::MissingPath(_)
+                | Self::PermissionDenied(_)
+                | Self::WrongPathType { .. }
+                | Self::MissingDir { .. }
+        )
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ImgtoolError>;
+
+/// Attach a description to an I/O error, like `anyhow::Context` does.
+pub trait IoContext<T> {
+    fn io_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::result::Result<T, std::io::Error> {
+    fn io_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|source| ImgtoolError::Io {
+            context: f().into(),
+            source,
+        })
+    }
+}
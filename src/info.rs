@@ -0,0 +1,211 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   info.rs
+
+Abstract:
+
+    File contains the versioned JSON description of a parsed manifest
+
+--*/
+
+use crate::soc_man;
+use cptra_imgtool::ParsedManifest;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Version of the `ManifestInfo` shape. Bump it on any change of a field name,
+/// type or meaning; adding a field also bumps it.
+pub(crate) const INFO_SCHEMA_VERSION: u32 = 2;
+
+/// Parsed manifest as printed by `dump-auth-man --format json`. Integers are
+/// JSON numbers, keys, signatures and digests lowercase hex strings of the
+/// bytes as stored in the manifest.
+#[derive(Serialize, Debug)]
+pub(crate) struct ManifestInfo {
+    schema_version: u32,
+
+    /* Manifest format version, the `ver` field of the preamble */
+    version: u32,
+
+    preamble: PreambleInfo,
+
+    image_metadata: Vec<ImageMetadataInfo>,
+}
+
+#[derive(Serialize, Debug)]
+struct PreambleInfo {
+    magic: u32,
+
+    size: u32,
+
+    security_version: u32,
+
+    flags: u32,
+
+    vnd_manifest_ecc_pubk: String,
+
+    vnd_manifest_lms_pubk: String,
+
+    vnd_manifest_ecc_sig: String,
+
+    vnd_manifest_lms_sig: String,
+
+    owner_manifest_ecc_pubk: String,
+
+    owner_manifest_lms_pubk: String,
+
+    owner_manifest_ecc_sig: String,
+
+    owner_manifest_lms_sig: String,
+
+    owner_manifest_svn_ecc_sig: String,
+
+    owner_manifest_svn_lms_sig: String,
+
+    vnd_metadata_ecc_sig: String,
+
+    vnd_metadata_lms_sig: String,
+
+    owner_metadata_ecc_sig: String,
+
+    owner_metadata_lms_sig: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ImageMetadataInfo {
+    fw_id: u32,
+
+    /* Only with --cfg, for images with a name in the config */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    flags: u32,
+
+    digest: String,
+
+    /* Only with --annotate-dummies, for entries with an empty file in the config:
+     * "empty_image" or "digest_differs"
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dummy: Option<&'static str>,
+}
+
+impl ManifestInfo {
+    /// Describe `man`, annotating the entries whose fw_id is in `dummy_fw_ids`
+    /// and naming those in `names`.
+    pub(crate) fn new(
+        man: &ParsedManifest,
+        dummy_fw_ids: Option<&BTreeSet<u32>>,
+        names: &BTreeMap<u32, String>,
+    ) -> Self {
+        let preamble = PreambleInfo {
+            magic: man.magic,
+            size: man.size,
+            security_version: man.sec_ver,
+            flags: man.flags,
+            vnd_manifest_ecc_pubk: man.vnd_manifest_ecc_pubk.clone(),
+            vnd_manifest_lms_pubk: man.vnd_manifest_lms_pubk.clone(),
+            vnd_manifest_ecc_sig: man.vnd_manifest_ecc_sig.clone(),
+            vnd_manifest_lms_sig: man.vnd_manifest_lms_sig.clone(),
+            owner_manifest_ecc_pubk: man.owner_manifest_ecc_pubk.clone(),
+            owner_manifest_lms_pubk: man.owner_manifest_lms_pubk.clone(),
+            owner_manifest_ecc_sig: man.owner_manifest_ecc_sig.clone(),
+            owner_manifest_lms_sig: man.owner_manifest_lms_sig.clone(),
+            owner_manifest_svn_ecc_sig: man.owner_manifest_svn_ecc_sig.clone(),
+            owner_manifest_svn_lms_sig: man.owner_manifest_svn_lms_sig.clone(),
+            vnd_metadata_ecc_sig: man.vnd_metadata_ecc_sig.clone(),
+            vnd_metadata_lms_sig: man.vnd_metadata_lms_sig.clone(),
+            owner_metadata_ecc_sig: man.owner_metadata_ecc_sig.clone(),
+            owner_metadata_lms_sig: man.owner_metadata_lms_sig.clone(),
+        };
+
+        let image_metadata = man
+            .images
+            .iter()
+            .map(|m| ImageMetadataInfo {
+                fw_id: m.fw_id,
+                name: names.get(&m.fw_id).cloned(),
+                flags: m.flags,
+                digest: m.digest.clone(),
+                /* The digest is hex written by ParsedManifest, it always decodes */
+                dummy: soc_man::dummy_digest_matches(
+                    m.fw_id,
+                    &hex::decode(&m.digest).unwrap_or_default(),
+                    dummy_fw_ids,
+                )
+                .map(|matches| {
+                    if matches {
+                        "empty_image"
+                    } else {
+                        "digest_differs"
+                    }
+                }),
+            })
+            .collect();
+
+        Self {
+            schema_version: INFO_SCHEMA_VERSION,
+            version: man.ver,
+            preamble,
+            image_metadata,
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("manifest info serializes to JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::{synthetic_metadata, synthetic_preamble};
+    use crate::soc_man::{AspeedAuthorizationManifest, ManifestLayout};
+
+    #[test]
+    fn json_describes_the_written_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.bin");
+        let written = AspeedAuthorizationManifest::from_parts(
+            &path,
+            synthetic_preamble(),
+            synthetic_metadata(),
+        );
+        written.close(ManifestLayout::Aspeed).unwrap();
+
+        let parsed = ParsedManifest::from_path(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&ManifestInfo::new(&parsed, None, &BTreeMap::new()).to_json())
+                .unwrap();
+
+        let p = written.preamble();
+        assert_eq!(json["version"], p.ver.get());
+        assert_eq!(json["preamble"]["magic"], p.magic.get());
+        assert_eq!(json["preamble"]["security_version"], p.sec_ver.get());
+        assert_eq!(json["preamble"]["flags"], p.flags.get());
+        assert_eq!(
+            json["preamble"]["vnd_manifest_ecc_pubk"],
+            hex::encode(p.vnd_manifest_ecc_pubk)
+        );
+        assert_eq!(
+            json["preamble"]["owner_manifest_svn_lms_sig"],
+            hex::encode(p.owner_manifest_svn_lms_sig)
+        );
+        assert_eq!(
+            json["preamble"]["owner_metadata_lms_sig"],
+            hex::encode(p.owner_matadata_lms_sig)
+        );
+
+        let images = json["image_metadata"].as_array().unwrap();
+        assert_eq!(images.len(), written.metadata().count());
+        for (image, m) in images.iter().zip(written.metadata()) {
+            assert_eq!(image["fw_id"], m.id());
+            assert_eq!(image["flags"], m.flags());
+            assert_eq!(image["digest"], m.digest_hex());
+        }
+    }
+}
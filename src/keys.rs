@@ -0,0 +1,281 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   keys.rs
+
+Abstract:
+
+    File contains the offline format checks of the ECC and LMS key files
+
+--*/
+
+use crate::error::{ImgtoolError, IoContext, Result};
+use p384::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use std::path::Path;
+
+/* Caliptra LMS keys: tree_type (4), otstype (4), id (16), seed or digest (24) */
+const LMS_KEY_SIZE: usize = 48;
+
+/* Caliptra only uses the SHA256/192 LMS and LMOTS parameter sets */
+const LMS_SHA256_N24_TYPES: std::ops::RangeInclusive<u32> = 10..=14;
+const LMOTS_SHA256_N24_TYPES: std::ops::RangeInclusive<u32> = 5..=8;
+
+/// What a key file holds, from its config field or file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyKind {
+    EccPublic,
+    EccPrivate,
+    LmsPublic,
+    LmsPrivate,
+}
+
+impl KeyKind {
+    /// Kind of the key in a key config field, e.g. `ecc_pub_key`.
+    pub(crate) fn from_field(field: &str) -> Option<Self> {
+        match field {
+            "ecc_pub_key" => Some(KeyKind::EccPublic),
+            "ecc_priv_key" => Some(KeyKind::EccPrivate),
+            "lms_pub_key" => Some(KeyKind::LmsPublic),
+            "lms_priv_key" => Some(KeyKind::LmsPrivate),
+            _ => None,
+        }
+    }
+
+    /// Kind of the key from a file name following the key directory naming,
+    /// e.g. `vnd-man-ecc-pubk.pem` or `own-fw-lms-prvk.pem`.
+    pub(crate) fn from_file_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        match (
+            name.contains("ecc"),
+            name.contains("lms"),
+            name.contains("pubk"),
+            name.contains("prvk"),
+        ) {
+            (true, false, true, false) => Some(KeyKind::EccPublic),
+            (true, false, false, true) => Some(KeyKind::EccPrivate),
+            (false, true, true, false) => Some(KeyKind::LmsPublic),
+            (false, true, false, true) => Some(KeyKind::LmsPrivate),
+            _ => None,
+        }
+    }
+}
+
+/// The key block of a PEM file, re-wrapped to the 64 column lines the strict
+/// RFC 7468 parser wants. Blocks that hold no key, such as the EC PARAMETERS
+/// block openssl writes before a SEC1 key, are skipped.
+pub(crate) fn key_pem(pem: &str) -> String {
+    let mut lines = pem.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let Some(label) = line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|l| l.strip_suffix("-----"))
+        else {
+            continue;
+        };
+        if !label.ends_with("KEY") {
+            continue;
+        }
+
+        let end = format!("-----END {}-----", label);
+        let body: String = lines.by_ref().take_while(|l| *l != end).collect();
+        let wrapped: Vec<&str> = body
+            .as_bytes()
+            .chunks(64)
+            .map(|c| std::str::from_utf8(c).unwrap_or_default())
+            .collect();
+        return format!(
+            "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+            label,
+            wrapped.join("\n"),
+            label
+        );
+    }
+    pem.to_string()
+}
+
+fn check_ecc_key(file: &Path, private: bool) -> Result<String> {
+    let pem = std::fs::read_to_string(file)
+        .io_context(|| format!("Failed to read key file {}", file.display()))?;
+    let pem = key_pem(&pem);
+
+    if !private {
+        return p384::PublicKey::from_public_key_pem(&pem)
+            .map(|_| "ECC-P384 public key".to_string())
+            .map_err(|e| {
+                ImgtoolError::SignatureFormat(format!("not an ECC-P384 public key PEM: {}", e))
+            });
+    }
+
+    /* The sign helper reads SEC1, the caliptra tool PKCS#8 */
+    match p384::SecretKey::from_sec1_pem(&pem) {
+        Ok(_) => Ok("ECC-P384 private key (SEC1)".to_string()),
+        Err(sec1) => p384::SecretKey::from_pkcs8_pem(&pem)
+            .map(|_| "ECC-P384 private key (PKCS#8)".to_string())
+            .map_err(|pkcs8| {
+                ImgtoolError::SignatureFormat(format!(
+                    "not an ECC-P384 private key PEM: SEC1: {}, PKCS#8: {}",
+                    sec1, pkcs8
+                ))
+            }),
+    }
+}
+
+fn check_lms_key(file: &Path, private: bool) -> Result<String> {
+    let key =
+        std::fs::read(file).io_context(|| format!("Failed to read key file {}", file.display()))?;
+    if key.len() != LMS_KEY_SIZE {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "invalid LMS key size: expected {} bytes, got {}",
+            LMS_KEY_SIZE,
+            key.len()
+        )));
+    }
+
+    let tree_type = u32::from_be_bytes(key[0..4].try_into().unwrap());
+    let ots_type = u32::from_be_bytes(key[4..8].try_into().unwrap());
+    if !LMS_SHA256_N24_TYPES.contains(&tree_type) {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "unsupported LMS tree type {}",
+            tree_type
+        )));
+    }
+    if !LMOTS_SHA256_N24_TYPES.contains(&ots_type) {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "unsupported LMOTS type {}",
+            ots_type
+        )));
+    }
+
+    Ok(format!(
+        "LMS {} key (tree type {}, LMOTS type {})",
+        if private { "private" } else { "public" },
+        tree_type,
+        ots_type
+    ))
+}
+
+/// Parse `file` as a key of `kind` and describe it, or explain why it isn't one.
+pub(crate) fn check_key_file(file: &Path, kind: KeyKind) -> Result<String> {
+    match kind {
+        KeyKind::EccPublic => check_ecc_key(file, false),
+        KeyKind::EccPrivate => check_ecc_key(file, true),
+        KeyKind::LmsPublic => check_lms_key(file, false),
+        KeyKind::LmsPrivate => check_lms_key(file, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+
+    #[test]
+    fn key_kind_from_file_name() {
+        assert_eq!(
+            KeyKind::from_file_name("vnd-man-ecc-pubk.pem"),
+            Some(KeyKind::EccPublic)
+        );
+        assert_eq!(
+            KeyKind::from_file_name("own-fw-lms-prvk.pem"),
+            Some(KeyKind::LmsPrivate)
+        );
+        assert_eq!(KeyKind::from_file_name("README"), None);
+    }
+
+    #[test]
+    fn ecc_keys_are_parsed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let secret = p384::SecretKey::from_slice(&[0x22; 48]).unwrap();
+        let public = tmp.path().join("pubk.pem");
+        let sec1 = tmp.path().join("sec1.pem");
+        let pkcs8 = tmp.path().join("pkcs8.pem");
+        std::fs::write(
+            &public,
+            secret
+                .public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            &sec1,
+            secret.to_sec1_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+        std::fs::write(
+            &pkcs8,
+            secret.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        assert!(check_key_file(&public, KeyKind::EccPublic).is_ok());
+        assert!(check_key_file(&sec1, KeyKind::EccPrivate)
+            .unwrap()
+            .contains("SEC1"));
+        assert!(check_key_file(&pkcs8, KeyKind::EccPrivate)
+            .unwrap()
+            .contains("PKCS#8"));
+        assert!(check_key_file(&public, KeyKind::EccPrivate).is_err());
+        assert!(check_key_file(&sec1, KeyKind::EccPublic).is_err());
+    }
+
+    #[test]
+    fn shipped_ecc_keys_are_accepted() {
+        /* openssl output: EC PARAMETERS before the SEC1 key, unwrapped base64 */
+        for dir in [
+            "ast2700-default",
+            "ast2700-irot",
+            "ast2700a1-default",
+            "ast2700a1-irot",
+        ] {
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("key").join(dir);
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_str().unwrap();
+                let kind = KeyKind::from_file_name(name).unwrap();
+                if let Err(e) = check_key_file(&path, kind) {
+                    panic!("{}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn key_pem_keeps_only_the_key_block() {
+        let pem = "-----BEGIN EC PARAMETERS-----\nBgUrgQQAIg==\n-----END EC PARAMETERS-----\n\
+                   -----BEGIN PUBLIC KEY-----\n"
+            .to_string()
+            + &"A".repeat(100)
+            + "\n-----END PUBLIC KEY-----\n";
+        let expected = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n{}\n-----END PUBLIC KEY-----\n",
+            "A".repeat(64),
+            "A".repeat(36)
+        );
+        assert_eq!(key_pem(&pem), expected);
+        assert_eq!(key_pem("not a key"), "not a key");
+    }
+
+    #[test]
+    fn lms_keys_are_checked() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("lms-prvk.pem");
+        let mut key = vec![0u8; LMS_KEY_SIZE];
+        key[3] = 12;
+        key[7] = 7;
+        std::fs::write(&file, &key).unwrap();
+        assert!(check_key_file(&file, KeyKind::LmsPrivate).is_ok());
+
+        std::fs::write(&file, &key[1..]).unwrap();
+        let err = check_key_file(&file, KeyKind::LmsPrivate).unwrap_err();
+        assert!(err.to_string().contains("expected 48 bytes"), "{}", err);
+
+        key[7] = 3;
+        std::fs::write(&file, &key).unwrap();
+        let err = check_key_file(&file, KeyKind::LmsPublic).unwrap_err();
+        assert!(err.to_string().contains("LMOTS type 3"), "{}", err);
+    }
+}
@@ -0,0 +1,169 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   layout.rs
+
+Abstract:
+
+    File contains the on-disk layout of the aspeed authorization manifest
+
+--*/
+
+use crate::error::{ImgtoolError, Result};
+use std::mem::size_of;
+use zerocopy::little_endian::U32;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+pub const AUTH_MANIFEST_MAGIC: u32 = 0x4154_4D4E;
+pub const IMAGE_METADATA_MAX_COUNT: usize = 127;
+pub const ECC384_SIG_SIZE: usize = 96;
+pub const ECC384_PUBK_SIZE: usize = 96;
+pub const SHA384_DIGEST_SIZE: usize = 48;
+pub const LMS_SIG_SIZE: usize = 1620;
+pub const LMS_PUBK_SIZE: usize = 48;
+
+/*
+ * All multi-byte fields are stored little-endian on disk. The U32 wrapper keeps
+ * the layout independent of the build host byte order.
+ */
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct AspeedAuthManifestPreamble {
+    pub magic: U32,
+    pub size: U32,
+    pub ver: U32,
+    pub sec_ver: U32,
+    pub flags: U32,
+    pub vnd_manifest_ecc_pubk: [u8; ECC384_PUBK_SIZE],
+    pub vnd_manifest_lms_pubk: [u8; LMS_PUBK_SIZE],
+    pub vnd_manifest_ecc_sig: [u8; ECC384_SIG_SIZE],
+    pub vnd_manifest_lms_sig: [u8; LMS_SIG_SIZE],
+    pub owner_manifest_ecc_pubk: [u8; ECC384_PUBK_SIZE],
+    pub owner_manifest_lms_pubk: [u8; LMS_PUBK_SIZE],
+    pub owner_manifest_ecc_sig: [u8; ECC384_SIG_SIZE],
+    pub owner_manifest_lms_sig: [u8; LMS_SIG_SIZE],
+    pub owner_manifest_svn_ecc_sig: [u8; ECC384_SIG_SIZE],
+    pub owner_manifest_svn_lms_sig: [u8; LMS_SIG_SIZE],
+    pub vnd_matadata_ecc_sig: [u8; ECC384_SIG_SIZE],
+    pub vnd_matadata_lms_sig: [u8; LMS_SIG_SIZE],
+    pub owner_matadata_ecc_sig: [u8; ECC384_SIG_SIZE],
+    pub owner_matadata_lms_sig: [u8; LMS_SIG_SIZE],
+}
+
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct AspeedAuthManifestImageMetadata {
+    id: U32,
+    flags: U32,
+    digest: [u8; SHA384_DIGEST_SIZE],
+}
+
+impl AspeedAuthManifestImageMetadata {
+    pub fn id(&self) -> u32 {
+        self.id.get()
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags.get()
+    }
+
+    pub fn digest(&self) -> &[u8; SHA384_DIGEST_SIZE] {
+        &self.digest
+    }
+
+    pub fn digest_hex(&self) -> String {
+        hex::encode(self.digest())
+    }
+}
+
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct AspeedAuthManifestImageMetadataCollection {
+    pub count: U32,
+    pub metadata_list: [AspeedAuthManifestImageMetadata; IMAGE_METADATA_MAX_COUNT],
+}
+
+impl AspeedAuthManifestImageMetadataCollection {
+    /// The entries in use, `count` clamped to the collection size.
+    pub fn entries(&self) -> &[AspeedAuthManifestImageMetadata] {
+        let count = (self.count.get() as usize).min(IMAGE_METADATA_MAX_COUNT);
+        &self.metadata_list[..count]
+    }
+}
+
+/// Offset of the metadata collection in an aspeed manifest image, right after
+/// the preamble.
+pub const fn metadata_offset() -> usize {
+    size_of::<AspeedAuthManifestPreamble>()
+}
+
+/* Size of the aspeed manifest image */
+pub const ASPEED_AUTH_MANIFEST_SIZE: usize =
+    metadata_offset() + size_of::<AspeedAuthManifestImageMetadataCollection>();
+
+/// Read a `T` at `offset` of `buf`, an error when it doesn't fit.
+pub fn from_img<T: FromBytes>(buf: &[u8], offset: usize) -> Result<T> {
+    buf.get(offset..)
+        .and_then(|rest| T::read_from_prefix(rest).ok())
+        .map(|(val, _)| val)
+        .ok_or_else(|| {
+            ImgtoolError::Manifest(format!(
+                "{} bytes at offset {} are out of bounds of a {} byte image",
+                size_of::<T>(),
+                offset,
+                buf.len()
+            ))
+        })
+}
+
+/// Split an aspeed manifest image into its preamble and metadata collection,
+/// checking the size and that it starts with `magic`. `name` identifies the
+/// image in errors.
+pub fn parse_manifest(
+    name: &str,
+    img: &[u8],
+    magic: u32,
+) -> Result<(
+    AspeedAuthManifestPreamble,
+    AspeedAuthManifestImageMetadataCollection,
+)> {
+    if img.len() < ASPEED_AUTH_MANIFEST_SIZE {
+        return Err(ImgtoolError::Manifest(format!(
+            "SoC manifest {} is truncated: {} bytes, expected {}",
+            name,
+            img.len(),
+            ASPEED_AUTH_MANIFEST_SIZE
+        )));
+    }
+
+    let preamble = from_img::<AspeedAuthManifestPreamble>(img, 0)?;
+    if preamble.magic.get() != magic {
+        return Err(ImgtoolError::Manifest(format!(
+            "Invalid SoC manifest magic in {}: 0x{:08x}, expected 0x{:08x}",
+            name,
+            preamble.magic.get(),
+            magic
+        )));
+    }
+
+    let metadata_col =
+        from_img::<AspeedAuthManifestImageMetadataCollection>(img, metadata_offset())?;
+
+    Ok((preamble, metadata_col))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_img_checks_bounds() {
+        let buf = [1u8, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!(from_img::<U32>(&buf, 4).unwrap().get(), 2);
+        assert!(from_img::<U32>(&buf, 5).is_err());
+        assert!(from_img::<U32>(&buf, 9).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   lib.rs
+
+Abstract:
+
+    Library interface of the image tool, for reading manifests from other programs
+
+--*/
+
+/* Shared with the cptra-imgtool binary, not part of the stable interface */
+#[doc(hidden)]
+pub mod error;
+#[doc(hidden)]
+pub mod layout;
+mod manifest;
+
+pub use error::{ImgtoolError, Result};
+pub use layout::AUTH_MANIFEST_MAGIC;
+pub use manifest::{ParsedImageMetadata, ParsedManifest};
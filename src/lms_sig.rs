@@ -0,0 +1,211 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   lms_sig.rs
+
+Abstract:
+
+    File contains the portable text format of LMS signatures
+
+--*/
+
+use crate::error::{ImgtoolError, Result};
+use crate::layout::LMS_SIG_SIZE;
+use serde_derive::{Deserialize, Serialize};
+
+const PORTABLE_FORMAT: &str = "cptra-lms-sig";
+const PORTABLE_VERSION: u32 = 1;
+
+/* Caliptra LMS signature: LMOTS SHA256/192 W4 and a tree of height 15 */
+const LMS_N: usize = 24;
+const LMS_P: usize = 51;
+const LMS_H: usize = 15;
+
+const _: () = assert!(
+    4 + 4 + LMS_N + LMS_P * LMS_N + 4 + LMS_H * LMS_N == LMS_SIG_SIZE,
+    "LMS signature layout does not match LMS_SIG_SIZE"
+);
+
+/// LMS signature with every field of the caliptra structure named, so prebuilt
+/// signatures don't depend on the structure layout of a tool version. Integers
+/// are numbers, hashes are hex strings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct PortableLmsSignature {
+    format: String,
+
+    version: u32,
+
+    q: u32,
+
+    ots_type: u32,
+
+    nonce: String,
+
+    y: Vec<String>,
+
+    tree_type: u32,
+
+    tree_path: Vec<String>,
+}
+
+/* Reads the big-endian fields of a raw signature in order */
+struct RawReader<'a> {
+    raw: &'a [u8],
+}
+
+impl RawReader<'_> {
+    fn u32(&mut self) -> u32 {
+        let (field, rest) = self.raw.split_at(4);
+        self.raw = rest;
+        u32::from_be_bytes(field.try_into().unwrap())
+    }
+
+    fn hash(&mut self) -> String {
+        let (field, rest) = self.raw.split_at(LMS_N);
+        self.raw = rest;
+        hex::encode(field)
+    }
+}
+
+fn decode_hash(field: &str, value: &str) -> Result<Vec<u8>> {
+    match hex::decode(value) {
+        Ok(bytes) if bytes.len() == LMS_N => Ok(bytes),
+        _ => Err(ImgtoolError::SignatureFormat(format!(
+            "Invalid {} in portable LMS signature: expected {} hex characters, got \"{}\"",
+            field,
+            LMS_N * 2,
+            value
+        ))),
+    }
+}
+
+/// Write a raw caliptra LMS signature in the portable format.
+pub(crate) fn to_portable(raw: &[u8; LMS_SIG_SIZE]) -> Result<String> {
+    let mut r = RawReader { raw };
+    let sig = PortableLmsSignature {
+        format: PORTABLE_FORMAT.to_string(),
+        version: PORTABLE_VERSION,
+        q: r.u32(),
+        ots_type: r.u32(),
+        nonce: r.hash(),
+        y: (0..LMS_P).map(|_| r.hash()).collect(),
+        tree_type: r.u32(),
+        tree_path: (0..LMS_H).map(|_| r.hash()).collect(),
+    };
+
+    Ok(toml::to_string(&sig)?)
+}
+
+/// Build the raw caliptra LMS signature from the portable format.
+pub(crate) fn from_portable(text: &str) -> Result<[u8; LMS_SIG_SIZE]> {
+    let sig: PortableLmsSignature = toml::from_str(text).map_err(|e| {
+        ImgtoolError::SignatureFormat(format!("Invalid portable LMS signature: {}", e))
+    })?;
+    if sig.format != PORTABLE_FORMAT {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "Not a portable LMS signature: format \"{}\"",
+            sig.format
+        )));
+    }
+    if sig.version != PORTABLE_VERSION {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "Unsupported portable LMS signature version {}, supported: {}",
+            sig.version, PORTABLE_VERSION
+        )));
+    }
+    for (field, values, expected) in [("y", &sig.y, LMS_P), ("tree_path", &sig.tree_path, LMS_H)] {
+        if values.len() != expected {
+            return Err(ImgtoolError::SignatureFormat(format!(
+                "Portable LMS signature has {} {} entries, expected {}",
+                values.len(),
+                field,
+                expected
+            )));
+        }
+    }
+
+    let mut raw = Vec::with_capacity(LMS_SIG_SIZE);
+    raw.extend_from_slice(&sig.q.to_be_bytes());
+    raw.extend_from_slice(&sig.ots_type.to_be_bytes());
+    raw.extend(decode_hash("nonce", &sig.nonce)?);
+    for y in sig.y.iter() {
+        raw.extend(decode_hash("y", y)?);
+    }
+    raw.extend_from_slice(&sig.tree_type.to_be_bytes());
+    for node in sig.tree_path.iter() {
+        raw.extend(decode_hash("tree_path", node)?);
+    }
+
+    Ok(raw.try_into().expect("field sizes add up to LMS_SIG_SIZE"))
+}
+
+/// Read an LMS signature file in either format: the portable text format or
+/// the raw caliptra structure (`LMS_SIG_SIZE` bytes).
+pub(crate) fn parse_lms_sig(sig: &[u8]) -> Result<[u8; LMS_SIG_SIZE]> {
+    if let Some(text) = std::str::from_utf8(sig)
+        .ok()
+        .filter(|text| text.contains(PORTABLE_FORMAT))
+    {
+        return from_portable(text);
+    }
+
+    sig.try_into().map_err(|_| {
+        ImgtoolError::SignatureFormat(format!(
+            "LMS signature is neither the portable format nor the raw structure ({} bytes, expected {})",
+            sig.len(),
+            LMS_SIG_SIZE
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_sig() -> [u8; LMS_SIG_SIZE] {
+        crate::selftest::pattern(LMS_SIG_SIZE, 0)
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn portable_round_trip() {
+        let raw = raw_sig();
+        let text = to_portable(&raw).unwrap();
+        assert!(text.contains("version = 1"), "{}", text);
+        assert_eq!(from_portable(&text).unwrap(), raw);
+        assert_eq!(parse_lms_sig(text.as_bytes()).unwrap(), raw);
+    }
+
+    #[test]
+    fn portable_fields_are_big_endian() {
+        let mut raw = raw_sig();
+        raw[..8].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 7]);
+        let text = to_portable(&raw).unwrap();
+        assert!(text.contains("q = 1\n"), "{}", text);
+        assert!(text.contains("ots_type = 7\n"), "{}", text);
+    }
+
+    #[test]
+    fn raw_signatures_are_detected() {
+        let raw = raw_sig();
+        assert_eq!(parse_lms_sig(&raw).unwrap(), raw);
+        assert!(parse_lms_sig(&raw[1..]).is_err());
+    }
+
+    #[test]
+    fn portable_version_and_counts_are_checked() {
+        let text = to_portable(&raw_sig()).unwrap();
+
+        let err = from_portable(&text.replace("version = 1", "version = 2")).unwrap_err();
+        assert!(err.to_string().contains("version 2"), "{}", err);
+
+        let mut sig: PortableLmsSignature = toml::from_str(&text).unwrap();
+        sig.y.pop();
+        let err = from_portable(&toml::to_string(&sig).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("50 y entries"), "{}", err);
+    }
+}
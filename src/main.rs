@@ -13,13 +13,26 @@ Abstract:
 --*/
 
 use anyhow::Context;
-use clap::{arg, value_parser, ArgMatches, Command};
-use log::debug;
-use std::path::PathBuf;
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
+use cptra_imgtool::{error, layout};
+use log::{debug, info};
+use sha2::{Digest, Sha384};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use tool::ToolRunner;
 use utility::PathBufExt;
 
+mod cache;
 mod config;
+mod info;
+mod keys;
+mod lms_sig;
+mod progress;
+mod selftest;
+mod sign;
 mod soc_man;
+mod summary;
+mod tool;
 mod utility;
 
 fn main() {
@@ -31,6 +44,13 @@ fn main() {
                     .required(true)
                     .value_parser(value_parser!(String)),
             )
+            .arg(
+                arg!(--"cfg-overlay" <FILE> "config merged over --cfg, repeat to apply several in order")
+                    .required(false)
+                    .requires("cfg")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+            )
             .arg(
                 arg!(--"man" <FILE> "Output manifest file")
                     .required(false)
@@ -45,6 +65,106 @@ fn main() {
                 arg!(--"prebuilt-dir" <String> "prebuilt directory")
                     .required(false)
                     .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"owner-key-index" <N> "Owner manifest key slot in owner_man_key_configs")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+            )
+            .arg(
+                arg!(--"max-images" <N> "Fail when the config lists more images, 1 to 127")
+                    .required(false)
+                    .value_parser(parse_max_images),
+            )
+            .arg(
+                arg!(--"cache-dir" <DIR> "Directory of the image digest cache")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"out-dir" <DIR> "Directory of the intermediate files")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"print-caliptra-cfg" "Print the generated caliptra config to stderr")
+                    .required(false),
+            )
+            .arg(arg!(--"progress" "Show build progress on a terminal").required(false))
+            .arg(
+                arg!(--"format" <FORMAT> "Format of the summary printed after the build")
+                    .required(false)
+                    .default_value("text")
+                    .value_parser(["text", "json"]),
+            )
+            .arg(
+                arg!(--"allow-unauthenticated" "Allow images with ignore_auth_check = true")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"reproducible" "Sort the image metadata by fw_id for byte-identical builds")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"verify-digests" "Check digests given in the config against their files")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"verify-keys" "Check the manifest public keys match the config key files")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"compare-config" "Compare the image digests of an existing --man with the config instead of building")
+                    .required(false)
+                    .requires("man"),
+            )
+            .arg(
+                arg!(--"no-svn" "Skip the security version signature, for development builds")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"if-changed" "Skip the build when the inputs match the last build")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"layout" <LAYOUT> "Manifest layout, caliptra drops sec_ver and the SVN signatures")
+                    .required(false)
+                    .default_value("aspeed")
+                    .value_parser(["caliptra", "aspeed"]),
+            )
+            .arg(
+                arg!(--"tool-dir" <DIR> "Directory of the caliptra tools")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"tool-retries" <N> "Retries when an external tool fails to start")
+                    .required(false)
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                arg!(--"tool-timeout" <SECS> "Timeout in seconds for each external tool call, retries included")
+                    .required(false)
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                arg!(--"caliptra-cfg-out" <FILE> "Output path of the generated caliptra config")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"prev-man" <FILE> "Previous manifest to check security version against")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"artifact-mode" <OCTAL> "File mode of the output files, e.g. 0644 (unix only)")
+                    .required(false)
+                    .value_parser(parse_file_mode),
+            )
+            .arg(
+                arg!(--"artifact-read-only" "Clear the write permission of the output files")
+                    .required(false),
             ),
         Command::new("create-auth-flash")
             .about("Create a new authorization flash image")
@@ -53,6 +173,13 @@ fn main() {
                     .required(true)
                     .value_parser(value_parser!(String)),
             )
+            .arg(
+                arg!(--"cfg-overlay" <FILE> "config merged over --cfg, repeat to apply several in order")
+                    .required(false)
+                    .requires("cfg")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+            )
             .arg(
                 arg!(--"man" <FILE> "Input manifest file")
                     .required(false)
@@ -72,6 +199,326 @@ fn main() {
                 arg!(--"prebuilt-dir" <String> "prebuilt directory")
                     .required(false)
                     .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"tool-dir" <DIR> "Directory of the caliptra tools")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"tool-retries" <N> "Retries when an external tool fails to start")
+                    .required(false)
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                arg!(--"tool-timeout" <SECS> "Timeout in seconds for each external tool call, retries included")
+                    .required(false)
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                arg!(--"owner-key-index" <N> "Owner manifest key slot in owner_man_key_configs")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+            )
+            .arg(
+                arg!(--"max-images" <N> "Fail when the config lists more images, 1 to 127")
+                    .required(false)
+                    .value_parser(parse_max_images),
+            )
+            .arg(
+                arg!(--"cache-dir" <DIR> "Directory of the image digest cache")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"out-dir" <DIR> "Directory of the intermediate files")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"print-caliptra-cfg" "Print the generated caliptra config to stderr")
+                    .required(false),
+            )
+            .arg(arg!(--"progress" "Show build progress on a terminal").required(false))
+            .arg(
+                arg!(--"format" <FORMAT> "Format of the summary printed after the build")
+                    .required(false)
+                    .default_value("text")
+                    .value_parser(["text", "json"]),
+            )
+            .arg(
+                arg!(--"allow-unauthenticated" "Allow images with ignore_auth_check = true")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"reproducible" "Sort the image metadata by fw_id for byte-identical builds")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"verify-digests" "Check digests given in the config against their files")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"verify-keys" "Check the manifest public keys match the config key files")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"caliptra-fw" <FILE> "Caliptra firmware, overrides the config")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"mcu-runtime" <FILE> "MCU runtime firmware, overrides the config")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"fail-on-dummy" "Fail when a flash region would be backed by the empty dummy image")
+                    .required(false),
+            )
+            .arg(arg!(--"emit-checksum" "Write a <flash>.sha384 checksum file").required(false))
+            .arg(
+                arg!(--"strict-digest" "Re-hash the images in the flash and compare to the manifest")
+                    .required(false),
+            )
+            .arg(
+                arg!(--"artifact-mode" <OCTAL> "File mode of the output files, e.g. 0644 (unix only)")
+                    .required(false)
+                    .value_parser(parse_file_mode),
+            )
+            .arg(
+                arg!(--"artifact-read-only" "Clear the write permission of the output files")
+                    .required(false),
+            ),
+        Command::new("extract-manifest")
+            .about("Extract the SoC manifest from a flash image")
+            .arg(
+                arg!(--"flash" <FILE> "Input flash file")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"out" <FILE> "Output manifest file")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"cfg" <String> "config the manifest was built from, for its expected_magic")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--"prebuilt-dir" <String> "prebuilt directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"magic" <MAGIC> "Manifest magic, a u32 or 4 characters, instead of --cfg")
+                    .required(false)
+                    .conflicts_with("cfg")
+                    .value_parser(parse_magic),
+            ),
+        Command::new("diff")
+            .about("Compare two authorization manifests")
+            .arg(
+                arg!(--"man" <FILE> "Manifest files to compare")
+                    .required(true)
+                    .num_args(2)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"cfg" <String> "config the manifest was built from, for its expected_magic")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--"prebuilt-dir" <String> "prebuilt directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"magic" <MAGIC> "Manifest magic, a u32 or 4 characters, instead of --cfg")
+                    .required(false)
+                    .conflicts_with("cfg")
+                    .value_parser(parse_magic),
+            ),
+        Command::new("dump-auth-man")
+            .about("Print the fields of an authorization manifest")
+            .arg(
+                arg!(--"man" <FILE> "Manifest file to print")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"annotate-dummies" "Mark images backed by the dummy image in --cfg")
+                    .required(false)
+                    .requires("cfg"),
+            )
+            .arg(
+                arg!(--"cfg" <String> "config the manifest was built from")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--"cfg-overlay" <FILE> "config merged over --cfg, repeat to apply several in order")
+                    .required(false)
+                    .requires("cfg")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"prebuilt-dir" <String> "prebuilt directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"format" <FORMAT> "text, or json in the versioned manifest info schema")
+                    .required(false)
+                    .default_value("text")
+                    .value_parser(["text", "json"]),
+            ),
+        Command::new("generate-config-template")
+            .about("Write a commented example configuration file")
+            .arg(
+                arg!(--"out" <FILE> "Output config file, stdout when not given")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        Command::new("sign-digest")
+            .about("Sign a SHA384 digest, ecc in-process and lms with the example sign helper")
+            .arg(
+                arg!(--"algo" <ALGO> "Signing algorithm")
+                    .required(true)
+                    .value_parser(["ecc", "lms"]),
+            )
+            .arg(
+                arg!(--"key" <FILE> "Private key file, PEM for ecc, binary for lms")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"key-dir" <DIR> "Directory --key is relative to")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"input" <FILE> "Raw digest file, stdin when not given")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"output" <FILE> "Signature file, defaults to overwriting --input")
+                    .required(false)
+                    .requires("input")
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"stdin-format" <FORMAT> "Digest encoding on stdin")
+                    .required(false)
+                    .default_value("hex")
+                    .value_parser(["raw", "hex"]),
+            )
+            .arg(
+                arg!(--"helper" <FILE> "Sign helper executable, for lms")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"tool-dir" <DIR> "Directory of the caliptra tools and the sign helper")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        Command::new("convert-lms-sig")
+            .about("Convert an LMS signature between the raw and the portable format")
+            .arg(
+                arg!(--"input" <FILE> "LMS signature file in either format")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"output" <FILE> "Converted signature file")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"to" <FORMAT> "Output format")
+                    .required(false)
+                    .default_value("portable")
+                    .value_parser(["portable", "raw"]),
+            ),
+        Command::new("list-images")
+            .about("Print the image set the config resolves to")
+            .arg(
+                arg!(--"cfg" <String> "config path")
+                    .required(true)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--"cfg-overlay" <FILE> "config merged over --cfg, repeat to apply several in order")
+                    .required(false)
+                    .requires("cfg")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"prebuilt-dir" <String> "prebuilt directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        Command::new("validate-keys")
+            .about("Check the format of the ECC and LMS key files")
+            .arg(
+                arg!(--"cfg" <String> "config path, checks the keys it configures")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--"cfg-overlay" <FILE> "config merged over --cfg, repeat to apply several in order")
+                    .required(false)
+                    .requires("cfg")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"key-dir" <String> "key directory, checks every key file in it without --cfg")
+                    .required_unless_present("cfg")
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        Command::new("self-test")
+            .about("Round-trip a synthetic manifest to check the installation, no tools or keys needed"),
+        Command::new("config-check")
+            .about("Validate a configuration file without building anything")
+            .arg(
+                arg!(--"cfg" <String> "config path")
+                    .required(true)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--"cfg-overlay" <FILE> "config merged over --cfg, repeat to apply several in order")
+                    .required(false)
+                    .requires("cfg")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"key-dir" <String> "key directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"prebuilt-dir" <String> "prebuilt directory")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                arg!(--"owner-key-index" <N> "Owner manifest key slot in owner_man_key_configs")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+            )
+            .arg(
+                arg!(--"max-images" <N> "Fail when the config lists more images, 1 to 127")
+                    .required(false)
+                    .value_parser(parse_max_images),
             ),
     ];
 
@@ -80,150 +527,1360 @@ fn main() {
 
     let cmd: ArgMatches = Command::new("cptra-imgtool")
         .arg_required_else_help(true)
+        .arg(
+            arg!(--"threads" <N> "Worker threads, defaults to the number of CPUs. 1 runs serially")
+                .required(false)
+                .global(true)
+                .value_parser(value_parser!(usize)),
+        )
         .subcommands(sub_cmds)
         .about("Aspeed authorization manifest tools")
         .get_matches();
 
     let result = match cmd.subcommand().unwrap() {
-        ("create-auth-man", args) => run_auth_man_cmd(args),
-        ("create-auth-flash", args) => run_auth_flash_cmd(args),
+        ("create-auth-man", args) => run_auth_man_cmd(args, &tool::RealToolRunner::new(args)),
+        ("create-auth-flash", args) => run_auth_flash_cmd(args, &tool::RealToolRunner::new(args)),
+        ("extract-manifest", args) => run_extract_man_cmd(args),
+        ("diff", args) => run_diff_cmd(args),
+        ("dump-auth-man", args) => run_dump_man_cmd(args),
+        ("config-check", args) => run_config_check_cmd(args),
+        ("validate-keys", args) => run_validate_keys_cmd(args),
+        ("list-images", args) => run_list_images_cmd(args),
+        ("generate-config-template", args) => run_gen_cfg_template_cmd(args),
+        ("sign-digest", args) => run_sign_digest_cmd(args, &tool::RealToolRunner::new(args)),
+        ("convert-lms-sig", args) => run_convert_lms_sig_cmd(args),
+        ("self-test", _) => run_self_test_cmd(),
         (_, _) => unreachable!(),
     };
 
-    config::remove_tmp_folder().unwrap();
-    result.unwrap();
+    config::remove_tmp_folder().unwrap();
+    result.map_err(highlight_path_error).unwrap();
+}
+
+/* Path errors are shown in red like the warnings, only when stderr is a terminal */
+fn highlight_path_error(e: anyhow::Error) -> anyhow::Error {
+    let is_path_error = e
+        .downcast_ref::<error::ImgtoolError>()
+        .is_some_and(|e| e.is_path_error());
+    if is_path_error && std::io::stderr().is_terminal() {
+        anyhow::anyhow!("\x1b[31;1mError: {:#}\x1b[0m", e)
+    } else {
+        e
+    }
+}
+
+/* println!, on stderr when stdout carries the --format json output */
+macro_rules! report {
+    ($args:expr, $($arg:tt)*) => {
+        if json_output($args) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub(crate) fn show_important_cfg_path(args: &ArgMatches, cfg: &config::AspeedManifestCreationPath) {
+    report!(args, "-----------------------------------------------------------------------------------------------------------");
+    report!(args, "prebuilt_dir : {}", cfg.prebuilt_dir.display());
+    report!(
+        args,
+        "key_dir : {}",
+        cfg.key_dir
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<None>".to_string())
+    );
+    report!(
+        args,
+        "svn_sig : {}",
+        cfg.svn_sig
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<None>".to_string())
+    );
+    report!(
+        args,
+        "manifest : {}",
+        cfg.manifest
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<None>".to_string())
+    );
+    report!(
+        args,
+        "caliptra_cfg : {}",
+        cfg.caliptra_cfg
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<None>".to_string())
+    );
+    /* Only flash runs have an output flash image */
+    if let Some(flash_image) = cfg.flash_image.as_ref() {
+        report!(args, "flash_image : {}", flash_image.display());
+    }
+    report!(args, "-----------------------------------------------------------------------------------------------------------");
+}
+
+/* Owner manifest key slot from --owner-key-index, slot 0 when not given */
+fn owner_key_index(args: &ArgMatches) -> usize {
+    args.try_get_one::<usize>("owner-key-index")
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or(0)
+}
+
+/* --max-images value, the cap can only be lowered below the metadata capacity */
+fn parse_max_images(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(max) if (1..=layout::IMAGE_METADATA_MAX_COUNT).contains(&max) => Ok(max),
+        _ => Err(format!(
+            "\"{}\" is not an image count from 1 to {}",
+            s,
+            layout::IMAGE_METADATA_MAX_COUNT
+        )),
+    }
+}
+
+/* --max-images, None for the metadata capacity */
+fn max_images(args: &ArgMatches) -> Option<usize> {
+    args.try_get_one::<usize>("max-images")
+        .ok()
+        .flatten()
+        .copied()
+}
+
+/* --magic value: a number, hex with 0x, or 4 characters like the config's expected_magic */
+fn parse_magic(s: &str) -> Result<u32, String> {
+    let number = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.parse::<u32>().ok(),
+    };
+    number
+        .or_else(|| config::magic_from_text(s))
+        .ok_or_else(|| format!("\"{}\" is not a u32 or 4 ASCII characters", s))
+}
+
+/* --artifact-mode value: an octal file mode, with or without a leading 0 or 0o */
+fn parse_file_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("\"{}\" is not an octal file mode like 0644", s)),
+    }
+}
+
+/* Apply --artifact-mode and --artifact-read-only to a final output file */
+fn set_artifact_permissions(args: &ArgMatches, file: &Path) -> anyhow::Result<()> {
+    let mode = args
+        .try_get_one::<u32>("artifact-mode")
+        .ok()
+        .flatten()
+        .copied();
+    let read_only = args
+        .try_get_one::<bool>("artifact-read-only")
+        .ok()
+        .flatten()
+        == Some(&true);
+    if mode.is_none() && !read_only {
+        return Ok(());
+    }
+
+    utility::set_artifact_permissions(file, mode, read_only)
+        .with_context(|| format!("Failed to set the permissions of {}", file.display()))
+}
+
+/* --format json asks for machine readable output */
+fn json_output(args: &ArgMatches) -> bool {
+    args.try_get_one::<String>("format")
+        .ok()
+        .flatten()
+        .is_some_and(|f| f == "json")
+}
+
+pub(crate) fn run_auth_man_cmd(args: &ArgMatches, runner: &dyn ToolRunner) -> anyhow::Result<()> {
+    if args.get_flag("compare-config") {
+        return compare_config(args);
+    }
+
+    let (cfg, summary) = create_auth_man(args, runner)?;
+    run_post_build_hook(runner, &cfg, &summary)?;
+    summary.print(json_output(args));
+
+    Ok(())
+}
+
+/* Run the config's post_build_hook on the outputs in `summary`, before the
+ * summary is printed. With --format json its stdout goes to stderr.
+ */
+fn run_post_build_hook(
+    runner: &dyn ToolRunner,
+    cfg: &config::AspeedAuthManifestConfigFromFile,
+    summary: &summary::RunSummary,
+) -> anyhow::Result<()> {
+    let Some(hook) = cfg.post_build_hook.as_ref() else {
+        return Ok(());
+    };
+
+    let program = PathBuf::from(&hook.program);
+    let hook_args = hook.expand_args(summary.manifest_path(), summary.flash_path());
+    info!("Running post_build_hook {:?} {:?}", program, hook_args);
+    let status = runner.run(&program, &tool::tool_args(hook_args), None)?;
+    if !status.success() {
+        return Err(error::ImgtoolError::ToolFailed { program, status })
+            .with_context(|| "post_build_hook failed");
+    }
+
+    Ok(())
 }
 
-pub(crate) fn show_important_cfg_path(cfg: &config::AspeedManifestCreationPath) {
-    println!("-----------------------------------------------------------------------------------------------------------");
-    println!("prebuilt_dir : {}", cfg.prebuilt_dir.display());
-    println!(
-        "key_dir : {}",
-        cfg.key_dir
-            .as_ref()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|| "<None>".to_string())
-    );
-    println!(
-        "svn_sig : {}",
-        cfg.svn_sig
-            .as_ref()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|| "<None>".to_string())
-    );
-    println!(
-        "manifest : {}",
-        cfg.manifest
-            .as_ref()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|| "<None>".to_string())
-    );
+/* Re-derive the image digests from the config and check the manifest at --man
+ * carries them, catching a stale caliptra config or a manifest edited by hand.
+ */
+fn compare_config(args: &ArgMatches) -> anyhow::Result<()> {
+    let path = config::AspeedManifestCreationPath::new_manifest(args)
+        .with_context(|| "Failed to create manifest creation path")?;
+    debug!("Manifest auth path:\n{:#?}", path);
+
+    let cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
+    let manifest = path.manifest.unwrap_or_err();
+    let soc_man = soc_man::AspeedAuthorizationManifest::open(&manifest, cfg.expected_magic())?;
+
+    /* Always hash the files, a cached digest could hide the drift being looked for */
+    let expected = cfg.image_metadata(
+        &mut cache::DigestCache::default(),
+        &progress::Progress::new(args),
+        utility::thread_count(args),
+        args.get_flag("verify-digests"),
+    )?;
+    let mismatches = soc_man.compare_metadata(&expected, &cfg.image_names());
+    if !mismatches.is_empty() {
+        return Err(error::ImgtoolError::Manifest(format!(
+            "{} does not match {}:\n{}",
+            manifest.display(),
+            path.aspeed_cfg.display(),
+            mismatches.join("\n")
+        ))
+        .into());
+    }
+
     println!(
-        "caliptra_cfg : {}",
-        cfg.caliptra_cfg
-            .as_ref()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|| "<None>".to_string())
+        "{} matches {} ({} images)",
+        manifest.display(),
+        path.aspeed_cfg.display(),
+        expected.len()
     );
-    println!("-----------------------------------------------------------------------------------------------------------");
+
+    Ok(())
 }
 
-pub(crate) fn run_auth_man_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+/* Build the manifest, the summary describes the manifest written or left up to date */
+fn create_auth_man(
+    args: &ArgMatches,
+    runner: &dyn ToolRunner,
+) -> anyhow::Result<(
+    config::AspeedAuthManifestConfigFromFile,
+    summary::RunSummary,
+)> {
     let path = config::AspeedManifestCreationPath::new_manifest(args)
         .with_context(|| "Failed to create manifest creation path")?;
     debug!("Manifest auth path:\n{:#?}", path);
-    show_important_cfg_path(&path);
+    show_important_cfg_path(args, &path);
 
     /* Create caliptra manifest config according to aspeed manifest config */
-    let cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
-    cfg.save_caliptra_cfg(&path)?;
+    let mut cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
+    cfg.validate_image_count(max_images(args))?;
+    /* The manifest of create-auth-flash authorizes its --mcu-runtime image */
+    if let Ok(Some(mcu_runtime)) = args.try_get_one::<PathBuf>("mcu-runtime") {
+        cfg.override_mcu_runtime(
+            config::decompress_img_if_needed(mcu_runtime.clone(), "mcu-runtime")?.to_string(),
+        );
+    }
+    /* Only create-auth-flash, which also builds its manifest here, has --flash */
+    if let Some(hook) = cfg.post_build_hook.as_ref() {
+        hook.validate_args(args.try_get_one::<PathBuf>("flash").is_ok())?;
+    }
+    cfg.select_owner_man_key(owner_key_index(args))?;
+    cfg.validate_owner_key_fields()?;
+    cfg.validate_auth_checks(
+        args.try_get_one::<bool>("allow-unauthenticated")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+    )?;
+    let reproducible = args
+        .try_get_one::<bool>("reproducible")
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or(false);
+    if reproducible {
+        cfg.sort_images_by_fw_id();
+    }
+
+    /* Refuse to build a manifest that would allow a security version rollback */
+    if let Ok(Some(prev_man)) = args.try_get_one::<PathBuf>("prev-man") {
+        soc_man::check_security_version(
+            prev_man,
+            cfg.manifest_config.security_version,
+            cfg.expected_magic(),
+        )?;
+    }
+
+    let no_svn = args
+        .try_get_one::<bool>("no-svn")
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or(false);
+    let layout = match args.try_get_one::<String>("layout").ok().flatten() {
+        Some(layout) if layout == "caliptra" => soc_man::ManifestLayout::Caliptra,
+        _ => soc_man::ManifestLayout::Aspeed,
+    };
+
+    /* Skip the build when nothing the manifest is made from changed since the last one */
+    let manifest = path.manifest.unwrap_or_err();
+    let mut inputs_file = manifest.clone().into_os_string();
+    inputs_file.push(".inputs");
+    let inputs_file = PathBuf::from(inputs_file);
+    let fingerprint = if args.try_get_one::<bool>("if-changed").ok().flatten() == Some(&true) {
+        let fingerprint = cfg.input_fingerprint(
+            &path,
+            runner,
+            &path.auth_manifest_tool(),
+            args.try_get_one::<PathBuf>("prev-man")
+                .ok()
+                .flatten()
+                .map(PathBuf::as_path),
+            &[
+                format!("no-svn={}", no_svn),
+                format!("layout={:?}", layout),
+                format!("reproducible={}", reproducible),
+                format!("owner-key-index={}", owner_key_index(args)),
+            ],
+        )?;
+        if manifest.is_file()
+            && std::fs::read_to_string(&inputs_file).ok().as_deref() == Some(&fingerprint)
+        {
+            report!(args, "manifest up to date");
+            let summary = summary::RunSummary::new(&path, &cfg, None)?;
+            return Ok((cfg, summary));
+        }
+        Some(fingerprint)
+    } else {
+        None
+    };
+
+    let caliptra_cfg = path
+        .caliptra_cfg
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No caliptra config path for this command"))?;
+    let mut cache = cache::DigestCache::open(
+        args.try_get_one::<PathBuf>("cache-dir").ok().flatten(),
+        runner,
+        &path.auth_manifest_tool(),
+    )?;
+    let progress = progress::Progress::new(args);
+    cfg.save_caliptra_cfg(
+        caliptra_cfg,
+        &mut cache,
+        &progress,
+        utility::thread_count(args),
+        args.try_get_one::<bool>("verify-digests")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+    )?;
+    cache.save()?;
+
+    /* Show the config with the computed digests as the caliptra tool will read it */
+    if args.get_flag("print-caliptra-cfg") {
+        let caliptra_cfg_str = std::fs::read_to_string(caliptra_cfg)
+            .with_context(|| format!("Failed to read {}", caliptra_cfg.display()))?;
+        eprintln!("# {}", caliptra_cfg.display());
+        eprint!("{}", caliptra_cfg_str);
+    }
 
     /* To satisfy the key-dir validation requirements of caliptra-auth-manifest-app */
     let key_dir = cfg.validate_key_dir_if_needed(path.key_dir.as_deref())?;
     debug!("key_dir_to_auth_manifest_tool: {:#?}", key_dir.display());
 
     /* Run the caliptra manifest tool to create the manifest */
-    let cmd = path.tool_dir.join("caliptra-auth-manifest-app");
-    config::check_path_exists(cmd.as_path())?;
-
-    let mut child = std::process::Command::new(cmd)
-        .args([
-            "create-aspeed-auth-man",
-            "--version",
-            &cfg.manifest_config.version.to_string(),
-            "--flags",
-            &cfg.manifest_config.flags.to_string(),
-            "--key-dir",
-            &key_dir.to_string(),
-            "--config",
-            &path.caliptra_cfg.to_string(),
-            "--out",
-            &path.manifest.to_string(),
-        ])
-        .spawn()
-        .expect("Failed to execute command");
+    let cmd = path.auth_manifest_tool();
+    config::check_file_exists(cmd.as_path())?;
+    cfg.check_caliptra_tool_version(runner, &cmd)?;
 
-    /* Wait for the process to exit */
-    let _ = child.wait().expect("Failed to wait on child");
+    /* Build and post-process the manifest in a staging file, the final name is
+     * only replaced by a complete manifest
+     */
+    let staging = utility::staging_path(&manifest);
+    let staged = (|| -> anyhow::Result<soc_man::AspeedAuthorizationManifest> {
+        progress.step("Creating the SoC manifest");
+        let status = runner.run(
+            &cmd,
+            &tool::tool_args([
+                "create-aspeed-auth-man",
+                "--version",
+                &cfg.manifest_config.version.to_string(),
+                "--flags",
+                &cfg.manifest_config.flags.bits().to_string(),
+                "--key-dir",
+                &key_dir.to_string(),
+                "--config",
+                &path.caliptra_cfg.to_string(),
+                "--out",
+                &staging.to_string(),
+            ]),
+            None,
+        )?;
+        if !status.success() {
+            return Err(error::ImgtoolError::ToolFailed {
+                program: cmd.clone(),
+                status,
+            }
+            .into());
+        }
 
-    /* Post-Processing to meet aspeed proprietary feature */
-    let mut soc_man = soc_man::AspeedAuthorizationManifest::new(&path.manifest.unwrap_or_err());
-    soc_man.modify_vnd_ecc_sig()?;
-    soc_man.modify_vnd_lms_sig()?;
-    soc_man.insert_security_version(&path, &cfg, &key_dir);
-    soc_man.close();
+        /* Post-Processing to meet aspeed proprietary feature */
+        let mut soc_man = soc_man::AspeedAuthorizationManifest::new(&staging)?;
+        soc_man.validate_magic(&cfg)?;
+        if args.try_get_one::<bool>("verify-keys").ok().flatten() == Some(&true) {
+            soc_man.verify_public_keys(&cfg, &key_dir)?;
+        }
+        soc_man.modify_vnd_ecc_sig()?;
+        soc_man.modify_vnd_lms_sig()?;
+        /* The caliptra layout has no room for sec_ver and the SVN signatures */
+        let svn_signed = !no_svn && layout == soc_man::ManifestLayout::Aspeed;
+        if layout == soc_man::ManifestLayout::Caliptra {
+            info!("Caliptra layout, sec_ver and the SVN signatures are not written.");
+        } else if no_svn {
+            utility::print_warning(
+                "--no-svn given, sec_ver and the SVN signatures are left zero. \
+                 This manifest is not rollback-protected.",
+            );
+        } else {
+            soc_man.insert_security_version(&path, &cfg, &key_dir, runner)?;
+        }
+        /* Vendor and owner signatures must all be in place before the manifest is written */
+        soc_man.validate_signatures(&cfg, svn_signed)?;
+        soc_man.close(layout)?;
+        Ok(soc_man)
+    })()
+    .and_then(|soc_man| {
+        std::fs::rename(&staging, &manifest)
+            .with_context(|| format!("Failed to rename to {}", manifest.display()))?;
+        Ok(soc_man)
+    });
+    let soc_man = staged.inspect_err(|_| {
+        let _ = std::fs::remove_file(&staging);
+    })?;
+    set_artifact_permissions(args, &manifest)?;
 
-    Ok(())
+    if let Some(fingerprint) = fingerprint {
+        std::fs::write(&inputs_file, fingerprint)
+            .with_context(|| format!("Failed to write {}", inputs_file.display()))?;
+    }
+
+    let summary = summary::RunSummary::new(&path, &cfg, Some(&soc_man))?;
+    Ok((cfg, summary))
 }
 
-pub(crate) fn run_auth_flash_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+pub(crate) fn run_auth_flash_cmd(args: &ArgMatches, runner: &dyn ToolRunner) -> anyhow::Result<()> {
     let path = config::AspeedManifestCreationPath::new_flash(args)
         .with_context(|| "Failed to create manifest creation path")?;
     debug!("Flash auth path:\n{:#?}", path);
+    show_important_cfg_path(args, &path);
+
+    /* Get the aspeed configuration */
+    let mut cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
+    cfg.validate_image_count(max_images(args))?;
+
+    /* Runtime images given on the command line replace the ones in the config */
+    for (arg_id, file) in [
+        ("caliptra-fw", &mut cfg.image_runtime_list.caliptra_file),
+        ("mcu-runtime", &mut cfg.image_runtime_list.mcu_file),
+    ] {
+        if let Some(override_file) = args.get_one::<PathBuf>(arg_id) {
+            config::check_file_exists(override_file)?;
+            utility::print_warning(&format!(
+                "--{} override in effect: using {} instead of {} from the config",
+                arg_id,
+                override_file.display(),
+                file
+            ));
+            *file = config::decompress_img_if_needed(override_file.clone(), arg_id)?.to_string();
+        }
+    }
+    /* The MCU runtime image is in the manifest as well */
+    if args.contains_id("mcu-runtime") {
+        cfg.override_mcu_runtime(cfg.image_runtime_list.mcu_file.clone());
+    }
+
+    /* An empty runtime or SoC image region leaves the flash image unbootable */
+    if args.get_flag("fail-on-dummy") {
+        let fields = cfg.dummy_flash_fields();
+        if !fields.is_empty() {
+            return Err(error::ImgtoolError::Config(format!(
+                "--fail-on-dummy: flash regions backed by the dummy image: {}",
+                fields.join(", ")
+            ))
+            .into());
+        }
+    }
+
+    /* Every image in the flash image needs its file */
+    let digest_only = cfg
+        .image_metadata_list
+        .iter()
+        .filter(|img| img.digest_only())
+        .map(|img| img.label())
+        .collect::<Vec<_>>();
+    if !digest_only.is_empty() {
+        return Err(error::ImgtoolError::Config(format!(
+            "{} given by digest only, a file is needed for the flash image",
+            digest_only.join(", ")
+        ))
+        .into());
+    }
+
+    /* Running out of space deep in the flash image tool gives a confusing error */
+    let flash = path.flash_image.unwrap_or_err();
+    let output_dir = flash
+        .parent()
+        .filter(|_| !utility::is_special_file(&flash))
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        });
+    config::check_free_space(output_dir, cfg.flash_size_estimate())?;
 
     /* If the user didn't specify the prebuild manifest, create it. */
     if !args.contains_id("man") {
-        run_auth_man_cmd(args)?;
+        create_auth_man(args, runner)?;
     }
 
-    /* Get the aspeed configuration */
-    let cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
+    /* Same image order as the manifest */
+    if args.get_flag("reproducible") {
+        cfg.sort_images_by_fw_id();
+    }
 
     /* To meet requirement: add FMC to SoC manifest but not in flash images list */
-    const MCU_RUN_TIME_FW_ID: u32 = 1;
-    /* Run the caliptra flash image tool to create the flash image */
-    let bl_list_args = std::iter::once("--soc-images")
-        .chain(
-            cfg.image_metadata_list
-                .iter()
-                .filter(|img| img.fw_id != MCU_RUN_TIME_FW_ID)
-                .map(|s| s.file.as_str()),
-        )
-        .collect::<Vec<_>>();
+    cfg.validate_mcu_runtime_image()?;
+    let bl_list_args = soc_images_args(&cfg);
     debug!("Caliptra flash image tool args: {:#?}", bl_list_args);
 
-    let cmd = path.tool_dir.join("xtask");
-    config::check_path_exists(cmd.as_path())?;
+    let cmd = path.flash_tool();
+    config::check_file_exists(cmd.as_path())?;
+
+    /* Build and check the flash image under a staging name, a failed or killed
+     * build never leaves a partial file under the final name. A FIFO or device
+     * node is built in the temp dir and only opened once the image is complete.
+     */
+    let to_device = utility::is_special_file(&flash);
+    let staging = if to_device {
+        config::tmp_file_path("flash-image.bin")
+    } else {
+        utility::staging_path(&flash)
+    };
+    let result = build_flash_image(args, runner, &path, &cfg, &cmd, &staging, bl_list_args)
+        .and_then(|_| {
+            if to_device {
+                return Ok(());
+            }
+            std::fs::rename(&staging, &flash)
+                .with_context(|| format!("Failed to rename {} into place", staging.display()))
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&staging);
+    }
+    result?;
+    if !to_device {
+        set_artifact_permissions(args, &flash)?;
+    }
+
+    let built = if to_device { &staging } else { &flash };
+    let img = std::fs::read(built)
+        .with_context(|| format!("Failed to read flash image {}", built.display()))?;
+    let mut emit_checksum = args.get_flag("emit-checksum");
+    if to_device {
+        write_to_device(args, &flash, &img)?;
+        if emit_checksum {
+            utility::print_warning(&format!(
+                "--emit-checksum ignored, {} is not a regular file",
+                flash.display()
+            ));
+            emit_checksum = false;
+        }
+    }
+
+    report_flash_image(args, &flash, &img, emit_checksum, cfg.expected_magic())?;
+    let summary = summary::RunSummary::new(&path, &cfg, None)?.with_flash_size(img.len() as u64);
+    run_post_build_hook(runner, &cfg, &summary)?;
+    summary.print(json_output(args));
 
-    let mut child = std::process::Command::new(cmd)
-        .args([
-            "flash-image",
-            "create",
-            "--caliptra-fw",
+    Ok(())
+}
+
+/* `--soc-images` of the flash image tool: every image but the MCU runtime. A
+ * --soc-images without values is an error for the tool, so leave it out without
+ * SoC images.
+ */
+fn soc_images_args(cfg: &config::AspeedAuthManifestConfigFromFile) -> Vec<&str> {
+    let soc_images = soc_images(cfg).map(|s| s.file.as_str()).collect::<Vec<_>>();
+    if soc_images.is_empty() {
+        Vec::new()
+    } else {
+        std::iter::once("--soc-images").chain(soc_images).collect()
+    }
+}
+
+/* Every image of the config but the MCU runtime, in --soc-images order */
+fn soc_images(
+    cfg: &config::AspeedAuthManifestConfigFromFile,
+) -> impl Iterator<Item = &config::AspeedImageMetadataConfigFromFile> {
+    let mcu_runtime_fw_id = cfg.mcu_runtime_fw_id();
+    cfg.image_metadata_list
+        .iter()
+        .filter(move |img| img.fw_id != mcu_runtime_fw_id)
+}
+
+/* Run the flash image tool into `staging`, add the extra regions and check the digests */
+fn build_flash_image(
+    args: &ArgMatches,
+    runner: &dyn ToolRunner,
+    path: &config::AspeedManifestCreationPath,
+    cfg: &config::AspeedAuthManifestConfigFromFile,
+    cmd: &Path,
+    staging: &Path,
+    bl_list_args: Vec<&str>,
+) -> anyhow::Result<()> {
+    /* Catch layout mistakes before spending time on the flash image tool */
+    let flash_size = cfg.manifest_config.flash_size;
+    if let Some(flash_size) = flash_size {
+        let images = [
             &cfg.image_runtime_list.caliptra_file,
-            "--soc-manifest",
             &path.manifest.to_string(),
-            "--mcu-runtime",
             &cfg.image_runtime_list.mcu_file,
-            "--output",
-            &path.flash_image.to_string(),
-        ])
-        .args(bl_list_args)
-        .spawn()
-        .expect("Failed to execute command");
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .chain(bl_list_args.iter().skip(1).map(PathBuf::from))
+        .collect::<Vec<_>>();
+        let regions = cfg
+            .extra_flash_regions
+            .iter()
+            .flatten()
+            .map(|r| (PathBuf::from(&r.file), r.offset))
+            .collect::<Vec<_>>();
+        soc_man::check_flash_layout(&images, &regions, flash_size)?;
+    }
+
+    progress::Progress::new(args).step("Creating the flash image");
+    let status = runner.run(
+        cmd,
+        &tool::tool_args(
+            [
+                "flash-image",
+                "create",
+                "--caliptra-fw",
+                &cfg.image_runtime_list.caliptra_file,
+                "--soc-manifest",
+                &path.manifest.to_string(),
+                "--mcu-runtime",
+                &cfg.image_runtime_list.mcu_file,
+                "--output",
+                &staging.to_path_buf().to_string(),
+            ]
+            .into_iter()
+            .chain(bl_list_args),
+        ),
+        None,
+    )?;
+    if !status.success() {
+        return Err(error::ImgtoolError::ToolFailed {
+            program: cmd.to_path_buf(),
+            status,
+        }
+        .into());
+    }
+
+    /* Board specific blobs go after the regions written by the flash image tool */
+    if let Some(regions) = cfg.extra_flash_regions.as_ref() {
+        let flash = staging;
+        let mut img = std::fs::read(flash)
+            .with_context(|| format!("Failed to read flash image {}", flash.display()))?;
+        let regions = regions
+            .iter()
+            .map(|r| (PathBuf::from(&r.file), r.offset))
+            .collect::<Vec<_>>();
+        soc_man::splice_flash_regions(&mut img, &regions)?;
+        std::fs::write(flash, img)
+            .with_context(|| format!("Failed to write flash image {}", flash.display()))?;
+    }
+
+    /* The tool may align the images, so check the real size too */
+    if let Some(flash_size) = flash_size {
+        let len = std::fs::metadata(staging)
+            .with_context(|| format!("Failed to stat flash image {}", staging.display()))?
+            .len();
+        if len > flash_size {
+            return Err(error::ImgtoolError::Config(format!(
+                "Flash image is {} bytes, larger than flash_size {}",
+                len, flash_size
+            ))
+            .into());
+        }
+    }
+
+    /* Catch images that changed between digesting and flash creation */
+    if args.get_flag("strict-digest") {
+        let flash = path.flash_image.unwrap_or_err();
+        let img = std::fs::read(staging)
+            .with_context(|| format!("Failed to read flash image {}", flash.display()))?;
+        let digest_ranges = cfg
+            .image_metadata_list
+            .iter()
+            .filter(|img| img.digest_offset.is_some() || img.digest_len.is_some())
+            .map(|img| Ok((img.fw_id, img.digest_range()?)))
+            .collect::<anyhow::Result<_>>()?;
+        /* The flash image tool writes the MCU runtime, then the --soc-images */
+        let flash_fw_ids = std::iter::once(cfg.mcu_runtime_fw_id())
+            .chain(soc_images(cfg).map(|img| img.fw_id))
+            .collect::<Vec<_>>();
+        soc_man::verify_flash_digests(
+            &flash,
+            &img,
+            &flash_fw_ids,
+            &digest_ranges,
+            &cfg.image_names(),
+            cfg.expected_magic(),
+        )?;
+        report!(args, "Flash image content matches the manifest digests");
+    }
+
+    Ok(())
+}
+
+/* Write into an existing FIFO or device node, never creating or truncating it */
+fn write_to_device(args: &ArgMatches, dev: &Path, img: &[u8]) -> anyhow::Result<()> {
+    let mut out = std::fs::OpenOptions::new()
+        .write(true)
+        .open(dev)
+        .with_context(|| format!("Failed to open {}", dev.display()))?;
+    out.write_all(img)
+        .and_then(|_| out.flush())
+        .with_context(|| format!("Failed to write flash image to {}", dev.display()))?;
+    report!(args, "Flash image written to {}", dev.display());
+
+    Ok(())
+}
+
+/// Print the size, checksum and manifest location of the flash image `img`
+/// written to `flash`.
+pub(crate) fn report_flash_image(
+    args: &ArgMatches,
+    flash: &Path,
+    img: &[u8],
+    emit_checksum: bool,
+    magic: u32,
+) -> anyhow::Result<()> {
+    let digest = hex::encode(Sha384::digest(img));
+
+    report!(args, "-----------------------------------------------------------------------------------------------------------");
+    report!(args, "flash_image : {}", flash.display());
+    report!(args, "size : {} bytes", img.len());
+    report!(args, "sha384 : {}", digest);
+    match soc_man::find_manifest_in_flash(img, magic) {
+        Ok((offset, len)) => {
+            report!(
+                args,
+                "soc_manifest : offset 0x{:08x}, length {} bytes",
+                offset,
+                len
+            )
+        }
+        Err(e) => report!(args, "soc_manifest : <{}>", e),
+    }
+    report!(args, "-----------------------------------------------------------------------------------------------------------");
+
+    /* Write a sha384sum compatible sidecar next to the flash image */
+    if emit_checksum {
+        let mut sidecar = flash.as_os_str().to_owned();
+        sidecar.push(".sha384");
+        let name = flash
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        std::fs::write(&sidecar, format!("{}  {}\n", digest, name))
+            .with_context(|| format!("Failed to write checksum file {:?}", sidecar))?;
+        report!(
+            args,
+            "Checksum written to {}",
+            PathBuf::from(sidecar).display()
+        );
+    }
+
+    Ok(())
+}
+
+/* The config a manifest was built from, given with --cfg. It may use a forked magic */
+fn manifest_cfg(
+    args: &ArgMatches,
+) -> anyhow::Result<Option<config::AspeedAuthManifestConfigFromFile>> {
+    if !args.contains_id("cfg") {
+        return Ok(None);
+    }
+
+    let path = config::AspeedManifestCreationPath::new_check(args)
+        .with_context(|| "Failed to create config path")?;
+    Ok(Some(config::AspeedAuthManifestConfigFromFile::new(&path)?))
+}
+
+/* Magic to look for: --magic, else expected_magic of --cfg, else the caliptra magic */
+fn manifest_magic(args: &ArgMatches) -> anyhow::Result<u32> {
+    if let Some(magic) = args.try_get_one::<u32>("magic").ok().flatten() {
+        return Ok(*magic);
+    }
+
+    Ok(manifest_cfg(args)?.map_or(layout::AUTH_MANIFEST_MAGIC, |cfg| cfg.expected_magic()))
+}
+
+pub(crate) fn run_extract_man_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let flash = args
+        .get_one::<PathBuf>("flash")
+        .with_context(|| "flash arg not specified")?;
+    let out = args
+        .get_one::<PathBuf>("out")
+        .with_context(|| "out arg not specified")?;
+    config::check_path_exists(flash)?;
+
+    let img = std::fs::read(flash)
+        .with_context(|| format!("Failed to read flash image {}", flash.display()))?;
+    let (offset, len) = soc_man::find_manifest_in_flash(&img, manifest_magic(args)?)?;
+    debug!(
+        "SoC manifest found at offset 0x{:08x}, length {}",
+        offset, len
+    );
+
+    std::fs::write(out, &img[offset..offset + len])
+        .with_context(|| format!("Failed to write manifest file {}", out.display()))?;
+    println!(
+        "SoC manifest extracted from {} (offset 0x{:08x}, {} bytes) to {}",
+        flash.display(),
+        offset,
+        len,
+        out.display()
+    );
 
-    /* Wait for the process to exit */
-    let _ = child.wait().expect("Failed to wait on child");
+    Ok(())
+}
+
+pub(crate) fn run_diff_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let mans = args
+        .get_many::<PathBuf>("man")
+        .with_context(|| "man arg not specified")?
+        .collect::<Vec<_>>();
+
+    let magic = manifest_magic(args)?;
+    let old = soc_man::AspeedAuthorizationManifest::open(mans[0], magic)?;
+    let new = soc_man::AspeedAuthorizationManifest::open(mans[1], magic)?;
+
+    let diffs = old.diff(&new);
+    if diffs.is_empty() {
+        println!("Manifests are identical");
+        return Ok(());
+    }
+
+    for d in diffs.iter() {
+        println!("{}", d);
+    }
+
+    Err(anyhow::anyhow!(
+        "Manifests differ in {} field(s)",
+        diffs.len()
+    ))
+}
+
+pub(crate) fn run_dump_man_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let man = args
+        .get_one::<PathBuf>("man")
+        .with_context(|| "man arg not specified")?;
+
+    let cfg = manifest_cfg(args)?;
+    let magic = cfg
+        .as_ref()
+        .map_or(layout::AUTH_MANIFEST_MAGIC, |cfg| cfg.expected_magic());
+
+    /* Empty image entries in the config were built from the dummy image */
+    let names = cfg
+        .as_ref()
+        .map(|cfg| cfg.image_names())
+        .unwrap_or_default();
+    let dummy_fw_ids = cfg
+        .filter(|_| args.get_flag("annotate-dummies"))
+        .map(|cfg| cfg.dummy_fw_ids());
+
+    if json_output(args) {
+        let parsed = cptra_imgtool::ParsedManifest::from_path_with_magic(man, magic)?;
+        println!(
+            "{}",
+            info::ManifestInfo::new(&parsed, dummy_fw_ids.as_ref(), &names).to_json()
+        );
+        return Ok(());
+    }
+
+    let soc_man = soc_man::AspeedAuthorizationManifest::open(man, magic)?;
+
+    println!("-----------------------------------------------------------------------------------------------------------");
+    println!("manifest : {}", man.display());
+    for line in soc_man.dump(dummy_fw_ids.as_ref(), &names) {
+        println!("{}", line);
+    }
+    println!("-----------------------------------------------------------------------------------------------------------");
+
+    Ok(())
+}
+
+pub(crate) fn run_gen_cfg_template_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let template = config::AspeedAuthManifestConfigFromFile::template_toml()?;
+
+    match args.get_one::<PathBuf>("out") {
+        Some(out) => {
+            std::fs::write(out, template)
+                .with_context(|| format!("Failed to write config template {}", out.display()))?;
+            println!("Config template written to {}", out.display());
+        }
+        None => print!("{}", template),
+    }
+
+    Ok(())
+}
+
+/// Sign a digest the same way the manifest build does with a sign helper: ECC
+/// signatures are DER, LMS signatures are the raw caliptra structure. File mode
+/// writes the signature file, stdin mode prints it as hex on stdout.
+pub(crate) fn run_sign_digest_cmd(
+    args: &ArgMatches,
+    runner: &dyn ToolRunner,
+) -> anyhow::Result<()> {
+    let key = args.get_one::<PathBuf>("key").unwrap();
+    let key = match args.get_one::<PathBuf>("key-dir") {
+        Some(dir) => dir.join(key),
+        None => key.clone(),
+    };
+    config::check_file_exists(&key)?;
+
+    if args.get_one::<String>("algo").unwrap() == "lms" {
+        return run_sign_helper_lms(args, runner, &key);
+    }
+
+    /* ECC signing needs no helper, p384 signs in-process */
+    match args.get_one::<PathBuf>("input") {
+        Some(input) => {
+            config::check_file_exists(input)?;
+            let file = std::fs::File::open(input)
+                .with_context(|| format!("Failed to open {}", input.display()))?;
+            let digest = sign::read_digest(&mut std::io::BufReader::new(file), false)?;
+            let der = sign::ecc_sign_digest(&key, &digest)?;
+            let output = args.get_one::<PathBuf>("output").unwrap_or(input);
+            utility::write_atomic(output, &der)
+                .with_context(|| format!("Failed to write {}", output.display()))?;
+            eprintln!("ECC signature written to {}", output.display());
+        }
+        None => {
+            let hex_input = args.get_one::<String>("stdin-format").unwrap() == "hex";
+            let digest = sign::read_digest(&mut std::io::stdin().lock(), hex_input)?;
+            println!("{}", hex::encode(sign::ecc_sign_digest(&key, &digest)?));
+        }
+    }
+
+    Ok(())
+}
+
+/* LMS signing stays in the example helper, it needs caliptra-image-crypto */
+fn run_sign_helper_lms(
+    args: &ArgMatches,
+    runner: &dyn ToolRunner,
+    key: &Path,
+) -> anyhow::Result<()> {
+    let helper = config::sign_helper_path(args)?;
+
+    /* The helper still wants a key role, --key-path overrides the file it picks */
+    let mut helper_args = tool::tool_args(["--algo", "lms", "--key", "man", "--key-path"]);
+    helper_args.push(key.into());
+
+    match args.get_one::<PathBuf>("input") {
+        Some(input) => {
+            config::check_file_exists(input)?;
+            helper_args.extend(tool::tool_args(["--by-file", "--input"]));
+            helper_args.push(input.into());
+            if let Some(output) = args.get_one::<PathBuf>("output") {
+                helper_args.push("--output".into());
+                helper_args.push(output.into());
+            }
+        }
+        None => {
+            helper_args.push("--stdin-format".into());
+            helper_args.push(args.get_one::<String>("stdin-format").unwrap().into());
+        }
+    }
+
+    let status = runner.run(&helper, &helper_args, None)?;
+    if !status.success() {
+        return Err(error::ImgtoolError::ToolFailed {
+            program: helper,
+            status,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run_convert_lms_sig_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let input = args.get_one::<PathBuf>("input").unwrap();
+    let output = args.get_one::<PathBuf>("output").unwrap();
+    config::check_file_exists(input)?;
+
+    let sig = std::fs::read(input)
+        .with_context(|| format!("Failed to read LMS signature {}", input.display()))?;
+    let raw = lms_sig::parse_lms_sig(&sig)
+        .with_context(|| format!("Failed to parse LMS signature {}", input.display()))?;
+    let converted = match args.get_one::<String>("to").unwrap().as_str() {
+        "raw" => raw.to_vec(),
+        _ => lms_sig::to_portable(&raw)?.into_bytes(),
+    };
+
+    std::fs::write(output, converted)
+        .with_context(|| format!("Failed to write LMS signature {}", output.display()))?;
+    println!("LMS signature written to {}", output.display());
+
+    Ok(())
+}
+
+pub(crate) fn run_config_check_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let path = config::AspeedManifestCreationPath::new_check(args)
+        .with_context(|| "Failed to create config check path")?;
+    debug!("Config check path:\n{:#?}", path);
+
+    /* Parsing the config also checks every image file exists */
+    let mut cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
+    let owner_key = cfg.select_owner_man_key(owner_key_index(args));
+
+    let checks = [
+        ("image files", Ok(())),
+        ("owner key slot", owner_key),
+        ("image count", cfg.validate_image_count(max_images(args))),
+        ("owner key fields", cfg.validate_owner_key_fields()),
+        ("fw_id uniqueness", cfg.validate_fw_ids()),
+        ("MCU runtime fw_id", cfg.validate_mcu_runtime_image()),
+        (
+            "key files",
+            cfg.validate_key_dir_if_needed(path.key_dir.as_deref())
+                .map(|_| ()),
+        ),
+    ];
+
+    println!("-----------------------------------------------------------------------------------------------------------");
+    println!("config : {}", path.aspeed_cfg.display());
+    println!("prebuilt_dir : {}", path.prebuilt_dir.display());
+    println!("images : {}", cfg.image_metadata_list.len());
+    for (name, result) in checks.iter() {
+        match result {
+            Ok(()) => println!("{} : ok", name),
+            Err(e) => println!("{} : FAILED, {:#}", name, e),
+        }
+    }
+    println!("-----------------------------------------------------------------------------------------------------------");
+
+    let failed = checks.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!("Config check found {} problem(s)", failed));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run_self_test_cmd() -> anyhow::Result<()> {
+    let results = selftest::run()?;
+
+    println!("-----------------------------------------------------------------------------------------------------------");
+    println!("version : {}", env!("CARGO_PKG_VERSION"));
+    for (stage, failures) in results.iter() {
+        if failures.is_empty() {
+            println!("{} : ok", stage);
+        } else {
+            println!("{} : FAILED, {}", stage, failures.join(", "));
+        }
+    }
+    println!("-----------------------------------------------------------------------------------------------------------");
+
+    let failed = results.iter().filter(|(_, f)| !f.is_empty()).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!("Self-test found {} problem(s)", failed));
+    }
+
+    Ok(())
+}
+
+/* Key file to check: label, path and the expected kind if known */
+type KeyFileToValidate = (String, PathBuf, Option<keys::KeyKind>);
+
+/* Key files from the config, or from the key dir file names without --cfg */
+fn key_files_to_validate(args: &ArgMatches) -> anyhow::Result<(PathBuf, Vec<KeyFileToValidate>)> {
+    if args.get_one::<String>("cfg").is_none() {
+        let key_dir = args.get_one::<PathBuf>("key-dir").unwrap().clone();
+        let mut files = std::fs::read_dir(&key_dir)
+            .with_context(|| format!("Failed to read key directory {}", key_dir.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|file| file.is_file())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let files = files
+            .into_iter()
+            .map(|file| {
+                let name = file.file_name().unwrap().to_string_lossy().to_string();
+                let kind = keys::KeyKind::from_file_name(&name);
+                (name, file, kind)
+            })
+            .collect();
+        return Ok((key_dir, files));
+    }
+
+    let path = config::AspeedManifestCreationPath::new_keys(args)
+        .with_context(|| "Failed to create validate keys path")?;
+    debug!("Validate keys path:\n{:#?}", path);
+    let cfg = config::AspeedAuthManifestConfigFromFile::parse(&path.aspeed_cfg)?;
+    let key_dir = path.key_dir.unwrap();
+
+    let mut key_configs = cfg
+        .key_configs()
+        .into_iter()
+        .map(|(section, key_cfg)| (section.to_string(), key_cfg))
+        .collect::<Vec<_>>();
+    for (i, key_cfg) in cfg.owner_man_key_configs.iter().flatten().enumerate() {
+        key_configs.push((format!("owner_man_key_configs[{}]", i), key_cfg));
+    }
+
+    let files = key_configs
+        .into_iter()
+        .flat_map(|(section, key_cfg)| {
+            key_cfg
+                .key_files()
+                .into_iter()
+                .map(|(field, file)| {
+                    (
+                        format!("{}.{}", section, field),
+                        key_dir.join(file),
+                        keys::KeyKind::from_field(field),
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    Ok((key_dir, files))
+}
+
+pub(crate) fn run_validate_keys_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let (key_dir, files) = key_files_to_validate(args)?;
+
+    println!("-----------------------------------------------------------------------------------------------------------");
+    println!("key_dir : {}", key_dir.display());
+    let mut failed = 0;
+    for (label, file, kind) in files.iter() {
+        let Some(kind) = kind else {
+            println!("{} : skipped, not a key file name", label);
+            continue;
+        };
+        match keys::check_key_file(file, *kind) {
+            Ok(desc) => println!("{} : OK, {}", label, desc),
+            Err(e) => {
+                println!("{} : ERROR, {:#}", label, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("-----------------------------------------------------------------------------------------------------------");
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!("Found {} invalid key file(s)", failed));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run_list_images_cmd(args: &ArgMatches) -> anyhow::Result<()> {
+    let path = config::AspeedManifestCreationPath::new_check(args)
+        .with_context(|| "Failed to create list images path")?;
+    debug!("List images path:\n{:#?}", path);
+
+    let cfg = config::AspeedAuthManifestConfigFromFile::new(&path)?;
+    /* Same classification as create-auth-flash, the MCU runtime entry is
+     * listed in the SoC manifest but not passed with --soc-images.
+     */
+    let mcu_runtime_fw_id = cfg.mcu_runtime_fw_id();
+
+    println!("-----------------------------------------------------------------------------------------------------------");
+    println!("config : {}", path.aspeed_cfg.display());
+    println!("prebuilt_dir : {}", path.prebuilt_dir.display());
+    println!("caliptra_file : {}", cfg.image_runtime_list.caliptra_file);
+    println!("mcu_file : {}", cfg.image_runtime_list.mcu_file);
+    println!("-----------------------------------------------------------------------------------------------------------");
+    /* The name column is as wide as the longest name */
+    let name_width = cfg
+        .image_metadata_list
+        .iter()
+        .filter_map(|img| img.name.as_ref().map(|name| name.len()))
+        .max()
+        .unwrap_or(0)
+        .max("name".len());
+    println!(
+        "{:<8} {:<name_width$} {:<8} {:<12} {:<18} {:<12} file",
+        "fw_id", "name", "source", "load_stage", "ignore_auth_check", "mcu_runtime"
+    );
+    for img in cfg.image_metadata_list.iter() {
+        println!(
+            "{:<8} {:<name_width$} {:<8} {:<12} {:<18} {:<12} {}",
+            format!("0x{:x}", img.fw_id),
+            img.name.as_deref().unwrap_or("-"),
+            img.source,
+            img.load_stage,
+            img.ignore_auth_check,
+            if img.fw_id == mcu_runtime_fw_id {
+                "yes"
+            } else {
+                "no"
+            },
+            if img.digest_only() {
+                "<digest only>"
+            } else {
+                &img.file
+            }
+        );
+    }
+    println!("-----------------------------------------------------------------------------------------------------------");
+
+    if let Err(e) = cfg.validate_mcu_runtime_image() {
+        utility::print_warning(&format!("create-auth-flash would fail: {}", e));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(fw_id: u32, file: &str) -> config::AspeedImageMetadataConfigFromFile {
+        config::AspeedImageMetadataConfigFromFile {
+            file: file.to_string(),
+            fw_id,
+            ..Default::default()
+        }
+    }
+
+    /* Flash tool argv for a config with `images` */
+    fn flash_tool_argv(images: Vec<config::AspeedImageMetadataConfigFromFile>) -> Vec<String> {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = config::AspeedManifestCreationPath {
+            prebuilt_dir: tmp.path().to_path_buf(),
+            tool_dir: tmp.path().to_path_buf(),
+            key_dir: None,
+            aspeed_cfg: tmp.path().join("manifest.toml"),
+            caliptra_cfg: None,
+            manifest: Some(PathBuf::from("man.bin")),
+            flash_image: Some(PathBuf::from("flash.bin")),
+            svn_sig: None,
+            tool_names: Default::default(),
+        };
+        let mut cfg = config::AspeedAuthManifestConfigFromFile {
+            image_metadata_list: images,
+            ..Default::default()
+        };
+        cfg.image_runtime_list.caliptra_file = "caliptra.bin".to_string();
+        cfg.image_runtime_list.mcu_file = "mcu.bin".to_string();
+        let args = Command::new("test")
+            .arg(arg!(--"strict-digest" "check digests"))
+            .get_matches_from(["test"]);
+
+        let runner = tool::RecordingRunner::default();
+        let staging = tmp.path().join("flash.bin.tmp");
+        build_flash_image(
+            &args,
+            &runner,
+            &path,
+            &cfg,
+            Path::new("xtask"),
+            &staging,
+            soc_images_args(&cfg),
+        )
+        .unwrap();
+
+        let mut calls = runner.calls.into_inner();
+        assert_eq!(calls.len(), 1);
+        let mut argv = calls.remove(0);
+        assert_eq!(argv[10], staging.display().to_string());
+        argv[10] = "staging".to_string();
+        argv
+    }
+
+    #[test]
+    fn path_errors_are_plain_text() {
+        let err = error::ImgtoolError::MissingPath(PathBuf::from("prebuilt/fw.bin"));
+        assert!(err.is_path_error());
+        assert_eq!(
+            err.to_string(),
+            "Path or file not found: \"prebuilt/fw.bin\""
+        );
+
+        /* Test output is captured, so no color is added */
+        let err = highlight_path_error(err.into());
+        assert!(!format!("{:#}", err).contains('\x1b'));
+    }
+
+    #[test]
+    fn flash_tool_gets_the_soc_images_besides_the_mcu_runtime() {
+        let argv = flash_tool_argv(vec![image(2, "soc.bin"), image(1, "mcu.bin")]);
+        assert_eq!(
+            argv,
+            [
+                "xtask",
+                "flash-image",
+                "create",
+                "--caliptra-fw",
+                "caliptra.bin",
+                "--soc-manifest",
+                "man.bin",
+                "--mcu-runtime",
+                "mcu.bin",
+                "--output",
+                "staging",
+                "--soc-images",
+                "soc.bin",
+            ]
+        );
+    }
+
+    #[test]
+    fn flash_tool_without_soc_images_gets_no_soc_images_flag() {
+        let argv = flash_tool_argv(vec![image(1, "mcu.bin")]);
+        assert_eq!(argv.len(), 11, "{:?}", argv);
+        assert!(!argv.iter().any(|a| a == "--soc-images"), "{:?}", argv);
+    }
+
+    #[test]
+    fn magic_is_a_number_or_four_characters() {
+        assert_eq!(parse_magic("0x4154_4D4E"), Ok(layout::AUTH_MANIFEST_MAGIC));
+        assert_eq!(parse_magic("1096043854"), Ok(layout::AUTH_MANIFEST_MAGIC));
+        assert_eq!(parse_magic("ATMN"), Ok(layout::AUTH_MANIFEST_MAGIC));
+        assert!(parse_magic("ATM").is_err());
+        assert!(parse_magic("0xATMN").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   manifest.rs
+
+Abstract:
+
+    File contains the serializable view of an aspeed authorization manifest
+
+--*/
+
+use crate::error::{IoContext, Result};
+use crate::layout::{self, AUTH_MANIFEST_MAGIC};
+use serde_derive::Serialize;
+use std::path::Path;
+
+/// Image metadata entry of a `ParsedManifest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParsedImageMetadata {
+    pub fw_id: u32,
+
+    pub flags: u32,
+
+    /// SHA384 digest as lowercase hex.
+    pub digest: String,
+}
+
+/// Decoded aspeed authorization manifest. Integers are decoded from their
+/// little-endian form, keys and signatures are lowercase hex strings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParsedManifest {
+    pub magic: u32,
+
+    pub size: u32,
+
+    pub ver: u32,
+
+    pub sec_ver: u32,
+
+    pub flags: u32,
+
+    pub vnd_manifest_ecc_pubk: String,
+
+    pub vnd_manifest_lms_pubk: String,
+
+    pub vnd_manifest_ecc_sig: String,
+
+    pub vnd_manifest_lms_sig: String,
+
+    pub owner_manifest_ecc_pubk: String,
+
+    pub owner_manifest_lms_pubk: String,
+
+    pub owner_manifest_ecc_sig: String,
+
+    pub owner_manifest_lms_sig: String,
+
+    pub owner_manifest_svn_ecc_sig: String,
+
+    pub owner_manifest_svn_lms_sig: String,
+
+    pub vnd_metadata_ecc_sig: String,
+
+    pub vnd_metadata_lms_sig: String,
+
+    pub owner_metadata_ecc_sig: String,
+
+    pub owner_metadata_lms_sig: String,
+
+    /// Entries in use, in manifest order.
+    pub images: Vec<ParsedImageMetadata>,
+}
+
+impl ParsedManifest {
+    /// Read and parse a manifest file written by `create-auth-man`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_path_with_magic(path, AUTH_MANIFEST_MAGIC)
+    }
+
+    /// Like `from_path`, for a forked format starting with a different `magic`.
+    pub fn from_path_with_magic<P: AsRef<Path>>(path: P, magic: u32) -> Result<Self> {
+        let path = path.as_ref();
+        let img = std::fs::read(path)
+            .io_context(|| format!("Failed to read SoC manifest file {:?}", path))?;
+
+        Self::parse(&format!("{:?}", path), &img, magic)
+    }
+
+    /// Parse a manifest image. Bytes after the metadata collection are ignored.
+    pub fn from_bytes(img: &[u8]) -> Result<Self> {
+        Self::from_bytes_with_magic(img, AUTH_MANIFEST_MAGIC)
+    }
+
+    /// Like `from_bytes`, for a forked format starting with a different `magic`.
+    pub fn from_bytes_with_magic(img: &[u8], magic: u32) -> Result<Self> {
+        Self::parse("image", img, magic)
+    }
+
+    fn parse(name: &str, img: &[u8], magic: u32) -> Result<Self> {
+        let (p, metadata_col) = layout::parse_manifest(name, img, magic)?;
+
+        Ok(Self {
+            magic: p.magic.get(),
+            size: p.size.get(),
+            ver: p.ver.get(),
+            sec_ver: p.sec_ver.get(),
+            flags: p.flags.get(),
+            vnd_manifest_ecc_pubk: hex::encode(p.vnd_manifest_ecc_pubk),
+            vnd_manifest_lms_pubk: hex::encode(p.vnd_manifest_lms_pubk),
+            vnd_manifest_ecc_sig: hex::encode(p.vnd_manifest_ecc_sig),
+            vnd_manifest_lms_sig: hex::encode(p.vnd_manifest_lms_sig),
+            owner_manifest_ecc_pubk: hex::encode(p.owner_manifest_ecc_pubk),
+            owner_manifest_lms_pubk: hex::encode(p.owner_manifest_lms_pubk),
+            owner_manifest_ecc_sig: hex::encode(p.owner_manifest_ecc_sig),
+            owner_manifest_lms_sig: hex::encode(p.owner_manifest_lms_sig),
+            owner_manifest_svn_ecc_sig: hex::encode(p.owner_manifest_svn_ecc_sig),
+            owner_manifest_svn_lms_sig: hex::encode(p.owner_manifest_svn_lms_sig),
+            vnd_metadata_ecc_sig: hex::encode(p.vnd_matadata_ecc_sig),
+            vnd_metadata_lms_sig: hex::encode(p.vnd_matadata_lms_sig),
+            owner_metadata_ecc_sig: hex::encode(p.owner_matadata_ecc_sig),
+            owner_metadata_lms_sig: hex::encode(p.owner_matadata_lms_sig),
+            images: metadata_col
+                .entries()
+                .iter()
+                .map(|m| ParsedImageMetadata {
+                    fw_id: m.id(),
+                    flags: m.flags(),
+                    digest: m.digest_hex(),
+                })
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,67 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   progress.rs
+
+Abstract:
+
+    File contains the optional progress display for long image builds
+
+--*/
+
+use clap::ArgMatches;
+use std::io::{IsTerminal, Write};
+
+/* Hash images in chunks of this size so large images report partial progress */
+pub(crate) const PROGRESS_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Progress lines on stderr. Only enabled by `--progress` when stderr is a
+/// terminal and the output isn't machine readable, otherwise every call is a no-op.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct Progress {
+    enabled: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(args: &ArgMatches) -> Self {
+        let requested = args
+            .try_get_one::<bool>("progress")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false);
+        let json = args
+            .try_get_one::<String>("format")
+            .ok()
+            .flatten()
+            .is_some_and(|f| f == "json");
+
+        Self {
+            enabled: requested && !json && std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Replace the current progress line.
+    pub(crate) fn update(&self, msg: &str) {
+        if self.enabled {
+            let mut err = std::io::stderr().lock();
+            let _ = write!(err, "\r\x1b[2K{}", msg);
+            let _ = err.flush();
+        }
+    }
+
+    /// Keep `msg` on screen, e.g. before a tool that prints its own output.
+    pub(crate) fn step(&self, msg: &str) {
+        if self.enabled {
+            eprintln!("\r\x1b[2K{}", msg);
+        }
+    }
+
+    /// Clear the current progress line.
+    pub(crate) fn finish(&self) {
+        self.update("");
+    }
+}
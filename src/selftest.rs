@@ -0,0 +1,228 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   selftest.rs
+
+Abstract:
+
+    File contains the self-test round-tripping a synthetic manifest
+
+--*/
+
+use crate::config::tmp_file_path;
+use crate::error::{IoContext, Result};
+use crate::layout::{
+    self, AspeedAuthManifestImageMetadata, AspeedAuthManifestImageMetadataCollection,
+    AspeedAuthManifestPreamble, ASPEED_AUTH_MANIFEST_SIZE, AUTH_MANIFEST_MAGIC,
+    IMAGE_METADATA_MAX_COUNT, SHA384_DIGEST_SIZE,
+};
+use crate::soc_man::{AspeedAuthorizationManifest, ManifestLayout};
+use std::mem::size_of;
+use zerocopy::little_endian::U32;
+use zerocopy::{FromBytes, FromZeros, IntoBytes};
+
+/* Every byte of a multi-byte integer differs, so a swapped byte order shows */
+const SYNTHETIC_VER: u32 = 0x0102_0304;
+const SYNTHETIC_SEC_VER: u32 = 0x1122_3344;
+const SYNTHETIC_FLAGS: u32 = 0xa1b2_c3d4;
+
+/* fw_id and flags of the synthetic images, the digest is derived from the fw_id */
+const SYNTHETIC_IMAGES: [(u32, u32); 3] = [(0x0000_0001, 0), (0x0000_0100, 1), (0x1234_5678, 3)];
+
+/// `len` bytes that don't repeat within 251, so a shifted field shows. The unit
+/// tests share it for their synthetic data.
+pub(crate) fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+pub(crate) fn synthetic_preamble() -> AspeedAuthManifestPreamble {
+    /* Key and signature fields get distinct bytes, so a shifted field shows */
+    let bytes = pattern(size_of::<AspeedAuthManifestPreamble>(), 0x5a);
+    let mut preamble =
+        AspeedAuthManifestPreamble::read_from_bytes(&bytes).expect("pattern has the preamble size");
+    preamble.magic = U32::new(AUTH_MANIFEST_MAGIC);
+    preamble.size = U32::new(ManifestLayout::Aspeed.metadata_offset() as u32);
+    preamble.ver = U32::new(SYNTHETIC_VER);
+    preamble.sec_ver = U32::new(SYNTHETIC_SEC_VER);
+    preamble.flags = U32::new(SYNTHETIC_FLAGS);
+    preamble
+}
+
+fn synthetic_digest(fw_id: u32) -> Vec<u8> {
+    pattern(
+        SHA384_DIGEST_SIZE,
+        fw_id.to_le_bytes().iter().fold(0, |a, b| a ^ b),
+    )
+}
+
+pub(crate) fn synthetic_metadata() -> AspeedAuthManifestImageMetadataCollection {
+    let mut col = AspeedAuthManifestImageMetadataCollection::new_zeroed();
+    col.count = U32::new(SYNTHETIC_IMAGES.len() as u32);
+    for (entry, (fw_id, flags)) in col.metadata_list.iter_mut().zip(SYNTHETIC_IMAGES) {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&fw_id.to_le_bytes());
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes.extend(synthetic_digest(fw_id));
+        *entry = AspeedAuthManifestImageMetadata::read_from_bytes(&bytes)
+            .expect("entry has the metadata size");
+    }
+    col
+}
+
+fn check(failures: &mut Vec<String>, name: &str, ok: bool) {
+    if !ok {
+        failures.push(name.to_string());
+    }
+}
+
+/* Little-endian u32 of the written image, read without zerocopy */
+fn read_u32(img: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(img[offset..offset + 4].try_into().unwrap())
+}
+
+/* The bytes close() wrote, against the layout computed by hand */
+fn check_image(img: &[u8]) -> Vec<String> {
+    let mut failures = Vec::new();
+    let col = layout::metadata_offset();
+
+    check(
+        &mut failures,
+        "image size",
+        img.len() == ASPEED_AUTH_MANIFEST_SIZE,
+    );
+    if img.len() != ASPEED_AUTH_MANIFEST_SIZE {
+        return failures;
+    }
+    check(
+        &mut failures,
+        "magic bytes",
+        img[..4] == AUTH_MANIFEST_MAGIC.to_le_bytes(),
+    );
+    check(&mut failures, "size field", read_u32(img, 4) == col as u32);
+    check(
+        &mut failures,
+        "ver field",
+        read_u32(img, 8) == SYNTHETIC_VER,
+    );
+    check(
+        &mut failures,
+        "sec_ver field",
+        read_u32(img, 12) == SYNTHETIC_SEC_VER,
+    );
+    check(
+        &mut failures,
+        "flags field",
+        read_u32(img, 16) == SYNTHETIC_FLAGS,
+    );
+    check(
+        &mut failures,
+        "preamble bytes",
+        img[..col] == *synthetic_preamble().as_bytes(),
+    );
+    check(
+        &mut failures,
+        "metadata count",
+        read_u32(img, col) == SYNTHETIC_IMAGES.len() as u32,
+    );
+
+    let entry_size = size_of::<AspeedAuthManifestImageMetadata>();
+    for (i, (fw_id, flags)) in SYNTHETIC_IMAGES.into_iter().enumerate() {
+        let entry = col + 4 + i * entry_size;
+        check(
+            &mut failures,
+            &format!("metadata {} bytes", i),
+            read_u32(img, entry) == fw_id
+                && read_u32(img, entry + 4) == flags
+                && img[entry + 8..entry + entry_size] == synthetic_digest(fw_id)[..],
+        );
+    }
+    let unused = col + 4 + SYNTHETIC_IMAGES.len() * entry_size;
+    check(
+        &mut failures,
+        "unused metadata entries",
+        img[unused..col + 4 + IMAGE_METADATA_MAX_COUNT * entry_size]
+            .iter()
+            .all(|b| *b == 0),
+    );
+
+    failures
+}
+
+/* The manifest read back with open(), field by field */
+fn check_parsed(man: &AspeedAuthorizationManifest) -> Vec<String> {
+    let mut failures = Vec::new();
+    let (p, expected) = (man.preamble(), synthetic_preamble());
+
+    check(&mut failures, "magic", p.magic.get() == AUTH_MANIFEST_MAGIC);
+    check(&mut failures, "size", p.size.get() == expected.size.get());
+    check(&mut failures, "ver", p.ver.get() == SYNTHETIC_VER);
+    check(
+        &mut failures,
+        "sec_ver",
+        p.sec_ver.get() == SYNTHETIC_SEC_VER,
+    );
+    check(&mut failures, "flags", p.flags.get() == SYNTHETIC_FLAGS);
+    check(
+        &mut failures,
+        "keys and signatures",
+        p.as_bytes() == expected.as_bytes(),
+    );
+
+    let images = man.metadata().collect::<Vec<_>>();
+    check(
+        &mut failures,
+        "image count",
+        images.len() == SYNTHETIC_IMAGES.len(),
+    );
+    for (m, (fw_id, flags)) in images.iter().zip(SYNTHETIC_IMAGES) {
+        check(
+            &mut failures,
+            &format!("fw_id 0x{:08x}", fw_id),
+            m.id() == fw_id && m.flags() == flags && m.digest()[..] == synthetic_digest(fw_id)[..],
+        );
+    }
+
+    failures
+}
+
+/// Write a synthetic manifest with `close()`, read it back with `open()` and
+/// compare every field. Returns the failed checks of each stage.
+pub(crate) fn run() -> Result<Vec<(&'static str, Vec<String>)>> {
+    let path = tmp_file_path("self-test-manifest.bin");
+    let written =
+        AspeedAuthorizationManifest::from_parts(&path, synthetic_preamble(), synthetic_metadata());
+    written.close(ManifestLayout::Aspeed)?;
+
+    let read = AspeedAuthorizationManifest::open(&path, AUTH_MANIFEST_MAGIC)?;
+    let img = std::fs::read(&path).io_context(|| format!("Failed to read {}", path.display()))?;
+
+    Ok(vec![
+        ("serialized layout", check_image(&img)),
+        ("parsed fields", check_parsed(&read)),
+        ("round trip", written.diff(&read)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthetic_manifest_round_trips() {
+        for (stage, failures) in run().unwrap() {
+            assert!(failures.is_empty(), "{}: {:?}", stage, failures);
+        }
+    }
+
+    #[test]
+    fn swapped_bytes_are_reported() {
+        let mut img = synthetic_preamble().as_bytes().to_vec();
+        img.extend_from_slice(synthetic_metadata().as_bytes());
+        img[8..12].reverse();
+        let failures = check_image(&img);
+        assert_eq!(failures, ["ver field", "preamble bytes"]);
+    }
+}
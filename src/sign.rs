@@ -0,0 +1,147 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   sign.rs
+
+Abstract:
+
+    File contains the in-process ECC signing of the sign-digest subcommand
+
+--*/
+
+use crate::error::{ImgtoolError, IoContext, Result};
+use crate::layout::SHA384_DIGEST_SIZE;
+use p384::ecdsa::signature::hazmat::PrehashSigner;
+use p384::ecdsa::{Signature, SigningKey};
+use p384::pkcs8::DecodePrivateKey;
+use std::io::{BufRead, Read};
+use std::path::Path;
+
+/// Read one SHA384 digest from `reader`, raw bytes or a line of hex.
+pub(crate) fn read_digest(reader: &mut impl BufRead, hex_input: bool) -> Result<Vec<u8>> {
+    let digest = if hex_input {
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .io_context(|| "Failed to read the digest")?;
+        hex::decode(line.trim())
+            .map_err(|e| ImgtoolError::SignatureFormat(format!("Invalid hex digest: {}", e)))?
+    } else {
+        let mut digest = Vec::new();
+        reader
+            .take(SHA384_DIGEST_SIZE as u64 + 1)
+            .read_to_end(&mut digest)
+            .io_context(|| "Failed to read the digest")?;
+        digest
+    };
+
+    if digest.len() != SHA384_DIGEST_SIZE {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "Expected a {}-byte SHA384 digest, got {} bytes",
+            SHA384_DIGEST_SIZE,
+            digest.len()
+        )));
+    }
+    Ok(digest)
+}
+
+/// Sign a SHA384 digest with an ECC-P384 private key file (SEC1 or PKCS#8 PEM),
+/// returning the DER signature the sign helper writes. p384 derives the nonce
+/// with RFC 6979, so signing is deterministic.
+pub(crate) fn ecc_sign_digest(key: &Path, digest: &[u8]) -> Result<Vec<u8>> {
+    let pem = std::fs::read_to_string(key)
+        .io_context(|| format!("Failed to read key file {}", key.display()))?;
+    let pem = crate::keys::key_pem(&pem);
+    let signing_key = p384::SecretKey::from_sec1_pem(&pem)
+        .map(SigningKey::from)
+        .or_else(|_| SigningKey::from_pkcs8_pem(&pem))
+        .map_err(|e| {
+            ImgtoolError::SignatureFormat(format!(
+                "{} is not an ECC-P384 private key PEM: {}",
+                key.display(),
+                e
+            ))
+        })?;
+
+    let sig: Signature = signing_key
+        .sign_prehash(digest)
+        .map_err(|e| ImgtoolError::SignatureFormat(format!("Failed to sign digest: {}", e)))?;
+    Ok(sig.to_der().as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::ecdsa::signature::hazmat::PrehashVerifier;
+    use p384::ecdsa::VerifyingKey;
+    use p384::pkcs8::{DecodePublicKey, EncodePrivateKey, LineEnding};
+
+    const DIGEST: [u8; SHA384_DIGEST_SIZE] = [0x5a; SHA384_DIGEST_SIZE];
+
+    fn verify(pub_pem: &str, der: &[u8]) -> bool {
+        VerifyingKey::from_public_key_pem(pub_pem)
+            .unwrap()
+            .verify_prehash(&DIGEST, &Signature::from_der(der).unwrap())
+            .is_ok()
+    }
+
+    #[test]
+    fn shipped_key_signs_verifiably() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("key/ast2700-default");
+        let der = ecc_sign_digest(&dir.join("own-man-ecc-prvk.pem"), &DIGEST).unwrap();
+        let pub_pem = std::fs::read_to_string(dir.join("own-man-ecc-pubk.pem")).unwrap();
+        assert!(verify(&pub_pem, &der));
+
+        /* Deterministic, the same digest always gives the same bytes */
+        assert_eq!(
+            ecc_sign_digest(&dir.join("own-man-ecc-prvk.pem"), &DIGEST).unwrap(),
+            der
+        );
+    }
+
+    #[test]
+    fn sec1_and_pkcs8_keys_give_the_same_signature() {
+        use p384::pkcs8::EncodePublicKey;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let secret = p384::SecretKey::from_slice(&[0x33; 48]).unwrap();
+        let sec1 = tmp.path().join("sec1.pem");
+        let pkcs8 = tmp.path().join("pkcs8.pem");
+        std::fs::write(
+            &sec1,
+            secret.to_sec1_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+        std::fs::write(
+            &pkcs8,
+            secret.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        let der = ecc_sign_digest(&sec1, &DIGEST).unwrap();
+        assert_eq!(ecc_sign_digest(&pkcs8, &DIGEST).unwrap(), der);
+        let pub_pem = secret
+            .public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        assert!(verify(&pub_pem, &der));
+
+        std::fs::write(&sec1, "not a key").unwrap();
+        assert!(ecc_sign_digest(&sec1, &DIGEST).is_err());
+    }
+
+    #[test]
+    fn digest_is_read_as_raw_or_hex() {
+        let raw = read_digest(&mut &DIGEST[..], false).unwrap();
+        assert_eq!(raw, DIGEST);
+        let line = format!("{}\n", hex::encode(DIGEST));
+        assert_eq!(read_digest(&mut line.as_bytes(), true).unwrap(), DIGEST);
+
+        assert!(read_digest(&mut &DIGEST[1..], false).is_err());
+        assert!(read_digest(&mut &[0u8; SHA384_DIGEST_SIZE + 1][..], false).is_err());
+        assert!(read_digest(&mut "zz\n".as_bytes(), true).is_err());
+    }
+}
@@ -13,27 +13,40 @@ Abstract:
 --*/
 
 use crate::config;
-use crate::utility::PathBufExt;
-use anyhow::{anyhow, Result};
+use crate::error::{ImgtoolError, IoContext, Result};
+use crate::layout::{
+    self, from_img, AspeedAuthManifestImageMetadata, AspeedAuthManifestImageMetadataCollection,
+    AspeedAuthManifestPreamble, ASPEED_AUTH_MANIFEST_SIZE, ECC384_PUBK_SIZE, ECC384_SIG_SIZE,
+    LMS_PUBK_SIZE, LMS_SIG_SIZE,
+};
+use crate::tool;
+use crate::utility::{self, PathBufExt};
 use log::{debug, info};
 use p384::ecdsa::Signature;
+use p384::elliptic_curve::sec1::ToEncodedPoint;
+use p384::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use sha2::{Digest, Sha384};
+use std::collections::{BTreeMap, BTreeSet};
 use std::mem::size_of;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use zerocopy::little_endian::{U16, U32};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
-const IMAGE_METADATA_MAX_COUNT: usize = 127;
-const ECC384_SIG_SIZE: usize = 96;
-const ECC384_PUBK_SIZE: usize = 96;
-const SHA384_DIGEST_SIZE: usize = 48;
-const LMS_SIG_SIZE: usize = 1620;
-const LMS_PUBK_SIZE: usize = 48;
+/* Flash image layout written by `xtask flash-image create` */
+const FLASH_IMAGE_MAGIC: u32 = u32::from_be_bytes(*b"FLSH");
+const FLASH_ERASED_BYTE: u8 = 0xff;
+/* The caliptra firmware and the SoC manifest come before the manifest images */
+const FLASH_FIRST_IMAGE_REGION: usize = 2;
+const ECC384_SIG_DER_MAX_SIZE: usize = 104;
 
-#[derive(Clone, Copy)]
+/* Preamble written by the caliptra manifest tool, the aspeed one is in layout.rs */
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout)]
 #[repr(C)]
 struct AuthManifestPreamble {
-    magic: u32,
-    size: u32,
-    ver: u32,
-    flags: u32,
+    magic: U32,
+    size: U32,
+    ver: U32,
+    flags: U32,
     vnd_manifest_ecc_pubk: [u8; ECC384_PUBK_SIZE],
     vnd_manifest_lms_pubk: [u8; LMS_PUBK_SIZE],
     vnd_manifest_ecc_sig: [u8; ECC384_SIG_SIZE],
@@ -48,85 +61,466 @@ struct AuthManifestPreamble {
     owner_matadata_lms_sig: [u8; LMS_SIG_SIZE],
 }
 
-#[derive(Clone, Copy)]
+/* The aspeed preamble only adds sec_ver and the SVN signatures */
+const _: () = assert!(
+    layout::metadata_offset()
+        == size_of::<AuthManifestPreamble>() + size_of::<U32>() + ECC384_SIG_SIZE + LMS_SIG_SIZE,
+    "aspeed preamble is not the caliptra preamble plus sec_ver and the SVN signatures"
+);
+
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout)]
 #[repr(C)]
-struct AspeedAuthManifestPreamble {
-    magic: u32,
-    size: u32,
-    ver: u32,
-    sec_ver: u32,
-    flags: u32,
-    vnd_manifest_ecc_pubk: [u8; ECC384_PUBK_SIZE],
-    vnd_manifest_lms_pubk: [u8; LMS_PUBK_SIZE],
-    vnd_manifest_ecc_sig: [u8; ECC384_SIG_SIZE],
-    vnd_manifest_lms_sig: [u8; LMS_SIG_SIZE],
-    owner_manifest_ecc_pubk: [u8; ECC384_PUBK_SIZE],
-    owner_manifest_lms_pubk: [u8; LMS_PUBK_SIZE],
-    owner_manifest_ecc_sig: [u8; ECC384_SIG_SIZE],
-    owner_manifest_lms_sig: [u8; LMS_SIG_SIZE],
-    owner_manifest_svn_ecc_sig: [u8; ECC384_SIG_SIZE],
-    owner_manifest_svn_lms_sig: [u8; LMS_SIG_SIZE],
-    vnd_matadata_ecc_sig: [u8; ECC384_SIG_SIZE],
-    vnd_matadata_lms_sig: [u8; LMS_SIG_SIZE],
-    owner_matadata_ecc_sig: [u8; ECC384_SIG_SIZE],
-    owner_matadata_lms_sig: [u8; LMS_SIG_SIZE],
+struct FlashImageHeader {
+    magic: U32,
+    version: U16,
+    image_count: U16,
+    image_headers_offset: U32,
+    header_crc32: U32,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, KnownLayout)]
 #[repr(C)]
-struct AspeedAuthManifestImageMetadata {
-    id: u32,
-    flags: u32,
-    digest: [u8; SHA384_DIGEST_SIZE],
+struct FlashImageInfo {
+    identifier: U32,
+    offset: U32,
+    size: U32,
+    image_header_crc32: U32,
 }
 
-#[derive(Clone, Copy)]
-#[repr(C)]
-struct AspeedAuthManifestImageMetadataCollection {
-    pub(crate) count: u32,
-    pub(crate) metadata_list: [AspeedAuthManifestImageMetadata; IMAGE_METADATA_MAX_COUNT],
+/// On-disk layout of the manifest written by `close()`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ManifestLayout {
+    /* Plain caliptra preamble, for loaders that predate the aspeed extensions */
+    Caliptra,
+
+    /* Caliptra preamble extended with sec_ver and the SVN signatures */
+    #[default]
+    Aspeed,
+}
+
+impl ManifestLayout {
+    /// Offset of the metadata collection, which is also the preamble size.
+    pub(crate) const fn metadata_offset(self) -> usize {
+        match self {
+            ManifestLayout::Caliptra => size_of::<AuthManifestPreamble>(),
+            ManifestLayout::Aspeed => layout::metadata_offset(),
+        }
+    }
 }
 
 pub(crate) struct AspeedAuthorizationManifest {
     path: PathBuf,
     preamble: AspeedAuthManifestPreamble,
     metadata_col: AspeedAuthManifestImageMetadataCollection,
+    /* Original manifest image, kept to preserve bytes beyond the metadata collection */
+    raw: Vec<u8>,
+    /* Offset in raw where the trailing bytes start */
+    trailer_offset: usize,
 }
 
 const VND_ECC_SIG_BIN: &[u8] = include_bytes!("vnd_sig/vnd_ecc_sig.der");
 const VND_LMS_SIG_BIN: &[u8] = include_bytes!("vnd_sig/vnd_lms_sig.der");
-const _: () = assert!(VND_ECC_SIG_BIN.len() == 103, "VND_ECC_SIG_BIN size error!");
-const _: () = assert!(VND_LMS_SIG_BIN.len() == 1620, "VND_LMS_SIG_BIN size error!");
+/* The prebuilt ECC signature is either DER encoded or a raw r || s pair */
+const _: () = assert!(
+    VND_ECC_SIG_BIN.len() >= ECC384_SIG_SIZE && VND_ECC_SIG_BIN.len() <= ECC384_SIG_DER_MAX_SIZE,
+    "VND_ECC_SIG_BIN size error!"
+);
+
+/// The prebuilt vendor signatures spliced into every manifest, by name.
+pub(crate) fn prebuilt_vendor_signatures() -> [(&'static str, &'static [u8]); 2] {
+    [
+        ("vnd_ecc_sig", VND_ECC_SIG_BIN),
+        ("vnd_lms_sig", VND_LMS_SIG_BIN),
+    ]
+}
+
+fn to_img<T: IntoBytes + Immutable>(val: &T) -> Vec<u8> {
+    val.as_bytes().to_vec()
+}
+
+/// Parse an ECC-P384 signature that is either DER encoded or a raw 96-byte r || s
+/// pair, as emitted by some HSMs.
+fn parse_ecc_sig(sig: &[u8]) -> Result<Signature> {
+    if let Ok(sig) = Signature::from_der(sig) {
+        return Ok(sig);
+    }
+
+    if sig.len() == ECC384_SIG_SIZE {
+        return Signature::from_slice(sig)
+            .map_err(|_| ImgtoolError::SignatureFormat("Invalid raw ECC signature".to_string()));
+    }
+
+    Err(ImgtoolError::SignatureFormat(format!(
+        "ECC signature is neither DER nor raw r || s ({} bytes)",
+        sig.len()
+    )))
+}
+
+/// Lay out an ECC-P384 signature as r || s with every 32-bit word byte swapped
+/// for the hardware. p384 gives r and s as 48-byte big-endian scalars, leading
+/// zeros a DER integer drops included.
+fn ecc_sig_to_hw(sig: &Signature) -> [u8; ECC384_SIG_SIZE] {
+    let mut sig_raw = [0u8; ECC384_SIG_SIZE];
+    sig_raw.copy_from_slice(&sig.to_bytes());
 
-fn from_img<T: Copy>(buf: &[u8], offset: usize) -> T {
-    assert!(offset + size_of::<T>() <= buf.len(), "Out of bounds");
-    unsafe {
-        let ptr = buf.as_ptr().add(offset) as *const T;
-        ptr.read_unaligned()
+    for word in sig_raw.chunks_exact_mut(4) {
+        word.reverse();
     }
+
+    sig_raw
 }
 
-fn to_img<T: Copy>(val: &T) -> Vec<u8> {
-    let size = std::mem::size_of::<T>();
-    let ptr = val as *const T as *const u8;
-    unsafe { std::slice::from_raw_parts(ptr, size).to_vec() }
+/// Public key of an ECC-P384 PEM key file in the manifest layout: x || y, with
+/// every 32-bit word byte swapped like the signatures. A private key file
+/// (PKCS#8 or SEC1) gives the public key derived from it.
+fn ecc_pub_key_to_hw(file: &Path) -> Result<[u8; ECC384_PUBK_SIZE]> {
+    let pem = std::fs::read_to_string(file)
+        .io_context(|| format!("Failed to read key file {:?}", file))?;
+    let pem = crate::keys::key_pem(&pem);
+    let key = p384::PublicKey::from_public_key_pem(&pem)
+        .or_else(|_| p384::SecretKey::from_pkcs8_pem(&pem).map(|k| k.public_key()))
+        .or_else(|_| {
+            p384::SecretKey::from_sec1_pem(&pem)
+                .map(|k| k.public_key())
+                .map_err(|_| {
+                    ImgtoolError::SignatureFormat(format!("{:?} is not an ECC-P384 PEM key", file))
+                })
+        })?;
+
+    /* Uncompressed SEC1 point: 0x04 || x || y */
+    let point = key.to_encoded_point(false);
+    let mut pubk = [0u8; ECC384_PUBK_SIZE];
+    pubk.copy_from_slice(&point.as_bytes()[1..]);
+    for word in pubk.chunks_exact_mut(4) {
+        word.reverse();
+    }
+
+    Ok(pubk)
 }
 
-impl AspeedAuthorizationManifest {
-    pub(crate) fn new(path: &PathBuf) -> Self {
-        let img = std::fs::read(path).expect("Failed to read SoC manifest file");
+/// Locate the SoC manifest embedded in a flash image: the image region of the
+/// flash image header that starts with the manifest `magic`, or the first
+/// occurrence of the magic in a flash without that header. Returns the offset
+/// and length of the manifest region.
+pub(crate) fn find_manifest_in_flash(flash: &[u8], magic: u32) -> Result<(usize, usize)> {
+    let pattern = magic.to_le_bytes();
+    let has_header = from_img::<FlashImageHeader>(flash, 0)
+        .is_ok_and(|hdr| hdr.magic.get() == FLASH_IMAGE_MAGIC);
+    /* Image payloads may hold the magic too, only trust the region offsets */
+    let offset = if has_header {
+        flash_image_regions(flash)?
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .find(|offset| flash[*offset..].starts_with(&pattern))
+    } else {
+        flash.windows(pattern.len()).position(|w| w == pattern)
+    }
+    .ok_or_else(|| {
+        ImgtoolError::Manifest(format!(
+            "SoC manifest magic 0x{:08x} not found in flash image",
+            magic
+        ))
+    })?;
+
+    if offset + ASPEED_AUTH_MANIFEST_SIZE > flash.len() {
+        return Err(ImgtoolError::Manifest(format!(
+            "SoC manifest at offset 0x{:08x} is truncated: {} bytes available, expected {}",
+            offset,
+            flash.len() - offset,
+            ASPEED_AUTH_MANIFEST_SIZE
+        )));
+    }
 
-        let ori_preamble = from_img::<AuthManifestPreamble>(&img, 0);
-        let metadata_col = from_img::<AspeedAuthManifestImageMetadataCollection>(
-            &img,
-            size_of::<AuthManifestPreamble>(),
+    Ok((offset, ASPEED_AUTH_MANIFEST_SIZE))
+}
+
+/// Image regions of a flash image as (offset, size) pairs, read from the flash
+/// image header.
+fn flash_image_regions(flash: &[u8]) -> Result<Vec<(usize, usize)>> {
+    if flash.len() < size_of::<FlashImageHeader>() {
+        return Err(ImgtoolError::Manifest(
+            "Flash image is too small for its header".to_string(),
+        ));
+    }
+    let hdr = from_img::<FlashImageHeader>(flash, 0)?;
+    if hdr.magic.get() != FLASH_IMAGE_MAGIC {
+        return Err(ImgtoolError::Manifest(format!(
+            "Invalid flash image magic 0x{:08x}",
+            hdr.magic.get()
+        )));
+    }
+
+    let info_offset = hdr.image_headers_offset.get() as usize;
+    (0..hdr.image_count.get() as usize)
+        .map(|i| {
+            let at = info_offset + i * size_of::<FlashImageInfo>();
+            if at + size_of::<FlashImageInfo>() > flash.len() {
+                return Err(ImgtoolError::Manifest(format!(
+                    "Flash image header {} is truncated",
+                    i
+                )));
+            }
+            let info = from_img::<FlashImageInfo>(flash, at)?;
+            let (offset, size) = (info.offset.get() as usize, info.size.get() as usize);
+            if offset + size > flash.len() {
+                return Err(ImgtoolError::Manifest(format!(
+                    "Flash image {} (id 0x{:x}) at offset 0x{:08x}, {} bytes, is out of bounds",
+                    i,
+                    info.identifier.get(),
+                    offset,
+                    size
+                )));
+            }
+            Ok((offset, size))
+        })
+        .collect()
+}
+
+/// Check a flash image fits in `flash_size` before it is built. The flash image
+/// tool packs `images` in order after its header and image info table, each
+/// extra region goes at its offset. The error lists the computed layout.
+pub(crate) fn check_flash_layout(
+    images: &[PathBuf],
+    regions: &[(PathBuf, u32)],
+    flash_size: u64,
+) -> Result<()> {
+    let file_size = |file: &Path| {
+        std::fs::metadata(file)
+            .map(|m| m.len())
+            .io_context(|| format!("Failed to stat {:?}", file))
+    };
+
+    /* (name, start, end) of every region */
+    let headers_end =
+        (size_of::<FlashImageHeader>() + images.len() * size_of::<FlashImageInfo>()) as u64;
+    let mut layout = vec![("flash image headers".to_string(), 0, headers_end)];
+    let mut offset = headers_end;
+    for image in images {
+        let size = file_size(image)?;
+        layout.push((image.display().to_string(), offset, offset + size));
+        offset += size;
+    }
+    for (file, offset) in regions {
+        let start = *offset as u64;
+        layout.push((file.display().to_string(), start, start + file_size(file)?));
+    }
+    layout.sort_by_key(|(_, start, end)| (*start, *end));
+
+    let mut problems = Vec::new();
+    let mut furthest: Option<&(String, u64, u64)> = None;
+    for region in layout.iter() {
+        let (name, start, end) = region;
+        if let Some((prev, _, prev_end)) = furthest.filter(|(_, _, prev_end)| start < prev_end) {
+            problems.push(format!(
+                "{} at 0x{:08x} overlaps {} ending at 0x{:08x}",
+                name, start, prev, prev_end
+            ));
+        }
+        if *end > flash_size {
+            problems.push(format!(
+                "{} ends at 0x{:08x}, past flash_size 0x{:08x}",
+                name, end, flash_size
+            ));
+        }
+        if furthest.is_none_or(|(_, _, prev_end)| end > prev_end) {
+            furthest = Some(region);
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let table = layout
+        .iter()
+        .map(|(name, start, end)| {
+            format!(
+                "  0x{:08x}-0x{:08x} {:>10} {}",
+                start,
+                end,
+                end - start,
+                name
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Err(ImgtoolError::Config(format!(
+        "Flash image layout doesn't fit: {}\n{}",
+        problems.join("; "),
+        table
+    )))
+}
+
+/// Write each (file, offset) region into a flash image. Regions must start past
+/// the header and image regions of the flash image and must not overlap each
+/// other. Gaps are filled with the erased flash value.
+pub(crate) fn splice_flash_regions(flash: &mut Vec<u8>, regions: &[(PathBuf, u32)]) -> Result<()> {
+    /* Checks the header size and magic before the header is used */
+    let image_regions = flash_image_regions(flash)?;
+    let hdr = from_img::<FlashImageHeader>(flash, 0)?;
+    let headers_end = hdr.image_headers_offset.get() as usize
+        + hdr.image_count.get() as usize * size_of::<FlashImageInfo>();
+    let managed_end = image_regions
+        .into_iter()
+        .map(|(offset, size)| offset + size)
+        .fold(headers_end, usize::max);
+
+    let mut sorted = regions.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(_, offset)| *offset);
+
+    let mut prev: Option<(&PathBuf, usize)> = None;
+    for (file, offset) in sorted {
+        let data = std::fs::read(file)
+            .io_context(|| format!("Failed to read extra flash region {:?}", file))?;
+        let start = *offset as usize;
+        let end = start + data.len();
+
+        if start < managed_end {
+            return Err(ImgtoolError::Config(format!(
+                "Extra flash region {:?} at offset 0x{:08x} overlaps the flash image regions ending at 0x{:08x}",
+                file, start, managed_end
+            )));
+        }
+        if let Some((prev_file, prev_end)) = prev.filter(|(_, prev_end)| start < *prev_end) {
+            return Err(ImgtoolError::Config(format!(
+                "Extra flash region {:?} at offset 0x{:08x} overlaps {:?} ending at 0x{:08x}",
+                file, start, prev_file, prev_end
+            )));
+        }
+        if end > u32::MAX as usize + 1 {
+            return Err(ImgtoolError::Config(format!(
+                "Extra flash region {:?} at offset 0x{:08x}, {} bytes, is out of the 32-bit flash range",
+                file,
+                start,
+                data.len()
+            )));
+        }
+
+        debug!(
+            "Extra flash region {:?} at 0x{:08x}-0x{:08x}",
+            file, start, end
         );
+        if flash.len() < end {
+            flash.resize(end, FLASH_ERASED_BYTE);
+        }
+        flash[start..end].copy_from_slice(&data);
+        prev = Some((file, end));
+    }
+
+    Ok(())
+}
+
+/// Re-hash the image regions of a flash image and check each image digest of
+/// the SoC manifest embedded in it against the region of its fw_id. The flash
+/// image tool writes the caliptra firmware and the manifest first, then one
+/// region per entry of `flash_fw_ids` in order. Images listed in
+/// `digest_ranges` by fw_id are compared over their (offset, len) part only,
+/// `names` labels the images in the error.
+pub(crate) fn verify_flash_digests(
+    flash_path: &Path,
+    flash: &[u8],
+    flash_fw_ids: &[u32],
+    digest_ranges: &BTreeMap<u32, (u64, u64)>,
+    names: &BTreeMap<u32, String>,
+    magic: u32,
+) -> Result<()> {
+    let (offset, len) = find_manifest_in_flash(flash, magic)?;
+    let man = AspeedAuthorizationManifest::from_img(
+        flash_path,
+        flash[offset..offset + len].to_vec(),
+        magic,
+    )?;
+
+    let regions = flash_image_regions(flash)?;
+    let image_regions = regions.get(FLASH_FIRST_IMAGE_REGION..).unwrap_or_default();
+    if image_regions.len() != flash_fw_ids.len() {
+        return Err(ImgtoolError::Manifest(format!(
+            "Flash image has {} image regions after the manifest, expected {}",
+            image_regions.len(),
+            flash_fw_ids.len()
+        )));
+    }
+
+    /* Manifest digests are taken over the image padded to 4 bytes */
+    let digest_of = |data: &[u8]| {
+        let mut data = data.to_vec();
+        data.resize(data.len().next_multiple_of(4), 0);
+        Sha384::digest(&data).to_vec()
+    };
+    let matches = |m: &AspeedAuthManifestImageMetadata| {
+        let Some(i) = flash_fw_ids.iter().position(|fw_id| *fw_id == m.id()) else {
+            return false;
+        };
+        let (offset, size) = image_regions[i];
+        let (start, len) = match digest_ranges.get(&m.id()) {
+            Some(&(start, len)) => (start as usize, len as usize),
+            None => (0, size),
+        };
+        start + len <= size
+            && digest_of(&flash[offset + start..offset + start + len])[..] == m.digest()[..]
+    };
+
+    let mismatched = man
+        .metadata()
+        .filter(|m| !matches(m))
+        .map(|m| image_label(m.id(), names))
+        .collect::<Vec<_>>();
+    if !mismatched.is_empty() {
+        return Err(ImgtoolError::Manifest(format!(
+            "Flash image content does not match the manifest digest of {}",
+            mismatched.join(", ")
+        )));
+    }
+
+    debug!(
+        "All {} manifest digests match the flash image",
+        man.metadata().count()
+    );
+    Ok(())
+}
+
+/* config::image_label with the name from the config, if any */
+fn image_label(fw_id: u32, names: &BTreeMap<u32, String>) -> String {
+    config::image_label(fw_id, names.get(&fw_id).map(String::as_str))
+}
+
+/// For an entry whose fw_id is in `dummy_fw_ids`, whether `digest` is the digest
+/// of the empty dummy image. None for entries that aren't dummies in the config.
+pub(crate) fn dummy_digest_matches(
+    fw_id: u32,
+    digest: &[u8],
+    dummy_fw_ids: Option<&BTreeSet<u32>>,
+) -> Option<bool> {
+    dummy_fw_ids
+        .filter(|ids| ids.contains(&fw_id))
+        .map(|_| digest == &Sha384::digest([])[..])
+}
+
+impl AspeedAuthorizationManifest {
+    /// Load the manifest written by the caliptra tool, in the caliptra layout.
+    pub(crate) fn new(path: &Path) -> Result<Self> {
+        let img = std::fs::read(path)
+            .io_context(|| format!("Failed to read SoC manifest file {:?}", path))?;
+
+        /* The caliptra tool writes the caliptra layout */
+        let metadata_offset = ManifestLayout::Caliptra.metadata_offset();
+        let size = metadata_offset + size_of::<AspeedAuthManifestImageMetadataCollection>();
+        if img.len() < size {
+            return Err(ImgtoolError::Manifest(format!(
+                "SoC manifest {:?} is truncated: {} bytes, expected {}",
+                path,
+                img.len(),
+                size
+            )));
+        }
+        let ori_preamble = from_img::<AuthManifestPreamble>(&img, 0)?;
+        let metadata_col =
+            from_img::<AspeedAuthManifestImageMetadataCollection>(&img, metadata_offset)?;
 
         let preamble = AspeedAuthManifestPreamble {
             magic: ori_preamble.magic,
             size: ori_preamble.size,
             ver: ori_preamble.ver,
-            sec_ver: 0, // Security version is not used in the official manifest
+            sec_ver: U32::new(0), // Security version is not used in the official manifest
             flags: ori_preamble.flags,
             vnd_manifest_ecc_pubk: ori_preamble.vnd_manifest_ecc_pubk,
             vnd_manifest_lms_pubk: ori_preamble.vnd_manifest_lms_pubk,
@@ -144,119 +538,1246 @@ impl AspeedAuthorizationManifest {
             owner_matadata_lms_sig: ori_preamble.owner_matadata_lms_sig,
         };
 
+        Ok(Self {
+            path: path.to_path_buf(),
+            preamble,
+            metadata_col,
+            raw: img,
+            trailer_offset: size,
+        })
+    }
+
+    /// Manifest built in memory, `close()` writes it to `path`.
+    pub(crate) fn from_parts(
+        path: &Path,
+        preamble: AspeedAuthManifestPreamble,
+        metadata_col: AspeedAuthManifestImageMetadataCollection,
+    ) -> Self {
         Self {
-            path: path.clone(),
+            path: path.to_path_buf(),
+            preamble,
+            metadata_col,
+            raw: Vec::new(),
+            trailer_offset: 0,
+        }
+    }
+
+    /// Load a manifest previously written by `close()`, starting with `magic`.
+    pub(crate) fn open(path: &Path, magic: u32) -> Result<Self> {
+        let img = std::fs::read(path)
+            .io_context(|| format!("Failed to read SoC manifest file {:?}", path))?;
+
+        Self::from_img(path, img, magic)
+    }
+
+    /// Parse a manifest image in the layout written by `close()`. `path` is only
+    /// used in messages and by `close()`.
+    pub(crate) fn from_img(path: &Path, img: Vec<u8>, magic: u32) -> Result<Self> {
+        let (preamble, metadata_col) = layout::parse_manifest(&format!("{:?}", path), &img, magic)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
             preamble,
             metadata_col,
+            raw: img,
+            trailer_offset: ASPEED_AUTH_MANIFEST_SIZE,
+        })
+    }
+
+    /// The image metadata entries in use, `count` clamped to the collection size.
+    pub(crate) fn metadata(&self) -> impl Iterator<Item = &AspeedAuthManifestImageMetadata> {
+        self.metadata_col.entries().iter()
+    }
+
+    /// Compare against another manifest and describe every differing field.
+    pub(crate) fn diff(&self, other: &Self) -> Vec<String> {
+        let (a, b) = (&self.preamble, &other.preamble);
+        let mut diffs = Vec::new();
+
+        for (name, old, new) in [
+            ("ver", a.ver.get(), b.ver.get()),
+            ("sec_ver", a.sec_ver.get(), b.sec_ver.get()),
+            ("flags", a.flags.get(), b.flags.get()),
+        ] {
+            if old != new {
+                diffs.push(format!("{}: {} -> {}", name, old, new));
+            }
+        }
+
+        let fields: [(&str, &[u8], &[u8]); 14] = [
+            (
+                "vnd_manifest_ecc_pubk",
+                &a.vnd_manifest_ecc_pubk,
+                &b.vnd_manifest_ecc_pubk,
+            ),
+            (
+                "vnd_manifest_lms_pubk",
+                &a.vnd_manifest_lms_pubk,
+                &b.vnd_manifest_lms_pubk,
+            ),
+            (
+                "vnd_manifest_ecc_sig",
+                &a.vnd_manifest_ecc_sig,
+                &b.vnd_manifest_ecc_sig,
+            ),
+            (
+                "vnd_manifest_lms_sig",
+                &a.vnd_manifest_lms_sig,
+                &b.vnd_manifest_lms_sig,
+            ),
+            (
+                "owner_manifest_ecc_pubk",
+                &a.owner_manifest_ecc_pubk,
+                &b.owner_manifest_ecc_pubk,
+            ),
+            (
+                "owner_manifest_lms_pubk",
+                &a.owner_manifest_lms_pubk,
+                &b.owner_manifest_lms_pubk,
+            ),
+            (
+                "owner_manifest_ecc_sig",
+                &a.owner_manifest_ecc_sig,
+                &b.owner_manifest_ecc_sig,
+            ),
+            (
+                "owner_manifest_lms_sig",
+                &a.owner_manifest_lms_sig,
+                &b.owner_manifest_lms_sig,
+            ),
+            (
+                "owner_manifest_svn_ecc_sig",
+                &a.owner_manifest_svn_ecc_sig,
+                &b.owner_manifest_svn_ecc_sig,
+            ),
+            (
+                "owner_manifest_svn_lms_sig",
+                &a.owner_manifest_svn_lms_sig,
+                &b.owner_manifest_svn_lms_sig,
+            ),
+            (
+                "vnd_matadata_ecc_sig",
+                &a.vnd_matadata_ecc_sig,
+                &b.vnd_matadata_ecc_sig,
+            ),
+            (
+                "vnd_matadata_lms_sig",
+                &a.vnd_matadata_lms_sig,
+                &b.vnd_matadata_lms_sig,
+            ),
+            (
+                "owner_matadata_ecc_sig",
+                &a.owner_matadata_ecc_sig,
+                &b.owner_matadata_ecc_sig,
+            ),
+            (
+                "owner_matadata_lms_sig",
+                &a.owner_matadata_lms_sig,
+                &b.owner_matadata_lms_sig,
+            ),
+        ];
+        for (name, old, new) in fields {
+            if old != new {
+                diffs.push(format!("{}: changed", name));
+            }
         }
+
+        /* Compare image metadata by firmware id */
+        let old_list: BTreeMap<u32, &AspeedAuthManifestImageMetadata> =
+            self.metadata().map(|m| (m.id(), m)).collect();
+        let new_list: BTreeMap<u32, &AspeedAuthManifestImageMetadata> =
+            other.metadata().map(|m| (m.id(), m)).collect();
+
+        for (id, old) in old_list.iter() {
+            match new_list.get(id) {
+                None => diffs.push(format!("fw_id {}: removed", id)),
+                Some(new) => {
+                    if old.flags() != new.flags() {
+                        diffs.push(format!(
+                            "fw_id {}: flags {} -> {}",
+                            id,
+                            old.flags(),
+                            new.flags()
+                        ));
+                    }
+                    if old.digest() != new.digest() {
+                        diffs.push(format!(
+                            "fw_id {}: digest {} -> {}",
+                            id,
+                            old.digest_hex(),
+                            new.digest_hex()
+                        ));
+                    }
+                }
+            }
+        }
+        for id in new_list.keys().filter(|id| !old_list.contains_key(id)) {
+            diffs.push(format!("fw_id {}: added", id));
+        }
+
+        diffs
     }
 
-    pub(crate) fn close(&self) {
-        let preamble = to_img(&self.preamble);
+    /// Compare the image metadata against the metadata `expected` from the config
+    /// and describe every fw_id whose digest differs or that only one side has.
+    /// `names` labels the images.
+    pub(crate) fn compare_metadata(
+        &self,
+        expected: &[config::ImageMetadataConfigFromFile],
+        names: &BTreeMap<u32, String>,
+    ) -> Vec<String> {
+        let actual: BTreeMap<u32, &AspeedAuthManifestImageMetadata> =
+            self.metadata().map(|m| (m.id(), m)).collect();
+        let mut mismatches = Vec::new();
+
+        for img in expected {
+            match actual.get(&img.fw_id) {
+                None => mismatches.push(format!(
+                    "{}: missing from the manifest",
+                    image_label(img.fw_id, names)
+                )),
+                Some(m) if m.digest_hex() != img.digest => mismatches.push(format!(
+                    "{}: digest manifest {}, config {}",
+                    image_label(img.fw_id, names),
+                    m.digest_hex(),
+                    img.digest
+                )),
+                Some(_) => {}
+            }
+        }
+        for id in actual
+            .keys()
+            .filter(|id| !expected.iter().any(|img| img.fw_id == **id))
+        {
+            mismatches.push(format!("{}: not in the config", image_label(*id, names)));
+        }
+
+        mismatches
+    }
+
+    /// Describe the header and every image metadata entry. Entries whose fw_id is
+    /// in `dummy_fw_ids` are marked as backed by the empty dummy image, entries
+    /// in `names` are labelled with their name.
+    pub(crate) fn dump(
+        &self,
+        dummy_fw_ids: Option<&BTreeSet<u32>>,
+        names: &BTreeMap<u32, String>,
+    ) -> Vec<String> {
+        let p = &self.preamble;
+        let mut lines = vec![
+            format!("magic : 0x{:08x}", p.magic.get()),
+            format!("size : {}", p.size.get()),
+            format!("ver : {}", p.ver.get()),
+            format!("sec_ver : {}", p.sec_ver.get()),
+            format!("flags : 0x{:08x}", p.flags.get()),
+            format!("images : {}", self.metadata().count()),
+        ];
+
+        for m in self.metadata() {
+            let mut line = format!(
+                "{} : flags 0x{:08x}, digest {}",
+                image_label(m.id(), names),
+                m.flags(),
+                m.digest_hex()
+            );
+            match dummy_digest_matches(m.id(), m.digest(), dummy_fw_ids) {
+                Some(true) => line.push_str(" [dummy]"),
+                Some(false) => line.push_str(" [dummy in config, digest differs]"),
+                None => {}
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    pub(crate) fn preamble(&self) -> &AspeedAuthManifestPreamble {
+        &self.preamble
+    }
+
+    /// Vendor manifest signatures replaced by the prebuilt ones. The overlay
+    /// clears the matching public key, which the caliptra tool always fills.
+    pub(crate) fn prebuilt_signatures(&self) -> Vec<&'static str> {
+        let p = &self.preamble;
+        [
+            (
+                "vnd_manifest_ecc_sig",
+                p.vnd_manifest_ecc_pubk.iter().all(|b| *b == 0)
+                    && p.vnd_manifest_ecc_sig.iter().any(|b| *b != 0),
+            ),
+            (
+                "vnd_manifest_lms_sig",
+                p.vnd_manifest_lms_pubk.iter().all(|b| *b == 0)
+                    && p.vnd_manifest_lms_sig.iter().any(|b| *b != 0),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, overlaid)| overlaid.then_some(name))
+        .collect()
+    }
+
+    pub(crate) fn sec_ver(&self) -> u32 {
+        self.preamble.sec_ver.get()
+    }
+
+    /// Compare the manifest public keys to the key files of the config, so a
+    /// manifest signed with other keys than configured is caught. Must run
+    /// before the prebuilt vendor signatures clear the vendor public keys.
+    pub(crate) fn verify_public_keys(
+        &self,
+        cfg: &config::AspeedAuthManifestConfigFromFile,
+        key_dir: &Path,
+    ) -> Result<()> {
+        let p = &self.preamble;
+        let sections = [
+            (
+                "vendor_man_key_config",
+                Some(&cfg.vendor_man_key_config),
+                &p.vnd_manifest_ecc_pubk,
+                &p.vnd_manifest_lms_pubk,
+            ),
+            (
+                "owner_man_key_config",
+                cfg.owner_man_key_config.as_ref(),
+                &p.owner_manifest_ecc_pubk,
+                &p.owner_manifest_lms_pubk,
+            ),
+        ];
+
+        let mut mismatched = Vec::new();
+        for (section, keys, ecc_pubk, lms_pubk) in sections {
+            let Some(keys) = keys else {
+                continue;
+            };
+
+            /* The public key file, else the one derived from the private key */
+            if let Some(file) = keys.ecc_pub_key.as_ref().or(keys.ecc_priv_key.as_ref()) {
+                if ecc_pub_key_to_hw(&key_dir.join(file))? != *ecc_pubk {
+                    mismatched.push(format!("{}.ecc_pub_key", section));
+                }
+            }
+
+            /* LMS public key files hold the raw caliptra structure */
+            if let Some(file) = keys.lms_pub_key.as_ref() {
+                let file = key_dir.join(file);
+                let lms = std::fs::read(&file)
+                    .io_context(|| format!("Failed to read key file {:?}", file))?;
+                if lms[..] != lms_pubk[..] {
+                    mismatched.push(format!("{}.lms_pub_key", section));
+                }
+            }
+        }
+
+        if !mismatched.is_empty() {
+            return Err(ImgtoolError::Manifest(format!(
+                "Manifest public keys do not match the key files: {}",
+                mismatched.join(", ")
+            )));
+        }
+
+        debug!("Manifest public keys match the key files");
+        Ok(())
+    }
+
+    /// Check the caliptra manifest tool wrote the magic the config expects.
+    pub(crate) fn validate_magic(
+        &self,
+        cfg: &config::AspeedAuthManifestConfigFromFile,
+    ) -> Result<()> {
+        let magic = self.preamble.magic.get();
+        if magic != cfg.expected_magic() {
+            return Err(ImgtoolError::Manifest(format!(
+                "SoC manifest {:?} has magic 0x{:08x}, expected 0x{:08x}",
+                self.path,
+                magic,
+                cfg.expected_magic()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check every signature field that the key configs call for is populated.
+    /// The SVN signatures are only expected when `svn_signed` is set. A manifest
+    /// flagged as vendor signed needs a vendor manifest key, without one it can't
+    /// be verified and would only be rejected at boot.
+    pub(crate) fn validate_signatures(
+        &self,
+        cfg: &config::AspeedAuthManifestConfigFromFile,
+        svn_signed: bool,
+    ) -> Result<()> {
+        let p = &self.preamble;
+        let vnd_man = &cfg.vendor_man_key_config;
+        if cfg
+            .manifest_config
+            .flags
+            .contains(config::ManifestFlags::VENDOR_SIGNATURE_REQUIRED)
+            && !vnd_man.has_any_key()
+        {
+            return Err(ImgtoolError::Manifest(
+                "Vendor signature required by the manifest flags, \
+                 but vendor_man_key_config has no key"
+                    .to_string(),
+            ));
+        }
+        let vnd_fw = &cfg.vendor_fw_key_config;
+        let own_man = cfg.owner_man_key_config.clone().unwrap_or_default();
+        let own_fw = cfg.owner_fw_key_config.clone().unwrap_or_default();
+
+        let fields: [(&str, bool, &[u8]); 10] = [
+            (
+                "vnd_manifest_ecc_sig",
+                vnd_man.has_ecc_key(),
+                &p.vnd_manifest_ecc_sig,
+            ),
+            (
+                "vnd_manifest_lms_sig",
+                vnd_man.has_lms_key(),
+                &p.vnd_manifest_lms_sig,
+            ),
+            (
+                "owner_manifest_ecc_sig",
+                own_man.has_ecc_key(),
+                &p.owner_manifest_ecc_sig,
+            ),
+            (
+                "owner_manifest_lms_sig",
+                own_man.has_lms_key(),
+                &p.owner_manifest_lms_sig,
+            ),
+            (
+                "owner_manifest_svn_ecc_sig",
+                svn_signed && own_man.has_ecc_key(),
+                &p.owner_manifest_svn_ecc_sig,
+            ),
+            (
+                "owner_manifest_svn_lms_sig",
+                svn_signed && own_man.has_lms_key(),
+                &p.owner_manifest_svn_lms_sig,
+            ),
+            (
+                "vnd_matadata_ecc_sig",
+                vnd_fw.has_ecc_key(),
+                &p.vnd_matadata_ecc_sig,
+            ),
+            (
+                "vnd_matadata_lms_sig",
+                vnd_fw.has_lms_key(),
+                &p.vnd_matadata_lms_sig,
+            ),
+            (
+                "owner_matadata_ecc_sig",
+                own_fw.has_ecc_key(),
+                &p.owner_matadata_ecc_sig,
+            ),
+            (
+                "owner_matadata_lms_sig",
+                own_fw.has_lms_key(),
+                &p.owner_matadata_lms_sig,
+            ),
+        ];
+
+        let missing = fields
+            .iter()
+            .filter(|(_, expected, sig)| *expected && sig.iter().all(|b| *b == 0))
+            .map(|(name, _, _)| *name)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(ImgtoolError::Manifest(format!(
+                "Signature fields left zero although their keys are configured: {}",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Manifest image in `layout`. The caliptra layout drops sec_ver and the
+    /// SVN signatures.
+    pub(crate) fn to_image(&self, layout: ManifestLayout) -> Vec<u8> {
+        /* The size field describes the preamble, so rewrite it for the layout */
+        let p = &self.preamble;
+        let preamble = match layout {
+            ManifestLayout::Aspeed => {
+                let mut preamble_hdr = *p;
+                preamble_hdr.size.set(layout.metadata_offset() as u32);
+                to_img(&preamble_hdr)
+            }
+            ManifestLayout::Caliptra => to_img(&AuthManifestPreamble {
+                magic: p.magic,
+                size: U32::new(layout.metadata_offset() as u32),
+                ver: p.ver,
+                flags: p.flags,
+                vnd_manifest_ecc_pubk: p.vnd_manifest_ecc_pubk,
+                vnd_manifest_lms_pubk: p.vnd_manifest_lms_pubk,
+                vnd_manifest_ecc_sig: p.vnd_manifest_ecc_sig,
+                vnd_manifest_lms_sig: p.vnd_manifest_lms_sig,
+                owner_manifest_ecc_pubk: p.owner_manifest_ecc_pubk,
+                owner_manifest_lms_pubk: p.owner_manifest_lms_pubk,
+                owner_manifest_ecc_sig: p.owner_manifest_ecc_sig,
+                owner_manifest_lms_sig: p.owner_manifest_lms_sig,
+                vnd_matadata_ecc_sig: p.vnd_matadata_ecc_sig,
+                vnd_matadata_lms_sig: p.vnd_matadata_lms_sig,
+                owner_matadata_ecc_sig: p.owner_matadata_ecc_sig,
+                owner_matadata_lms_sig: p.owner_matadata_lms_sig,
+            }),
+        };
         let metadata_col = to_img(&self.metadata_col);
         let mut image = Vec::new();
 
         image.extend_from_slice(&preamble);
         image.extend_from_slice(&metadata_col);
+        // Keep any data the manifest tool placed after the metadata collection
+        image.extend_from_slice(&self.raw[self.trailer_offset..]);
+        image
+    }
 
-        std::fs::write(self.path.clone(), image).expect("Failed to write SoC manifest file");
+    pub(crate) fn close(&self, layout: ManifestLayout) -> Result<()> {
+        utility::write_atomic(&self.path, &self.to_image(layout))
+            .io_context(|| format!("Failed to write SoC manifest file {}", self.path.display()))
     }
 
-    pub(crate) fn modify_vnd_ecc_sig(
-        &mut self,
-    ) -> Result<()> {
+    pub(crate) fn modify_vnd_ecc_sig(&mut self) -> Result<()> {
         // Skip modification if not configured
         if self.preamble.vnd_manifest_ecc_sig == [0u8; ECC384_SIG_SIZE] {
             info!("No need to modify vendor ECC signature.");
             return Ok(());
         }
 
-        let sig_der = VND_ECC_SIG_BIN.to_vec();
-
-        // Parse DER and convert to raw little-endian hardware format
-        let sig_raw = Signature::from_der(&sig_der)
-            .map_err(|_| anyhow!("Failed to parse DER signature"))?
-            .to_vec()
-            .chunks_exact(4)
-            .flat_map(|chunk| {
-                u32::from_le_bytes(chunk.try_into().expect("Chunk size mismatch")).to_be_bytes()
-            })
-            .collect::<Vec<u8>>();
+        // Parse DER or raw r || s and convert to raw little-endian hardware format
+        let sig_raw = ecc_sig_to_hw(&parse_ecc_sig(VND_ECC_SIG_BIN)?);
 
         debug!("Prebuilt signature ECC: {:02x?}", sig_raw);
 
         // Apply to preamble
         self.preamble.vnd_manifest_ecc_pubk = [0; ECC384_PUBK_SIZE];
-        self.preamble.vnd_manifest_ecc_sig = sig_raw
-            .try_into()
-            .map_err(|_| anyhow!("Signature size mismatch"))?;
+        self.preamble.vnd_manifest_ecc_sig = sig_raw;
 
         Ok(())
     }
 
-    pub(crate) fn modify_vnd_lms_sig(
-        &mut self,
-    ) -> Result<()> {
+    pub(crate) fn modify_vnd_lms_sig(&mut self) -> Result<()> {
         // Skip modification if not configured
         if self.preamble.vnd_manifest_lms_sig == [0u8; LMS_SIG_SIZE] {
             info!("No need to modify vendor LMS signature.");
             return Ok(());
         }
 
-        let sig_raw = VND_LMS_SIG_BIN.to_vec();
+        /* The prebuilt signature is either raw or in the portable format */
+        let sig_raw = crate::lms_sig::parse_lms_sig(VND_LMS_SIG_BIN)?;
 
         debug!("Prebuilt signature LMS: {:02x?}", sig_raw);
 
         // Apply to preamble
         self.preamble.vnd_manifest_lms_pubk = [0; LMS_PUBK_SIZE];
-        self.preamble.vnd_manifest_lms_sig = sig_raw
-            .try_into()
-            .map_err(|_| anyhow!("Signature size mismatch"))?;
+        self.preamble.vnd_manifest_lms_sig = sig_raw;
 
         Ok(())
     }
 
+    /// Sign the configured security version with the caliptra manifest tool and
+    /// write it with its signatures to the preamble.
     pub(crate) fn insert_security_version(
         &mut self,
         path: &config::AspeedManifestCreationPath,
         cfg: &config::AspeedAuthManifestConfigFromFile,
         key_dir: &PathBuf,
+        runner: &dyn tool::ToolRunner,
+    ) -> Result<()> {
+        let (ecc_sig, lms_sig) = create_svn_signature(path, cfg, key_dir, runner)?;
+        self.apply_svn_signature(cfg.manifest_config.security_version, ecc_sig, lms_sig);
+
+        Ok(())
+    }
+
+    /// Write the security version and its owner SVN signatures to the preamble,
+    /// for signatures from any source.
+    pub(crate) fn apply_svn_signature(
+        &mut self,
+        sec_ver: u32,
+        ecc_sig: [u8; ECC384_SIG_SIZE],
+        lms_sig: [u8; LMS_SIG_SIZE],
     ) {
-        let cmd = path.tool_dir.join("caliptra-auth-manifest-app");
-        let mut child = std::process::Command::new(cmd)
-            .args([
+        debug!("Security Version ECC Signature: {:02x?}", ecc_sig);
+        debug!("Security Version LMS Signature: {:02x?}", lms_sig);
+        self.preamble.sec_ver.set(sec_ver);
+        self.preamble.owner_manifest_svn_ecc_sig = ecc_sig;
+        self.preamble.owner_manifest_svn_lms_sig = lms_sig;
+    }
+}
+
+/// Run `create-sig-svn` of the caliptra manifest tool and return the owner SVN
+/// signatures it wrote to `path.svn_sig`.
+pub(crate) fn create_svn_signature(
+    path: &config::AspeedManifestCreationPath,
+    cfg: &config::AspeedAuthManifestConfigFromFile,
+    key_dir: &PathBuf,
+    runner: &dyn tool::ToolRunner,
+) -> Result<([u8; ECC384_SIG_SIZE], [u8; LMS_SIG_SIZE])> {
+    /* The SVN signature comes from the prebuilt manifest tool, no toolchain is run */
+    let cmd = path.auth_manifest_tool();
+    config::check_file_exists(&cmd)?;
+    let status = runner.run(
+        &cmd,
+        &tool::tool_args([
+            "create-sig-svn",
+            "--version",
+            &cfg.manifest_config.version.to_string(),
+            "--sec-version",
+            &cfg.manifest_config.security_version.to_string(),
+            "--flags",
+            &cfg.manifest_config.flags.bits().to_string(),
+            "--key-dir",
+            &key_dir.to_string(),
+            "--config",
+            &path.caliptra_cfg.to_string(),
+            "--out",
+            &path.svn_sig.to_string(),
+        ]),
+        None,
+    )?;
+    if !status.success() {
+        return Err(ImgtoolError::ToolFailed {
+            program: cmd,
+            status,
+        });
+    }
+
+    let svn_sig = path.svn_sig.unwrap_or_err();
+    let sig = std::fs::read(&svn_sig)
+        .io_context(|| format!("Failed to read svn signature file {:?}", svn_sig))?;
+    parse_svn_sig(&sig)
+}
+
+/// Split the `create-sig-svn` output into the preamble SVN signatures. The file
+/// is the ECC signature (r || s, 96 bytes) followed by the caliptra LMS
+/// signature (1620 bytes), whose q is swapped to the byte order the ROM verifies.
+pub(crate) fn parse_svn_sig(sig: &[u8]) -> Result<([u8; ECC384_SIG_SIZE], [u8; LMS_SIG_SIZE])> {
+    if sig.len() != ECC384_SIG_SIZE + LMS_SIG_SIZE {
+        return Err(ImgtoolError::SignatureFormat(format!(
+            "Invalid svn signature size: expected {} bytes (ECC {} + LMS {}), got {}",
+            ECC384_SIG_SIZE + LMS_SIG_SIZE,
+            ECC384_SIG_SIZE,
+            LMS_SIG_SIZE,
+            sig.len()
+        )));
+    }
+
+    let ecc_sig: [u8; ECC384_SIG_SIZE] = from_img(sig, 0)?;
+    let mut lms_sig: [u8; LMS_SIG_SIZE] = from_img(sig, ECC384_SIG_SIZE)?;
+
+    // Convert lms q endianness to match rom verification.
+    lms_sig[0..4].reverse();
+
+    Ok((ecc_sig, lms_sig))
+}
+
+/// Reject a security version lower than the one in a previously released manifest.
+pub(crate) fn check_security_version(prev_man: &Path, sec_ver: u32, magic: u32) -> Result<()> {
+    let prev = AspeedAuthorizationManifest::open(prev_man, magic)?;
+    let prev_sec_ver = prev.preamble.sec_ver.get();
+
+    if sec_ver < prev_sec_ver {
+        return Err(ImgtoolError::Config(format!(
+            "Security version rollback: config security_version {} is lower than {} in {:?}",
+            sec_ver, prev_sec_ver, prev_man
+        )));
+    }
+
+    info!(
+        "Security version {} is not lower than previous manifest ({})",
+        sec_ver, prev_sec_ver
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{AUTH_MANIFEST_MAGIC, IMAGE_METADATA_MAX_COUNT};
+
+    fn svn_sig(ecc: u8, lms_q: [u8; 4], lms: u8) -> Vec<u8> {
+        let mut sig = vec![ecc; ECC384_SIG_SIZE];
+        sig.extend_from_slice(&lms_q);
+        sig.resize(ECC384_SIG_SIZE + LMS_SIG_SIZE, lms);
+        sig
+    }
+
+    #[test]
+    fn svn_sig_splits_ecc_then_lms() {
+        let (ecc_sig, lms_sig) = parse_svn_sig(&svn_sig(0xec, [1, 2, 3, 4], 0x15)).unwrap();
+
+        assert_eq!(ecc_sig, [0xec; ECC384_SIG_SIZE]);
+        assert_eq!(lms_sig[..4], [4, 3, 2, 1]);
+        assert!(lms_sig[4..].iter().all(|b| *b == 0x15));
+    }
+
+    #[test]
+    fn svn_sig_keeps_field_boundaries() {
+        let sig = (0..ECC384_SIG_SIZE + LMS_SIG_SIZE)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let (ecc_sig, lms_sig) = parse_svn_sig(&sig).unwrap();
+
+        assert_eq!(ecc_sig[..], sig[..ECC384_SIG_SIZE]);
+        assert_eq!(lms_sig[4..], sig[ECC384_SIG_SIZE + 4..]);
+        assert_eq!(lms_sig[0], sig[ECC384_SIG_SIZE + 3]);
+    }
+
+    #[test]
+    fn svn_sig_rejects_wrong_length() {
+        for len in [
+            0,
+            ECC384_SIG_SIZE,
+            ECC384_SIG_SIZE + LMS_SIG_SIZE - 1,
+            ECC384_SIG_SIZE + LMS_SIG_SIZE + 1,
+        ] {
+            let err = parse_svn_sig(&vec![0; len]).unwrap_err();
+            assert!(
+                matches!(err, ImgtoolError::SignatureFormat(_)),
+                "{} bytes: {}",
+                len,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn svn_signature_is_applied_to_the_preamble() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.bin");
+        let mut img = vec![0u8; ASPEED_AUTH_MANIFEST_SIZE];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        let mut man =
+            AspeedAuthorizationManifest::from_img(&path, img, AUTH_MANIFEST_MAGIC).unwrap();
+
+        man.apply_svn_signature(7, [0xec; ECC384_SIG_SIZE], [0x15; LMS_SIG_SIZE]);
+        assert_eq!(man.sec_ver(), 7);
+        assert_eq!(
+            man.preamble.owner_manifest_svn_ecc_sig,
+            [0xec; ECC384_SIG_SIZE]
+        );
+        assert_eq!(
+            man.preamble.owner_manifest_svn_lms_sig,
+            [0x15; LMS_SIG_SIZE]
+        );
+
+        let written = man.to_image(ManifestLayout::Aspeed);
+        let reread =
+            AspeedAuthorizationManifest::from_img(&path, written, AUTH_MANIFEST_MAGIC).unwrap();
+        assert_eq!(reread.sec_ver(), 7);
+        assert!(man.diff(&reread).is_empty());
+    }
+
+    #[test]
+    fn svn_signature_runs_create_sig_svn() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = config::AspeedManifestCreationPath {
+            prebuilt_dir: tmp.path().to_path_buf(),
+            tool_dir: tmp.path().to_path_buf(),
+            key_dir: None,
+            aspeed_cfg: tmp.path().join("manifest.toml"),
+            caliptra_cfg: Some(PathBuf::from("caliptra.toml")),
+            manifest: None,
+            flash_image: None,
+            svn_sig: Some(tmp.path().join("svn.sig")),
+            tool_names: Default::default(),
+        };
+        std::fs::write(path.auth_manifest_tool(), "").unwrap();
+        let mut cfg = config::AspeedAuthManifestConfigFromFile::default();
+        cfg.manifest_config.version = 1;
+        cfg.manifest_config.security_version = 3;
+
+        let man_path = tmp.path().join("manifest.bin");
+        let mut img = vec![
+            0u8;
+            size_of::<AuthManifestPreamble>()
+                + size_of::<AspeedAuthManifestImageMetadataCollection>()
+        ];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        std::fs::write(&man_path, &img).unwrap();
+        let mut man = AspeedAuthorizationManifest::new(&man_path).unwrap();
+
+        let mut svn_sig = vec![0xec; ECC384_SIG_SIZE];
+        svn_sig.extend_from_slice(&[0x15; LMS_SIG_SIZE]);
+        let runner = tool::RecordingRunner {
+            outputs: std::collections::BTreeMap::from([("create-sig-svn".to_string(), svn_sig)]),
+            ..Default::default()
+        };
+        man.insert_security_version(&path, &cfg, &PathBuf::from("keys"), &runner)
+            .unwrap();
+        assert_eq!(man.preamble.sec_ver.get(), 3);
+        assert_eq!(
+            man.preamble.owner_manifest_svn_ecc_sig,
+            [0xec; ECC384_SIG_SIZE]
+        );
+
+        let tool = tmp.path().join("caliptra-auth-manifest-app");
+        let svn_sig_path = tmp.path().join("svn.sig").display().to_string();
+        assert_eq!(
+            runner.calls.into_inner(),
+            [[
+                tool.display().to_string().as_str(),
                 "create-sig-svn",
                 "--version",
-                &cfg.manifest_config.version.to_string(),
+                "1",
                 "--sec-version",
-                &cfg.manifest_config.security_version.to_string(),
+                "3",
                 "--flags",
-                &cfg.manifest_config.flags.to_string(),
+                "0",
                 "--key-dir",
-                &key_dir.to_string(),
+                "keys",
                 "--config",
-                &path.caliptra_cfg.to_string(),
+                "caliptra.toml",
                 "--out",
-                &path.svn_sig.to_string(),
-            ])
-            .spawn()
-            .expect("Failed to execute command");
+                &svn_sig_path,
+            ]]
+        );
+    }
+
+    /* Aspeed manifest with `sec_ver`, written to `dir` */
+    fn manifest_with_sec_ver(dir: &Path, sec_ver: u32) -> PathBuf {
+        let path = dir.join(format!("prev-{}.bin", sec_ver));
+        let mut img = vec![0u8; ASPEED_AUTH_MANIFEST_SIZE];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        img[12..16].copy_from_slice(&sec_ver.to_le_bytes());
+        std::fs::write(&path, img).unwrap();
+        path
+    }
 
-        /* Wait for the process to exit */
-        let _ = child.wait().expect("Failed to wait on child");
+    #[test]
+    fn security_version_must_not_decrease() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prev = manifest_with_sec_ver(tmp.path(), 5);
 
-        let sig =
-            std::fs::read(path.svn_sig.unwrap_or_err()).expect("Failed to read svn signature file");
-        let ecc_sig: [u8; ECC384_SIG_SIZE] = from_img(&sig, 0);
-        let mut lms_sig: [u8; LMS_SIG_SIZE] = from_img(&sig, ECC384_SIG_SIZE);
+        assert!(check_security_version(&prev, 5, AUTH_MANIFEST_MAGIC).is_ok());
+        assert!(check_security_version(&prev, 6, AUTH_MANIFEST_MAGIC).is_ok());
+        let err = check_security_version(&prev, 4, AUTH_MANIFEST_MAGIC).unwrap_err();
+        assert!(err.to_string().contains("rollback"), "{}", err);
+    }
 
-        // Convert lms q endianness to match rom verification.
-        lms_sig[0..4].reverse();
+    #[test]
+    fn previous_manifest_is_validated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prev = manifest_with_sec_ver(tmp.path(), 5);
 
-        debug!("Security Version ECC Signature: {:02x?}", ecc_sig);
-        debug!("Security Version LMS Signature: {:02x?}", lms_sig);
-        self.preamble.sec_ver = cfg.manifest_config.security_version;
-        self.preamble.owner_manifest_svn_ecc_sig = ecc_sig;
-        self.preamble.owner_manifest_svn_lms_sig = lms_sig;
+        let mut img = std::fs::read(&prev).unwrap();
+        img[..4].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        std::fs::write(&prev, &img).unwrap();
+        let err = check_security_version(&prev, 5, AUTH_MANIFEST_MAGIC).unwrap_err();
+        assert!(err.to_string().contains("magic"), "{}", err);
+
+        let prev = manifest_with_sec_ver(tmp.path(), 5);
+        let img = std::fs::read(&prev).unwrap();
+        std::fs::write(&prev, &img[..img.len() - 1]).unwrap();
+        let err = check_security_version(&prev, 5, AUTH_MANIFEST_MAGIC).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+
+    #[test]
+    fn truncated_tool_output_is_an_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.bin");
+        std::fs::write(&path, vec![0u8; size_of::<AuthManifestPreamble>()]).unwrap();
+
+        let err = AspeedAuthorizationManifest::new(&path).err().unwrap();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+        assert!(AspeedAuthorizationManifest::new(&tmp.path().join("missing")).is_err());
+    }
+
+    /* Flash image holding `images` in order after its header and image infos */
+    fn flash_with_images(images: &[Vec<u8>]) -> Vec<u8> {
+        let headers_end =
+            size_of::<FlashImageHeader>() + images.len() * size_of::<FlashImageInfo>();
+        let mut flash = Vec::new();
+        flash.extend_from_slice(&FLASH_IMAGE_MAGIC.to_le_bytes());
+        flash.extend_from_slice(&1u16.to_le_bytes());
+        flash.extend_from_slice(&(images.len() as u16).to_le_bytes());
+        flash.extend_from_slice(&(size_of::<FlashImageHeader>() as u32).to_le_bytes());
+        flash.resize(size_of::<FlashImageHeader>(), 0);
+        let mut offset = headers_end;
+        for (id, image) in images.iter().enumerate() {
+            flash.extend_from_slice(&(id as u32).to_le_bytes());
+            flash.extend_from_slice(&(offset as u32).to_le_bytes());
+            flash.extend_from_slice(&(image.len() as u32).to_le_bytes());
+            flash.extend_from_slice(&0u32.to_le_bytes());
+            offset += image.len();
+        }
+        for image in images {
+            flash.extend_from_slice(image);
+        }
+        flash
+    }
+
+    #[test]
+    fn manifest_is_found_through_the_flash_header() {
+        let magic = AUTH_MANIFEST_MAGIC.to_le_bytes();
+        let mut manifest = vec![0u8; ASPEED_AUTH_MANIFEST_SIZE];
+        manifest[..4].copy_from_slice(&magic);
+
+        /* The first image carries the magic in its payload */
+        let mut payload = vec![0x5a; 64];
+        payload[16..20].copy_from_slice(&magic);
+        let flash = flash_with_images(&[payload.clone(), manifest.clone()]);
+        let manifest_at = flash.len() - manifest.len();
+        assert_eq!(
+            find_manifest_in_flash(&flash, AUTH_MANIFEST_MAGIC).unwrap(),
+            (manifest_at, ASPEED_AUTH_MANIFEST_SIZE)
+        );
+
+        /* No image region starts with the magic */
+        let flash = flash_with_images(&[payload.clone()]);
+        assert!(find_manifest_in_flash(&flash, AUTH_MANIFEST_MAGIC).is_err());
+
+        /* Without a flash image header the magic is scanned for */
+        let mut raw = vec![0u8; 8];
+        raw.extend_from_slice(&manifest);
+        assert_eq!(
+            find_manifest_in_flash(&raw, AUTH_MANIFEST_MAGIC).unwrap(),
+            (8, ASPEED_AUTH_MANIFEST_SIZE)
+        );
+    }
+
+    #[test]
+    fn trailing_bytes_survive_a_rewrite() {
+        const TRAILER: &[u8] = b"appended by the manifest tool";
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.bin");
+        let size = size_of::<AuthManifestPreamble>()
+            + size_of::<AspeedAuthManifestImageMetadataCollection>();
+        let mut img = vec![0u8; size];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        img.extend_from_slice(TRAILER);
+        std::fs::write(&path, &img).unwrap();
+
+        let mut man = AspeedAuthorizationManifest::new(&path).unwrap();
+        man.preamble.sec_ver = U32::new(9);
+        man.close(ManifestLayout::Aspeed).unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), ASPEED_AUTH_MANIFEST_SIZE + TRAILER.len());
+        assert!(written.ends_with(TRAILER));
+        assert_eq!(written[12..16], 9u32.to_le_bytes());
+
+        /* A manifest read back in the aspeed layout keeps them as well */
+        AspeedAuthorizationManifest::open(&path, AUTH_MANIFEST_MAGIC)
+            .unwrap()
+            .close(ManifestLayout::Aspeed)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+    }
+
+    #[test]
+    fn closed_manifest_size_covers_the_aspeed_preamble() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.bin");
+        let caliptra_size = size_of::<AuthManifestPreamble>();
+        let mut img =
+            vec![0u8; caliptra_size + size_of::<AspeedAuthManifestImageMetadataCollection>()];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        /* the tool writes the caliptra preamble size */
+        img[4..8].copy_from_slice(&(caliptra_size as u32).to_le_bytes());
+        std::fs::write(&path, &img).unwrap();
+
+        let man = AspeedAuthorizationManifest::new(&path).unwrap();
+        man.close(ManifestLayout::Aspeed).unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        let preamble_len = written.len() - size_of::<AspeedAuthManifestImageMetadataCollection>();
+        let size = u32::from_le_bytes(written[4..8].try_into().unwrap());
+        assert_eq!(size as usize, preamble_len);
+        assert_ne!(written[4..8], img[4..8]);
+    }
+
+    /* r with a leading zero byte, which DER encodes one byte short */
+    fn raw_ecc_sig() -> [u8; ECC384_SIG_SIZE] {
+        let mut raw = [0u8; ECC384_SIG_SIZE];
+        for (i, b) in raw.iter_mut().enumerate() {
+            *b = i as u8 + 1;
+        }
+        raw[0] = 0;
+        raw
+    }
+
+    #[test]
+    fn der_and_raw_ecc_signatures_give_the_same_hw_bytes() {
+        let raw = raw_ecc_sig();
+        let der = Signature::from_slice(&raw).unwrap().to_der();
+        assert_ne!(der.as_bytes().len(), ECC384_SIG_SIZE);
+
+        let from_der = ecc_sig_to_hw(&parse_ecc_sig(der.as_bytes()).unwrap());
+        let from_raw = ecc_sig_to_hw(&parse_ecc_sig(&raw).unwrap());
+        assert_eq!(from_der, from_raw);
+
+        assert!(parse_ecc_sig(&raw[..ECC384_SIG_SIZE - 1]).is_err());
+        assert!(parse_ecc_sig(&[0u8; ECC384_SIG_SIZE]).is_err());
+    }
+
+    #[test]
+    fn ecc_sig_hw_layout_swaps_every_word() {
+        let der = Signature::from_slice(&raw_ecc_sig()).unwrap().to_der();
+        let hw = ecc_sig_to_hw(&parse_ecc_sig(der.as_bytes()).unwrap());
+
+        /* r = 00 02 03 04 05 .. 30, s = 31 .. 60, each word reversed */
+        assert_eq!(hw[..8], [0x04, 0x03, 0x02, 0x00, 0x08, 0x07, 0x06, 0x05]);
+        assert_eq!(hw[44..48], [0x30, 0x2f, 0x2e, 0x2d]);
+        assert_eq!(hw[48..52], [0x34, 0x33, 0x32, 0x31]);
+        assert_eq!(hw[92..], [0x60, 0x5f, 0x5e, 0x5d]);
+    }
+
+    /* Flash image with one 8-byte image right after its header and image info */
+    fn flash_with_one_image() -> Vec<u8> {
+        let headers_end = size_of::<FlashImageHeader>() + size_of::<FlashImageInfo>();
+        let mut flash = Vec::new();
+        flash.extend_from_slice(&FLASH_IMAGE_MAGIC.to_le_bytes());
+        flash.extend_from_slice(&1u16.to_le_bytes());
+        flash.extend_from_slice(&1u16.to_le_bytes());
+        flash.extend_from_slice(&(size_of::<FlashImageHeader>() as u32).to_le_bytes());
+        flash.resize(size_of::<FlashImageHeader>(), 0);
+        flash.extend_from_slice(&0u32.to_le_bytes());
+        flash.extend_from_slice(&(headers_end as u32).to_le_bytes());
+        flash.extend_from_slice(&8u32.to_le_bytes());
+        flash.resize(headers_end + 8, 0xaa);
+        flash
+    }
+
+    #[test]
+    fn extra_regions_are_spliced_past_the_images() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blob = tmp.path().join("board-data.bin");
+        std::fs::write(&blob, [1, 2, 3, 4]).unwrap();
+
+        let mut flash = flash_with_one_image();
+        let image_end = flash.len();
+        splice_flash_regions(&mut flash, &[(blob.clone(), 0x100)]).unwrap();
+        assert_eq!(flash.len(), 0x104);
+        assert!(flash[image_end..0x100]
+            .iter()
+            .all(|b| *b == FLASH_ERASED_BYTE));
+        assert_eq!(flash[0x100..], [1, 2, 3, 4]);
+
+        /* A region inside the image regions written by the tool */
+        let mut flash = flash_with_one_image();
+        let err =
+            splice_flash_regions(&mut flash, &[(blob.clone(), image_end as u32 - 4)]).unwrap_err();
+        assert!(err.to_string().contains("flash image regions"), "{}", err);
+
+        /* Two regions overlapping each other */
+        let mut flash = flash_with_one_image();
+        let err =
+            splice_flash_regions(&mut flash, &[(blob.clone(), 0x102), (blob, 0x100)]).unwrap_err();
+        assert!(err.to_string().contains("overlaps"), "{}", err);
+    }
+
+    #[test]
+    fn extra_regions_need_a_valid_flash_image() {
+        let regions = [(PathBuf::from("unused.bin"), 0x100)];
+        assert!(splice_flash_regions(&mut vec![0u8; 4], &regions).is_err());
+
+        let mut flash = flash_with_one_image();
+        flash[..4].copy_from_slice(b"XXXX");
+        let err = splice_flash_regions(&mut flash, &regions).unwrap_err();
+        assert!(err.to_string().contains("magic"), "{}", err);
+    }
+
+    #[test]
+    fn flash_digests_are_matched_by_fw_id() {
+        let images = [vec![0x11u8; 8], vec![0x22u8; 8]];
+        let mut manifest = vec![0u8; ASPEED_AUTH_MANIFEST_SIZE];
+        manifest[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        let col = size_of::<AspeedAuthManifestPreamble>();
+        manifest[col..col + 4].copy_from_slice(&2u32.to_le_bytes());
+        for (i, (id, image)) in [1u32, 2].into_iter().zip(&images).enumerate() {
+            let entry = col + 4 + i * 56;
+            manifest[entry..entry + 4].copy_from_slice(&id.to_le_bytes());
+            manifest[entry + 8..entry + 56].copy_from_slice(&Sha384::digest(image));
+        }
+        let verify = |flash: &[u8], fw_ids: &[u32]| {
+            verify_flash_digests(
+                Path::new("flash.bin"),
+                flash,
+                fw_ids,
+                &BTreeMap::new(),
+                &BTreeMap::new(),
+                AUTH_MANIFEST_MAGIC,
+            )
+        };
+
+        let caliptra_fw = vec![0xcc; 16];
+        let flash = flash_with_images(&[
+            caliptra_fw.clone(),
+            manifest.clone(),
+            images[0].clone(),
+            images[1].clone(),
+        ]);
+        assert!(verify(&flash, &[1, 2]).is_ok());
+
+        /* Both digests are in the manifest, but under the other fw_id */
+        let swapped =
+            flash_with_images(&[caliptra_fw, manifest, images[1].clone(), images[0].clone()]);
+        let err = verify(&swapped, &[1, 2]).unwrap_err();
+        assert!(err.to_string().contains("fw_id 1, fw_id 2"), "{}", err);
+
+        /* An image region missing from the flash */
+        assert!(verify(&flash, &[1]).is_err());
+    }
+
+    #[test]
+    fn vendor_signature_follows_the_vendor_key() {
+        let path = PathBuf::from("manifest.bin");
+        let mut img = vec![0u8; ASPEED_AUTH_MANIFEST_SIZE];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        let mut man =
+            AspeedAuthorizationManifest::from_img(&path, img, AUTH_MANIFEST_MAGIC).unwrap();
+        let mut cfg = config::AspeedAuthManifestConfigFromFile::default();
+
+        /* Flagged as vendor signed without a vendor manifest key */
+        cfg.manifest_config.flags = config::ManifestFlags::VENDOR_SIGNATURE_REQUIRED;
+        assert!(man.validate_signatures(&cfg, false).is_err());
+
+        /* A configured vendor key needs its signature, whatever the flags */
+        cfg.vendor_man_key_config.ecc_pub_key = Some("vnd-man-ecc-pubk.pem".to_string());
+        assert!(man.validate_signatures(&cfg, false).is_err());
+        cfg.manifest_config.flags = config::ManifestFlags::empty();
+        assert!(man.validate_signatures(&cfg, false).is_err());
+
+        man.preamble.vnd_manifest_ecc_sig[0] = 1;
+        assert!(man.validate_signatures(&cfg, false).is_ok());
+        cfg.manifest_config.flags = config::ManifestFlags::VENDOR_SIGNATURE_REQUIRED;
+        assert!(man.validate_signatures(&cfg, false).is_ok());
+
+        cfg.vendor_man_key_config.ecc_pub_key = None;
+        cfg.manifest_config.flags = config::ManifestFlags::empty();
+        man.preamble.vnd_manifest_ecc_sig[0] = 0;
+        assert!(man.validate_signatures(&cfg, false).is_ok());
+    }
+
+    #[test]
+    fn manifest_layout_sizes() {
+        const CALIPTRA_PREAMBLE_SIZE: usize = 7168;
+        const ASPEED_PREAMBLE_SIZE: usize = 8888;
+        const METADATA_COLLECTION_SIZE: usize = 4 + IMAGE_METADATA_MAX_COUNT * 56;
+
+        assert_eq!(
+            ManifestLayout::Caliptra.metadata_offset(),
+            CALIPTRA_PREAMBLE_SIZE
+        );
+        assert_eq!(
+            ManifestLayout::Aspeed.metadata_offset(),
+            ASPEED_PREAMBLE_SIZE
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("manifest.bin");
+        let mut img = vec![0u8; CALIPTRA_PREAMBLE_SIZE + METADATA_COLLECTION_SIZE];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        std::fs::write(&path, &img).unwrap();
+        let man = AspeedAuthorizationManifest::new(&path).unwrap();
+
+        let caliptra = man.to_image(ManifestLayout::Caliptra);
+        assert_eq!(
+            caliptra.len(),
+            CALIPTRA_PREAMBLE_SIZE + METADATA_COLLECTION_SIZE
+        );
+        assert_eq!(
+            caliptra[4..8],
+            (CALIPTRA_PREAMBLE_SIZE as u32).to_le_bytes()
+        );
+
+        let aspeed = man.to_image(ManifestLayout::Aspeed);
+        assert_eq!(
+            aspeed.len(),
+            ASPEED_PREAMBLE_SIZE + METADATA_COLLECTION_SIZE
+        );
+        assert_eq!(aspeed.len(), ASPEED_AUTH_MANIFEST_SIZE);
+        assert_eq!(aspeed[4..8], (ASPEED_PREAMBLE_SIZE as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn compare_metadata_reports_per_fw_id() {
+        let mut img = vec![0u8; ASPEED_AUTH_MANIFEST_SIZE];
+        img[..4].copy_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+        let col = layout::metadata_offset();
+        img[col..col + 4].copy_from_slice(&2u32.to_le_bytes());
+        for (i, id) in [1u32, 2].into_iter().enumerate() {
+            let entry = col + 4 + i * 56;
+            img[entry..entry + 4].copy_from_slice(&id.to_le_bytes());
+            img[entry + 8..entry + 56].fill(id as u8);
+        }
+        let man =
+            AspeedAuthorizationManifest::from_img(Path::new("m.bin"), img, AUTH_MANIFEST_MAGIC)
+                .unwrap();
+
+        let expected = |fw_id: u32, fill: u8| config::ImageMetadataConfigFromFile {
+            digest: hex::encode([fill; 48]),
+            fw_id,
+            ..Default::default()
+        };
+        let names = BTreeMap::from([(2, "u-boot".to_string())]);
+        assert!(man
+            .compare_metadata(&[expected(1, 1), expected(2, 2)], &names)
+            .is_empty());
+
+        let mismatches = man.compare_metadata(
+            &[expected(1, 1), expected(3, 3), expected(2, 9)],
+            &BTreeMap::new(),
+        );
+        assert_eq!(mismatches.len(), 2, "{:?}", mismatches);
+        assert_eq!(mismatches[0], "fw_id 3: missing from the manifest");
+        assert!(mismatches[1].starts_with("fw_id 2: digest manifest 0202"));
+
+        let mismatches = man.compare_metadata(&[expected(1, 1)], &names);
+        assert_eq!(mismatches, ["fw_id 2 \"u-boot\": not in the config"]);
+    }
+
+    #[test]
+    fn ecc_pub_key_from_public_or_private_pem() {
+        use p384::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let secret = p384::SecretKey::from_slice(&[0x11; 48]).unwrap();
+        let public = tmp.path().join("pubk.pem");
+        let private = tmp.path().join("prvk.pem");
+        std::fs::write(
+            &public,
+            secret
+                .public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            &private,
+            secret.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+
+        let pubk = ecc_pub_key_to_hw(&public).unwrap();
+        assert_eq!(pubk, ecc_pub_key_to_hw(&private).unwrap());
+
+        /* x || y with every word byte swapped */
+        let point = secret.public_key().to_encoded_point(false);
+        let x = &point.as_bytes()[1..5];
+        assert_eq!(pubk[..4], [x[3], x[2], x[1], x[0]]);
+
+        std::fs::write(&public, "not a key").unwrap();
+        assert!(ecc_pub_key_to_hw(&public).is_err());
+    }
+
+    #[test]
+    fn shipped_ecc_keys_give_a_hw_key() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("key/ast2700-default");
+        for prefix in ["vnd-man", "vnd-fw", "own-man", "own-fw"] {
+            for kind in ["pubk", "prvk"] {
+                let file = dir.join(format!("{}-ecc-{}.pem", prefix, kind));
+                assert!(ecc_pub_key_to_hw(&file).is_ok(), "{}", file.display());
+            }
+        }
+
+        /* EC PARAMETERS block ahead of the SEC1 key */
+        assert_eq!(
+            ecc_pub_key_to_hw(&dir.join("vnd-man-ecc-prvk.pem")).unwrap(),
+            ecc_pub_key_to_hw(&dir.join("vnd-man-ecc-pubk.pem")).unwrap()
+        );
     }
 }
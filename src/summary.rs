@@ -0,0 +1,156 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   summary.rs
+
+Abstract:
+
+    File contains the result summary printed at the end of a successful build
+
+--*/
+
+use crate::config::{AspeedAuthManifestConfigFromFile, AspeedManifestCreationPath};
+use crate::error::{IoContext, Result};
+use crate::soc_man::AspeedAuthorizationManifest;
+use crate::utility::PathBufExt;
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Debug)]
+pub(crate) struct FileSummary {
+    path: PathBuf,
+
+    size: u64,
+}
+
+impl FileSummary {
+    fn new(path: &Path) -> Result<Self> {
+        let size = std::fs::metadata(path)
+            .io_context(|| format!("Failed to stat {}", path.display()))?
+            .len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size,
+        })
+    }
+}
+
+/// Outputs of a create-auth-man or create-auth-flash run, unlike
+/// `show_important_cfg_path` which prints the inputs before the build.
+#[derive(Serialize, Debug)]
+pub(crate) struct RunSummary {
+    config: PathBuf,
+
+    project: String,
+
+    manifest: FileSummary,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flash: Option<FileSummary>,
+
+    images: usize,
+
+    security_version: u32,
+
+    /* Vendor signatures overlaid from the prebuilt signature files */
+    prebuilt_signatures: Vec<&'static str>,
+}
+
+impl RunSummary {
+    /// Summarize the manifest at `path.manifest`, `man` is the manifest just
+    /// built. A manifest that wasn't rebuilt is read back from disk, counts
+    /// fall back to the config when it isn't in the aspeed layout.
+    pub(crate) fn new(
+        path: &AspeedManifestCreationPath,
+        cfg: &AspeedAuthManifestConfigFromFile,
+        man: Option<&AspeedAuthorizationManifest>,
+    ) -> Result<Self> {
+        let manifest = path.manifest.unwrap_or_err();
+        let loaded = match man {
+            Some(_) => None,
+            None => AspeedAuthorizationManifest::open(&manifest, cfg.expected_magic()).ok(),
+        };
+
+        let (images, security_version, prebuilt_signatures) = match man.or(loaded.as_ref()) {
+            Some(man) => (
+                man.metadata().count(),
+                man.sec_ver(),
+                man.prebuilt_signatures(),
+            ),
+            None => (
+                cfg.image_metadata_list.len(),
+                cfg.manifest_config.security_version,
+                Vec::new(),
+            ),
+        };
+
+        Ok(Self {
+            config: path.aspeed_cfg.clone(),
+            project: cfg.project_name(),
+            manifest: FileSummary::new(&manifest)?,
+            flash: path
+                .flash_image
+                .as_deref()
+                .map(FileSummary::new)
+                .transpose()?,
+            images,
+            security_version,
+            prebuilt_signatures,
+        })
+    }
+
+    pub(crate) fn manifest_path(&self) -> &Path {
+        &self.manifest.path
+    }
+
+    pub(crate) fn flash_path(&self) -> Option<&Path> {
+        self.flash.as_ref().map(|flash| flash.path.as_path())
+    }
+
+    /// Replace the flash size taken from the file system, which is meaningless
+    /// for a FIFO or device node.
+    pub(crate) fn with_flash_size(mut self, size: u64) -> Self {
+        if let Some(flash) = self.flash.as_mut() {
+            flash.size = size;
+        }
+        self
+    }
+
+    /// Print the summary, as one JSON object on the last line of stdout for
+    /// `--format json`.
+    pub(crate) fn print(&self, json: bool) {
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string(self).expect("summary serializes to JSON")
+            );
+            return;
+        }
+
+        let signatures = if self.prebuilt_signatures.is_empty() {
+            "<None>".to_string()
+        } else {
+            self.prebuilt_signatures.join(", ")
+        };
+
+        println!("-----------------------------------------------------------------------------------------------------------");
+        println!("config : {}", self.config.display());
+        println!("project : {}", self.project);
+        println!(
+            "manifest : {} ({} bytes)",
+            self.manifest.path.display(),
+            self.manifest.size
+        );
+        if let Some(flash) = &self.flash {
+            println!("flash : {} ({} bytes)", flash.path.display(), flash.size);
+        }
+        println!("images : {}", self.images);
+        println!("security_version : {}", self.security_version);
+        println!("prebuilt_signatures : {}", signatures);
+        println!("-----------------------------------------------------------------------------------------------------------");
+    }
+}
@@ -0,0 +1,362 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   tool.rs
+
+Abstract:
+
+    File contains helpers for running the external caliptra tools
+
+--*/
+
+use crate::error::{ImgtoolError, IoContext, Result};
+use clap::ArgMatches;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/* Initial delay before retrying a failed spawn, doubled on every attempt */
+const SPAWN_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/* `--version` output per tool, the tools are only probed once per run */
+static TOOL_VERSIONS: Lazy<Mutex<BTreeMap<PathBuf, String>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Default, Debug, Clone)]
+pub(crate) struct ToolOptions {
+    /* Number of extra spawn attempts when the tool fails to start */
+    pub retries: u32,
+
+    /* Overall limit of a call, including the spawn retries, the tool is killed at it */
+    pub timeout: Option<Duration>,
+
+    /* Send the tool's stdout to stderr, stdout carries the --format json output */
+    pub stdout_to_stderr: bool,
+}
+
+impl ToolOptions {
+    pub(crate) fn new(args: &ArgMatches) -> Self {
+        let retries = args
+            .try_get_one::<u32>("tool-retries")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(0);
+        let timeout = args
+            .try_get_one::<u64>("tool-timeout")
+            .ok()
+            .flatten()
+            .map(|secs| Duration::from_secs(*secs));
+        let stdout_to_stderr = args
+            .try_get_one::<String>("format")
+            .ok()
+            .flatten()
+            .is_some_and(|format| format == "json");
+
+        Self {
+            retries,
+            timeout,
+            stdout_to_stderr,
+        }
+    }
+}
+
+/// Runs the external caliptra tools. The pipeline only talks to the tools
+/// through this trait, so a test double can stand in for the real binaries.
+pub(crate) trait ToolRunner {
+    fn run(&self, program: &Path, args: &[OsString], cwd: Option<&Path>) -> Result<ExitStatus>;
+
+    /// Like `run`, also returning what the tool printed to stdout.
+    fn run_captured(
+        &self,
+        program: &Path,
+        args: &[OsString],
+        cwd: Option<&Path>,
+    ) -> Result<(ExitStatus, Vec<u8>)>;
+}
+
+/// Collect tool arguments into the owned form taken by `ToolRunner::run`.
+pub(crate) fn tool_args<I, S>(args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    args.into_iter().map(|a| a.as_ref().to_owned()).collect()
+}
+
+/// Spawns the real tool binaries with the retry and timeout policy from `ToolOptions`.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct RealToolRunner {
+    opts: ToolOptions,
+}
+
+impl RealToolRunner {
+    pub(crate) fn new(args: &ArgMatches) -> Self {
+        Self {
+            opts: ToolOptions::new(args),
+        }
+    }
+}
+
+impl ToolRunner for RealToolRunner {
+    fn run(&self, program: &Path, args: &[OsString], cwd: Option<&Path>) -> Result<ExitStatus> {
+        run_tool(program, args, cwd, &self.opts, false).map(|(status, _)| status)
+    }
+
+    fn run_captured(
+        &self,
+        program: &Path,
+        args: &[OsString],
+        cwd: Option<&Path>,
+    ) -> Result<(ExitStatus, Vec<u8>)> {
+        run_tool(program, args, cwd, &self.opts, true)
+    }
+}
+
+/// Test double for the tools: records the argv of every call and writes the
+/// canned output of the subcommand (first argument) to its `--out`/`--output`.
+#[cfg(test)]
+#[derive(Default, Debug)]
+pub(crate) struct RecordingRunner {
+    pub calls: std::cell::RefCell<Vec<Vec<String>>>,
+    pub outputs: BTreeMap<String, Vec<u8>>,
+}
+
+#[cfg(test)]
+impl ToolRunner for RecordingRunner {
+    fn run(&self, program: &Path, args: &[OsString], cwd: Option<&Path>) -> Result<ExitStatus> {
+        self.run_captured(program, args, cwd)
+            .map(|(status, _)| status)
+    }
+
+    /* The canned output of a call without `--out` is its stdout */
+    fn run_captured(
+        &self,
+        program: &Path,
+        args: &[OsString],
+        _cwd: Option<&Path>,
+    ) -> Result<(ExitStatus, Vec<u8>)> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let argv = args
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let out = argv
+            .iter()
+            .position(|a| a == "--out" || a == "--output")
+            .and_then(|i| argv.get(i + 1));
+        let output = argv.first().and_then(|cmd| self.outputs.get(cmd));
+        let stdout = match (out, output) {
+            (Some(out), Some(output)) => {
+                std::fs::write(out, output).io_context(|| format!("Failed to write {}", out))?;
+                Vec::new()
+            }
+            (None, Some(output)) => output.clone(),
+            _ => Vec::new(),
+        };
+
+        self.calls.borrow_mut().push(
+            std::iter::once(program.display().to_string())
+                .chain(argv)
+                .collect(),
+        );
+        Ok((ExitStatus::from_raw(0), stdout))
+    }
+}
+
+/// Run `program --version` through `runner` and return what it printed to
+/// stdout. The output is remembered, later calls for the same program don't run
+/// it again.
+pub(crate) fn query_version(runner: &dyn ToolRunner, program: &Path) -> Result<String> {
+    if let Some(version) = TOOL_VERSIONS.lock().unwrap().get(program) {
+        return Ok(version.clone());
+    }
+
+    let (status, stdout) = runner.run_captured(program, &tool_args(["--version"]), None)?;
+    if !status.success() {
+        return Err(ImgtoolError::ToolFailed {
+            program: program.to_path_buf(),
+            status,
+        });
+    }
+
+    let version = String::from_utf8_lossy(&stdout).into_owned();
+    TOOL_VERSIONS
+        .lock()
+        .unwrap()
+        .insert(program.to_path_buf(), version.clone());
+    Ok(version)
+}
+
+/// Spawn an external tool and wait for it to exit, retrying a failed spawn. The
+/// optional timeout from `opts` bounds the whole call, retries included. With
+/// `capture` the tool's stdout is returned, otherwise it is inherited.
+fn run_tool(
+    program: &Path,
+    args: &[OsString],
+    cwd: Option<&Path>,
+    opts: &ToolOptions,
+    capture: bool,
+) -> Result<(ExitStatus, Vec<u8>)> {
+    debug!("Running {:?} {:?}", program, args);
+
+    let deadline = opts
+        .timeout
+        .map(|timeout| (Instant::now() + timeout, timeout));
+    let timed_out = |timeout| ImgtoolError::ToolTimeout {
+        program: program.to_path_buf(),
+        timeout,
+    };
+
+    let mut attempt = 0;
+    let mut child = loop {
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        if let Some(dir) = cwd {
+            cmd.current_dir(dir);
+        }
+        if capture {
+            cmd.stdout(Stdio::piped());
+        } else if opts.stdout_to_stderr {
+            cmd.stdout(std::io::stderr());
+        }
+
+        match cmd.spawn() {
+            Ok(child) => break child,
+            Err(e) if attempt < opts.retries => {
+                let backoff = SPAWN_RETRY_BACKOFF * 2u32.pow(attempt.min(6));
+                if let Some((deadline, timeout)) = deadline {
+                    if Instant::now() + backoff >= deadline {
+                        warn!("Failed to start {:?}: {}", program, e);
+                        return Err(timed_out(timeout));
+                    }
+                }
+                attempt += 1;
+                warn!(
+                    "Failed to start {:?}: {}, retry {}/{} in {:?}",
+                    program, e, attempt, opts.retries, backoff
+                );
+                std::thread::sleep(backoff);
+            }
+            Err(e) => {
+                return Err(ImgtoolError::Io {
+                    context: format!("Failed to execute {:?}", program),
+                    source: e,
+                })
+            }
+        }
+    };
+
+    /* Drain stdout while the tool runs, a full pipe would block it */
+    let stdout = child.stdout.take().map(|mut out| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            out.read_to_end(&mut buf).map(|_| buf)
+        })
+    });
+    let collect = |status| -> Result<(ExitStatus, Vec<u8>)> {
+        let out = match stdout {
+            Some(reader) => reader
+                .join()
+                .expect("stdout reader panicked")
+                .io_context(|| format!("Failed to read the output of {:?}", program))?,
+            None => Vec::new(),
+        };
+        Ok((status, out))
+    };
+
+    let Some((deadline, timeout)) = deadline else {
+        let status = child
+            .wait()
+            .io_context(|| format!("Failed to wait on {:?}", program))?;
+        return collect(status);
+    };
+
+    loop {
+        if let Some(status) = child
+            .try_wait()
+            .io_context(|| format!("Failed to wait on {:?}", program))?
+        {
+            return collect(status);
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(timed_out(timeout));
+        }
+
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_bounds_the_spawn_retries() {
+        let opts = ToolOptions {
+            retries: 5,
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let err = run_tool(Path::new("/nonexistent/tool"), &[], None, &opts, false).unwrap_err();
+
+        /* The retry backoff alone would take 15.5s */
+        assert!(matches!(err, ImgtoolError::ToolTimeout { .. }), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn timeout_kills_a_hung_tool() {
+        let opts = ToolOptions {
+            retries: 0,
+            timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let err = run_tool(Path::new("sleep"), &tool_args(["10"]), None, &opts, false).unwrap_err();
+        assert!(matches!(err, ImgtoolError::ToolTimeout { .. }), "{}", err);
+    }
+
+    #[test]
+    fn captured_output_is_returned() {
+        let (status, out) = run_tool(
+            Path::new("echo"),
+            &tool_args(["1.2.3"]),
+            None,
+            &ToolOptions::default(),
+            true,
+        )
+        .unwrap();
+        assert!(status.success());
+        assert_eq!(out, b"1.2.3\n");
+    }
+
+    #[test]
+    fn version_is_queried_through_the_runner_once() {
+        let runner = RecordingRunner {
+            outputs: BTreeMap::from([("--version".to_string(), b"tool 1.2.3\n".to_vec())]),
+            ..Default::default()
+        };
+        let program = Path::new("/version-test/tool");
+        assert_eq!(query_version(&runner, program).unwrap(), "tool 1.2.3\n");
+        assert_eq!(query_version(&runner, program).unwrap(), "tool 1.2.3\n");
+        assert_eq!(
+            runner.calls.into_inner(),
+            [["/version-test/tool", "--version"]]
+        );
+    }
+}
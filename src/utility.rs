@@ -12,7 +12,12 @@ Abstract:
 
 --*/
 
-use std::path::PathBuf;
+use clap::ArgMatches;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 pub trait PathBufExt {
     fn unwrap_or_def(&self, default: PathBuf) -> PathBuf;
@@ -33,10 +38,7 @@ impl PathBufExt for Option<PathBuf> {
     }
 
     fn to_string(&self) -> String {
-        self.as_ref()
-            .and_then(|p| p.to_str())
-            .expect("Unknown path format")
-            .to_string()
+        path_to_string(self.as_ref().expect("Unknown path format"))
     }
 }
 
@@ -50,6 +52,192 @@ impl PathBufExt for PathBuf {
     }
 
     fn to_string(&self) -> String {
-        self.to_str().expect("Unknown path format").to_string()
+        path_to_string(self)
+    }
+}
+
+/* Non UTF-8 paths are converted lossily instead of panicking, so warn that the
+ * resulting string may not name the original file.
+ */
+fn path_to_string(path: &Path) -> String {
+    let s = path.to_string_lossy();
+    if path.to_str().is_none() {
+        print_warning(&format!("Path {:?} is not valid UTF-8, using {}", path, s));
+    }
+    s.into_owned()
+}
+
+/// Temp file next to `path`, written first and renamed over `path` once complete.
+/// Same directory, so the rename never crosses file systems.
+pub fn staging_path(path: &Path) -> PathBuf {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}.partial", std::process::id()));
+    PathBuf::from(staging)
+}
+
+/// True for an existing path that is neither a regular file nor a directory,
+/// e.g. a FIFO or a device node. Such outputs are written in place, never replaced.
+pub fn is_special_file(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| !m.is_file() && !m.is_dir())
+}
+
+/// Identifier of the file system holding `path`, to tell whether two paths share
+/// free space. None when unknown.
+#[cfg(unix)]
+pub fn filesystem_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+pub fn filesystem_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Write `data` through a staging file renamed into place, so readers never see
+/// a half-written file under `path`.
+pub fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let staging = staging_path(path);
+    let result = fs::File::create(&staging)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&staging, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    result
+}
+
+/// Set the permissions of a generated file: `mode` when given, then the write
+/// bits cleared for `read_only`.
+#[cfg(unix)]
+pub fn set_artifact_permissions(
+    path: &Path,
+    mode: Option<u32>,
+    read_only: bool,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path)?.permissions();
+    if let Some(mode) = mode {
+        perms.set_mode(mode);
+    }
+    if read_only {
+        perms.set_mode(perms.mode() & !0o222);
+    }
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+pub fn set_artifact_permissions(
+    path: &Path,
+    mode: Option<u32>,
+    read_only: bool,
+) -> std::io::Result<()> {
+    if mode.is_some() {
+        print_warning(&format!(
+            "File modes are not supported on this platform, {} keeps its permissions",
+            path.display()
+        ));
+    }
+    if read_only {
+        let mut perms = fs::metadata(path)?.permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(path, perms)?;
+    }
+    Ok(())
+}
+
+/// Worker threads from `--threads`, the number of logical CPUs when not given.
+pub fn thread_count(args: &ArgMatches) -> usize {
+    args.try_get_one::<usize>("threads")
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1)
+}
+
+/// Map `f` over `items` on up to `threads` worker threads, keeping the item
+/// order. One thread runs everything in order on the calling thread.
+pub fn parallel_map<T, R, E, F>(items: &[T], threads: usize, f: F) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+    F: Fn(usize, &T) -> Result<R, E> + Sync,
+{
+    if threads <= 1 || items.len() <= 1 {
+        return items.iter().enumerate().map(|(i, t)| f(i, t)).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|s| {
+        for _ in 0..threads.min(items.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                let result = f(i, item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item is processed"))
+        .collect()
+}
+
+/// Print a warning that must be visible without RUST_LOG, in the style of the
+/// red path errors the CLI prints.
+pub fn print_warning(msg: &str) {
+    eprintln!("\x1b[33;1mWarning: {}\x1b[0m", msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_ascii_path_is_kept() {
+        let path = Path::new("prebuilt")
+            .join("ast2700-固件")
+            .join("u-böot.bin");
+        assert_eq!(
+            path.to_string(),
+            format!(
+                "prebuilt{0}ast2700-固件{0}u-böot.bin",
+                std::path::MAIN_SEPARATOR
+            )
+        );
+        assert_eq!(Some(path.clone()).to_string(), path.to_string());
+
+        let staging = staging_path(&path);
+        assert_eq!(staging.parent(), path.parent());
+        assert!(staging.to_string().starts_with(&path.to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_path_is_converted_lossily() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = PathBuf::from(std::ffi::OsStr::from_bytes(b"prebuilt/fw-\xff.bin"));
+        assert_eq!(path.to_string(), "prebuilt/fw-\u{fffd}.bin");
+    }
+
+    #[test]
+    fn write_atomic_to_a_non_ascii_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("輸出");
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("mänifest.bin");
+
+        write_atomic(&path, b"manifest").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"manifest");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
     }
 }
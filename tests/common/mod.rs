@@ -0,0 +1,208 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   mod.rs
+
+Abstract:
+
+    Fixtures and stub caliptra tools shared by the integration tests
+
+--*/
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const AUTH_MANIFEST_MAGIC: u32 = 0x4154_4D4E;
+pub const CALIPTRA_PREAMBLE_SIZE: usize = 7168;
+pub const ASPEED_PREAMBLE_SIZE: usize = 8888;
+pub const IMAGE_METADATA_MAX_COUNT: usize = 127;
+pub const IMAGE_METADATA_SIZE: usize = 56;
+pub const ECC384_SIG_SIZE: usize = 96;
+pub const SVN_SIG_SIZE: usize = ECC384_SIG_SIZE + 1620;
+
+/* Offset of owner_manifest_svn_ecc_sig in the aspeed preamble */
+pub const SVN_ECC_SIG_OFFSET: usize = 3740;
+
+/* Test double for caliptra-auth-manifest-app: copy the recorded output of the
+ * subcommand to the --out path.
+ */
+const MANIFEST_TOOL_STUB: &str = r#"#!/bin/sh
+cmd=$1
+out=
+while [ $# -gt 0 ]; do
+    [ "$1" = "--out" ] && out=$2
+    shift
+done
+cp "$(dirname "$0")/recorded/$cmd.bin" "$out"
+"#;
+
+/* Test double for `xtask flash-image create`: a flash image header, one image
+ * info per input in argument order, then the inputs each padded to 4 bytes.
+ * The arguments are logged to xtask.args, a --soc-images without values fails
+ * like the real argument parser.
+ */
+const XTASK_STUB: &str = r#"#!/bin/sh
+u32le() {
+    for shift in 0 8 16 24; do
+        printf "\\$(printf '%03o' $(( ($1 >> shift) & 255 )))"
+    done
+}
+u16le() {
+    for shift in 0 8; do
+        printf "\\$(printf '%03o' $(( ($1 >> shift) & 255 )))"
+    done
+}
+
+echo "$@" > "$(dirname "$0")/xtask.args"
+
+out=
+images=
+while [ $# -gt 0 ]; do
+    case "$1" in
+    --caliptra-fw|--soc-manifest|--mcu-runtime) images="$images $2"; shift 2 ;;
+    --output) out=$2; shift 2 ;;
+    --soc-images)
+        shift
+        if [ $# -eq 0 ] || [ "${1#--}" != "$1" ]; then
+            echo "error: --soc-images requires a value" >&2
+            exit 2
+        fi
+        while [ $# -gt 0 ] && [ "${1#--}" = "$1" ]; do
+            images="$images $1"
+            shift
+        done
+        ;;
+    *) shift ;;
+    esac
+done
+
+count=$(echo $images | wc -w)
+offset=$((16 + count * 16))
+{
+    u32le 0x464C5348; u16le 1; u16le $count; u32le 16; u32le 0
+    id=0
+    for f in $images; do
+        size=$(wc -c < "$f")
+        u32le $id; u32le $offset; u32le $size; u32le 0
+        offset=$(( (offset + size + 3) / 4 * 4 ))
+        id=$((id + 1))
+    done
+    for f in $images; do
+        cat "$f"
+        size=$(wc -c < "$f")
+        head -c $(( (4 - size % 4) % 4 )) /dev/zero
+    done
+} > "$out"
+"#;
+
+pub fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden")
+}
+
+/* Same bytes as the binary's selftest::pattern, which integration tests can't reach */
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect::<Vec<_>>()
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/* Recorded caliptra manifest: caliptra preamble followed by the metadata
+ * collection holding the (fw_id, digest) entries.
+ */
+pub fn recorded_caliptra_manifest(entries: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut img = Vec::new();
+    img.extend_from_slice(&AUTH_MANIFEST_MAGIC.to_le_bytes());
+    img.extend_from_slice(&(CALIPTRA_PREAMBLE_SIZE as u32).to_le_bytes());
+    img.extend_from_slice(&1u32.to_le_bytes());
+    img.extend_from_slice(&0u32.to_le_bytes());
+    img.extend(pattern(CALIPTRA_PREAMBLE_SIZE - img.len(), 0x5a));
+
+    img.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (id, digest) in entries {
+        img.extend_from_slice(&id.to_le_bytes());
+        img.extend_from_slice(&0u32.to_le_bytes());
+        img.extend_from_slice(digest);
+    }
+    img.resize(
+        CALIPTRA_PREAMBLE_SIZE + 4 + IMAGE_METADATA_MAX_COUNT * IMAGE_METADATA_SIZE,
+        0,
+    );
+    img
+}
+
+fn write_script(path: &Path, script: &str) {
+    fs::write(path, script).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/* Stub tools in `dir`, the manifest tool returning `manifest` */
+pub fn write_tool_dir(dir: &Path, manifest: &[u8]) {
+    let recorded = dir.join("recorded");
+    fs::create_dir_all(&recorded).unwrap();
+    fs::write(recorded.join("create-aspeed-auth-man.bin"), manifest).unwrap();
+    fs::write(
+        recorded.join("create-sig-svn.bin"),
+        pattern(SVN_SIG_SIZE, 0xa5),
+    )
+    .unwrap();
+
+    write_script(&dir.join("caliptra-auth-manifest-app"), MANIFEST_TOOL_STUB);
+    write_script(&dir.join("xtask"), XTASK_STUB);
+}
+
+/* The golden config with only the first `images` image_metadata_list entries,
+ * written to `dir`.
+ */
+pub fn golden_cfg_with_images(dir: &Path, images: usize) -> PathBuf {
+    let golden = fs::read_to_string(fixture_dir().join("golden-manifest.toml")).unwrap();
+    let mut sections = golden.split("[[image_metadata_list]]");
+    let mut cfg = sections.next().unwrap().to_string();
+    for image in sections.take(images) {
+        cfg.push_str("[[image_metadata_list]]");
+        cfg.push_str(image);
+    }
+
+    let path = dir.join("golden-manifest.toml");
+    fs::write(&path, cfg).unwrap();
+    path
+}
+
+/* `cmd` with the golden config, keys and prebuilt images */
+pub fn imgtool(cmd: &str) -> Command {
+    imgtool_with_cfg(cmd, &fixture_dir().join("golden-manifest.toml"))
+}
+
+/* `cmd` with the config `cfg` and the golden keys and prebuilt images */
+pub fn imgtool_with_cfg(cmd: &str, cfg: &Path) -> Command {
+    let fixtures = fixture_dir();
+    let mut imgtool = Command::new(env!("CARGO_BIN_EXE_cptra-imgtool"));
+    imgtool
+        .arg(cmd)
+        .arg("--cfg")
+        .arg(cfg)
+        .arg("--key-dir")
+        .arg(fixtures.join("key"))
+        .arg("--prebuilt-dir")
+        .arg(fixtures.join("prebuilt"))
+        .arg("--allow-unauthenticated");
+    imgtool
+}
+
+/* create-auth-man with the golden config and the stub tools in `tool_dir` */
+pub fn create_auth_man(tool_dir: &Path, manifest: &Path) -> Command {
+    let mut cmd = imgtool("create-auth-man");
+    cmd.arg("--man")
+        .arg(manifest)
+        .arg("--tool-dir")
+        .arg(tool_dir);
+    cmd
+}
@@ -0,0 +1,766 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   end_to_end.rs
+
+Abstract:
+
+    End-to-end tests running create-auth-man and create-auth-flash against stub
+    caliptra-auth-manifest-app and xtask binaries. The stub manifest carries the
+    real digests of the fixture images, so the flash image passes --strict-digest.
+
+--*/
+
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use sha2::{Digest, Sha384};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/* Images of the golden config by fw_id, fw_id 4 is the empty dummy image */
+const IMAGES: [(u32, &str); 4] = [
+    (1, "mcu-runtime.bin"),
+    (2, "ddr-train.bin"),
+    (3, "u-boot.bin"),
+    (4, ""),
+];
+
+/* Manifest digest of an image: SHA-384 of the file zero-padded to 4 bytes */
+fn image_digest(file: &str) -> Vec<u8> {
+    let mut data = match file {
+        "" => Vec::new(),
+        file => fs::read(fixture_dir().join("prebuilt").join(file)).unwrap(),
+    };
+    data.resize(data.len().next_multiple_of(4), 0);
+    Sha384::digest(&data).to_vec()
+}
+
+fn write_tools(dir: &Path) {
+    let entries = IMAGES
+        .iter()
+        .map(|(id, file)| (*id, image_digest(file)))
+        .collect::<Vec<_>>();
+    write_tool_dir(dir, &recorded_caliptra_manifest(&entries));
+}
+
+fn check_manifest(man: &[u8]) {
+    assert_eq!(
+        man.len(),
+        ASPEED_PREAMBLE_SIZE + 4 + IMAGE_METADATA_MAX_COUNT * IMAGE_METADATA_SIZE
+    );
+    assert_eq!(read_u32(man, 0), AUTH_MANIFEST_MAGIC);
+    assert_eq!(read_u32(man, 4), ASPEED_PREAMBLE_SIZE as u32);
+    /* sec_ver from the config, signed by the stub create-sig-svn */
+    assert_eq!(read_u32(man, 12), 3);
+    assert_eq!(
+        man[SVN_ECC_SIG_OFFSET..SVN_ECC_SIG_OFFSET + ECC384_SIG_SIZE],
+        pattern(SVN_SIG_SIZE, 0xa5)[..ECC384_SIG_SIZE]
+    );
+
+    let col = ASPEED_PREAMBLE_SIZE;
+    assert_eq!(read_u32(man, col) as usize, IMAGES.len());
+    for (i, (id, file)) in IMAGES.iter().enumerate() {
+        let entry = col + 4 + i * IMAGE_METADATA_SIZE;
+        assert_eq!(read_u32(man, entry), *id);
+        assert_eq!(
+            man[entry + 8..entry + IMAGE_METADATA_SIZE],
+            image_digest(file)[..],
+            "fw_id {}",
+            id
+        );
+    }
+}
+
+#[test]
+fn create_auth_man_end_to_end() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    let manifest = tmp.path().join("out").join("golden-auth-manifest.bin");
+    let output = create_auth_man(&tool_dir, &manifest)
+        .args(["--format", "json"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-man failed: {:?}",
+        output
+    );
+
+    check_manifest(&fs::read(&manifest).unwrap());
+
+    /* The caliptra config handed to the tool holds the image digests */
+    let caliptra_cfg =
+        fs::read_to_string(tmp.path().join("out").join("golden-caliptra-manifest.toml")).unwrap();
+    for (id, file) in IMAGES {
+        let digest = hex::encode(image_digest(file));
+        assert!(
+            caliptra_cfg.contains(&digest),
+            "fw_id {} digest missing",
+            id
+        );
+    }
+
+    /* In JSON mode stdout holds only the summary, the rest goes to stderr */
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["images"], IMAGES.len());
+    assert_eq!(summary["security_version"], 3);
+    assert_eq!(summary["project"], "golden");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("prebuilt_dir : "));
+
+    /* Also when the build is skipped */
+    let if_changed = || {
+        create_auth_man(&tool_dir, &manifest)
+            .args(["--format", "json", "--if-changed"])
+            .output()
+            .unwrap()
+    };
+    if_changed();
+    let output = if_changed();
+    assert!(output.status.success(), "{:?}", output);
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["images"], IMAGES.len());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("manifest up to date"));
+}
+
+#[test]
+fn create_auth_flash_json_output() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let output = imgtool("create-auth-flash")
+        .arg("--flash")
+        .arg(&flash)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .args(["--strict-digest", "--emit-checksum", "--format", "json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["images"], IMAGES.len());
+    assert_eq!(summary["flash"]["size"], fs::read(&flash).unwrap().len());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in ["flash_image : ", "Checksum written to", "content matches"] {
+        assert!(stderr.contains(line), "{}: {}", line, stderr);
+    }
+}
+
+#[test]
+fn failed_build_keeps_the_previous_manifest() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    let out = tmp.path().join("out");
+    let manifest = out.join("golden-auth-manifest.bin");
+    let status = create_auth_man(&tool_dir, &manifest).status().unwrap();
+    assert!(status.success(), "create-auth-man failed: {}", status);
+    let previous = fs::read(&manifest).unwrap();
+
+    /* A manifest with the wrong magic fails the post-processing */
+    let mut bad = previous.clone();
+    bad[0] ^= 0xff;
+    write_tool_dir(&tool_dir, &bad);
+    let status = create_auth_man(&tool_dir, &manifest).status().unwrap();
+    assert!(!status.success());
+
+    assert_eq!(fs::read(&manifest).unwrap(), previous);
+    let partial = fs::read_dir(&out)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".partial"))
+        .collect::<Vec<_>>();
+    assert!(partial.is_empty(), "{:?}", partial);
+}
+
+#[test]
+fn create_auth_flash_end_to_end() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    /* The tools are found through the environment instead of --tool-dir */
+    let flash = tmp.path().join("out").join("flash.bin");
+    let output = imgtool("create-auth-flash")
+        .arg("--flash")
+        .arg(&flash)
+        .args(["--strict-digest", "--emit-checksum"])
+        .env("CPTRA_IMGTOOL_TOOL_DIR", &tool_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-flash failed: {:?}",
+        output
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("Flash image content matches the manifest digests"),
+        "{}",
+        stdout
+    );
+
+    /* The manifest is built next to the flash image */
+    let manifest = fs::read(tmp.path().join("out").join("golden-auth-manifest.bin")).unwrap();
+    check_manifest(&manifest);
+
+    /* Caliptra firmware, manifest and MCU runtime, then the SoC images */
+    let img = fs::read(&flash).unwrap();
+    let prebuilt = |file: &str| fs::read(fixture_dir().join("prebuilt").join(file)).unwrap();
+    let regions = [
+        prebuilt("caliptra-fw.bin"),
+        manifest,
+        prebuilt("mcu-runtime.bin"),
+        prebuilt("ddr-train.bin"),
+        prebuilt("u-boot.bin"),
+        Vec::new(),
+    ];
+    assert_eq!(img[..4], *b"HSLF");
+    assert_eq!(u16::from_le_bytes([img[6], img[7]]) as usize, regions.len());
+    for (i, region) in regions.iter().enumerate() {
+        let info = read_u32(&img, 8) as usize + i * 16;
+        let (offset, size) = (read_u32(&img, info + 4) as usize, read_u32(&img, info + 8));
+        assert_eq!(size as usize, region.len(), "region {}", i);
+        assert_eq!(
+            img[offset..offset + region.len()],
+            region[..],
+            "region {}",
+            i
+        );
+    }
+
+    let checksum = fs::read_to_string(tmp.path().join("out").join("flash.bin.sha384")).unwrap();
+    assert_eq!(
+        checksum,
+        format!("{}  flash.bin\n", hex::encode(Sha384::digest(&img)))
+    );
+}
+
+#[test]
+fn mcu_runtime_override_is_in_the_manifest() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    let mcu_runtime = tmp.path().join("new-mcu-runtime.bin");
+    fs::write(&mcu_runtime, pattern(64, 0x3c)).unwrap();
+    let digest = Sha384::digest(pattern(64, 0x3c)).to_vec();
+
+    /* The stub manifest carries the override's digest under the MCU runtime fw_id */
+    let entries = IMAGES
+        .iter()
+        .map(|(id, file)| match id {
+            1 => (*id, digest.clone()),
+            _ => (*id, image_digest(file)),
+        })
+        .collect::<Vec<_>>();
+    write_tool_dir(&tool_dir, &recorded_caliptra_manifest(&entries));
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let output = imgtool("create-auth-flash")
+        .arg("--flash")
+        .arg(&flash)
+        .arg("--mcu-runtime")
+        .arg(&mcu_runtime)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .arg("--strict-digest")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-flash failed: {:?}",
+        output
+    );
+
+    /* The manifest tool was given the digest of the flashed MCU runtime */
+    let caliptra_cfg =
+        fs::read_to_string(tmp.path().join("out").join("golden-caliptra-manifest.toml")).unwrap();
+    assert!(
+        caliptra_cfg.contains(&hex::encode(&digest)),
+        "{}",
+        caliptra_cfg
+    );
+    assert!(!caliptra_cfg.contains(&hex::encode(image_digest("mcu-runtime.bin"))));
+}
+
+#[test]
+fn manifest_without_images() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tool_dir(&tool_dir, &recorded_caliptra_manifest(&[]));
+    let cfg = golden_cfg_with_images(tmp.path(), 0);
+
+    let manifest = tmp.path().join("out").join("golden-auth-manifest.bin");
+    let status = imgtool_with_cfg("create-auth-man", &cfg)
+        .arg("--man")
+        .arg(&manifest)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "create-auth-man failed: {}", status);
+
+    /* The caliptra tool requires the list, even an empty one */
+    let caliptra_cfg =
+        fs::read_to_string(tmp.path().join("out").join("golden-caliptra-manifest.toml")).unwrap();
+    assert!(
+        caliptra_cfg.contains("image_metadata_list = []"),
+        "{}",
+        caliptra_cfg
+    );
+
+    let man = fs::read(&manifest).unwrap();
+    assert_eq!(read_u32(&man, 0), AUTH_MANIFEST_MAGIC);
+    assert_eq!(read_u32(&man, ASPEED_PREAMBLE_SIZE), 0);
+}
+
+#[test]
+fn flash_with_only_the_mcu_runtime() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    let (id, file) = IMAGES[0];
+    write_tool_dir(
+        &tool_dir,
+        &recorded_caliptra_manifest(&[(id, image_digest(file))]),
+    );
+    let cfg = golden_cfg_with_images(tmp.path(), 1);
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let output = imgtool_with_cfg("create-auth-flash", &cfg)
+        .arg("--flash")
+        .arg(&flash)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .arg("--strict-digest")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-flash failed: {:?}",
+        output
+    );
+
+    /* No SoC images, so no --soc-images flag at all */
+    let args = fs::read_to_string(tool_dir.join("xtask.args")).unwrap();
+    assert!(!args.contains("--soc-images"), "{}", args);
+    let img = fs::read(&flash).unwrap();
+    assert_eq!(u16::from_le_bytes([img[6], img[7]]), 3);
+}
+
+#[test]
+fn flash_without_images() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tool_dir(&tool_dir, &recorded_caliptra_manifest(&[]));
+    let cfg = golden_cfg_with_images(tmp.path(), 0);
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let output = imgtool_with_cfg("create-auth-flash", &cfg)
+        .arg("--flash")
+        .arg(&flash)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .arg("--strict-digest")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-flash failed: {:?}",
+        output
+    );
+
+    /* Caliptra firmware, manifest and MCU runtime from image_runtime_list */
+    let args = fs::read_to_string(tool_dir.join("xtask.args")).unwrap();
+    assert!(!args.contains("--soc-images"), "{}", args);
+    let img = fs::read(&flash).unwrap();
+    assert_eq!(u16::from_le_bytes([img[6], img[7]]), 3);
+    let man = fs::read(tmp.path().join("out").join("golden-auth-manifest.bin")).unwrap();
+    assert_eq!(read_u32(&man, ASPEED_PREAMBLE_SIZE), 0);
+}
+
+#[test]
+fn artifact_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let manifest = tmp.path().join("out").join("golden-auth-manifest.bin");
+    /* The second run replaces the read-only files of the first */
+    for _ in 0..2 {
+        let output = imgtool("create-auth-flash")
+            .arg("--flash")
+            .arg(&flash)
+            .arg("--tool-dir")
+            .arg(&tool_dir)
+            .args(["--artifact-mode", "0640", "--artifact-read-only"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "create-auth-flash failed: {:?}",
+            output
+        );
+
+        for file in [&flash, &manifest] {
+            let mode = fs::metadata(file).unwrap().permissions().mode() & 0o7777;
+            assert_eq!(mode, 0o440, "{}", file.display());
+        }
+    }
+}
+
+#[test]
+fn post_build_hook_gets_the_outputs() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    /* The hook logs its arguments, and fails when asked to */
+    let hook = tmp.path().join("hook.sh");
+    let log = tmp.path().join("hook.log");
+    fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\necho \"$@\" > {}\n[ \"$3\" != fail ]\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let manifest = tmp.path().join("out").join("golden-auth-manifest.bin");
+    for (last_arg, success) in [("ok", true), ("fail", false)] {
+        let cfg = golden_cfg_with_images(tmp.path(), IMAGES.len());
+        let mut toml = fs::read_to_string(&cfg).unwrap();
+        toml.push_str(&format!(
+            "\n[post_build_hook]\nprogram = \"{}\"\nargs = [\"--flash={{flash}}\", \"{{manifest}}\", \"{}\"]\n",
+            hook.display(),
+            last_arg
+        ));
+        fs::write(&cfg, toml).unwrap();
+
+        let status = imgtool_with_cfg("create-auth-flash", &cfg)
+            .arg("--flash")
+            .arg(&flash)
+            .arg("--tool-dir")
+            .arg(&tool_dir)
+            .status()
+            .unwrap();
+        assert_eq!(status.success(), success, "{}", status);
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!(
+                "--flash={} {} {}\n",
+                flash.display(),
+                manifest.display(),
+                last_arg
+            )
+        );
+    }
+
+    /* create-auth-man has no flash image, the hook is rejected before the build */
+    let cfg = golden_cfg_with_images(tmp.path(), IMAGES.len());
+    let mut toml = fs::read_to_string(&cfg).unwrap();
+    toml.push_str(&format!(
+        "\n[post_build_hook]\nprogram = \"{}\"\nargs = [\"{{flash}}\"]\n",
+        hook.display()
+    ));
+    fs::write(&cfg, toml).unwrap();
+    let man = tmp.path().join("man-only.bin");
+    let output = imgtool_with_cfg("create-auth-man", &cfg)
+        .arg("--man")
+        .arg(&man)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("only available with create-auth-flash"),
+        "{}",
+        stderr
+    );
+    assert!(!man.exists());
+}
+
+#[test]
+fn paths_relative_to_the_config_from_another_directory() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tools(&tool_dir);
+
+    /* project/config/golden-manifest.toml next to project/prebuilt and project/key */
+    let project = tmp.path().join("project");
+    let cfg_dir = project.join("config");
+    fs::create_dir_all(&cfg_dir).unwrap();
+    for dir in ["prebuilt", "key"] {
+        std::os::unix::fs::symlink(fixture_dir().join(dir), project.join(dir)).unwrap();
+    }
+    let golden = fs::read_to_string(fixture_dir().join("golden-manifest.toml")).unwrap();
+    let cfg = golden.replace(
+        "prj_name = \"golden\"\n",
+        "prj_name = \"golden\"\nprebuilt_dir = \"../prebuilt\"\nkey_dir = \"../key\"\npaths_relative_to_config = true\n",
+    );
+    fs::write(cfg_dir.join("golden-manifest.toml"), cfg).unwrap();
+
+    /* Run from a sibling directory, without --prebuilt-dir and --key-dir */
+    let cwd = tmp.path().join("elsewhere");
+    fs::create_dir_all(&cwd).unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_cptra-imgtool"))
+        .current_dir(&cwd)
+        .args([
+            "create-auth-man",
+            "--cfg",
+            "../project/config/golden-manifest.toml",
+        ])
+        .args([
+            "--man",
+            "out/golden-auth-manifest.bin",
+            "--allow-unauthenticated",
+        ])
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-man failed: {:?}",
+        output
+    );
+
+    /* Output paths stay relative to the working directory */
+    check_manifest(&fs::read(cwd.join("out").join("golden-auth-manifest.bin")).unwrap());
+
+    /* Without prebuilt_dir and key_dir, the default prebuilt/golden/ and
+     * key/golden/ are next to the config too
+     */
+    let defaults_dir = project.join("defaults");
+    for dir in ["prebuilt", "key"] {
+        fs::create_dir_all(defaults_dir.join(dir)).unwrap();
+        std::os::unix::fs::symlink(
+            fixture_dir().join(dir),
+            defaults_dir.join(dir).join("golden"),
+        )
+        .unwrap();
+    }
+    let cfg = golden.replace(
+        "prj_name = \"golden\"\n",
+        "prj_name = \"golden\"\npaths_relative_to_config = true\n",
+    );
+    fs::write(defaults_dir.join("golden-manifest.toml"), cfg).unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_cptra-imgtool"))
+        .current_dir(&cwd)
+        .args([
+            "create-auth-man",
+            "--cfg",
+            "../project/defaults/golden-manifest.toml",
+        ])
+        .args([
+            "--man",
+            "out/defaults-auth-manifest.bin",
+            "--allow-unauthenticated",
+        ])
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-man failed: {:?}",
+        output
+    );
+    check_manifest(&fs::read(cwd.join("out").join("defaults-auth-manifest.bin")).unwrap());
+}
+
+#[test]
+fn image_count_above_the_limit() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tool_dir(&tool_dir, &recorded_caliptra_manifest(&[]));
+    let manifest = tmp.path().join("out").join("golden-auth-manifest.bin");
+
+    let cfg = golden_cfg_with_images(tmp.path(), 0);
+    let mut toml = fs::read_to_string(&cfg).unwrap();
+    for fw_id in 1..=IMAGE_METADATA_MAX_COUNT + 1 {
+        toml.push_str(&format!(
+            "[[image_metadata_list]]\nfile = \"u-boot.bin\"\nsource = 1\nfw_id = {}\nignore_auth_check = false\nload_stage = 1\n\n",
+            fw_id
+        ));
+    }
+    fs::write(&cfg, toml).unwrap();
+
+    let output = imgtool_with_cfg("create-auth-man", &cfg)
+        .arg("--man")
+        .arg(&manifest)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("image_metadata_list has 128 images, the limit is 127"),
+        "{}",
+        stderr
+    );
+    assert!(!manifest.exists());
+
+    /* --max-images lowers the cap */
+    let cfg = golden_cfg_with_images(tmp.path(), IMAGES.len());
+    let output = imgtool_with_cfg("create-auth-man", &cfg)
+        .arg("--man")
+        .arg(&manifest)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .arg("--max-images")
+        .arg("3")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("has 4 images, the limit is 3 (--max-images)"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn renamed_tools_are_found_and_run() {
+    let tmp = tempfile::tempdir().unwrap();
+
+    /* Renamed stub tools in ./target/debug, found by probing without --tool-dir */
+    let tool_dir = tmp.path().join("target").join("debug");
+    write_tools(&tool_dir);
+    fs::rename(
+        tool_dir.join("caliptra-auth-manifest-app"),
+        tool_dir.join("vendor-auth-manifest"),
+    )
+    .unwrap();
+    fs::rename(tool_dir.join("xtask"), tool_dir.join("vendor-flash")).unwrap();
+
+    /* The manifest tool from the config, the flash tool from the environment */
+    let cfg = golden_cfg_with_images(tmp.path(), IMAGES.len());
+    let mut toml = fs::read_to_string(&cfg).unwrap();
+    toml.push_str("\n[tool_names]\nauth_manifest = \"vendor-auth-manifest\"\nflash = \"xtask\"\n");
+    fs::write(&cfg, toml).unwrap();
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let output = imgtool_with_cfg("create-auth-flash", &cfg)
+        .current_dir(tmp.path())
+        .arg("--flash")
+        .arg(&flash)
+        .env_remove("CPTRA_IMGTOOL_TOOL_DIR")
+        .env("CPTRA_IMGTOOL_FLASH_TOOL", "vendor-flash")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "create-auth-flash failed: {:?}",
+        output
+    );
+    check_manifest(&fs::read(tmp.path().join("out").join("golden-auth-manifest.bin")).unwrap());
+    assert!(tool_dir.join("xtask.args").is_file());
+    assert_eq!(fs::read(&flash).unwrap()[..4], *b"HSLF");
+
+    /* The probe error names the tools it looked for */
+    let output = imgtool_with_cfg("create-auth-flash", &cfg)
+        .current_dir(tmp.path())
+        .arg("--flash")
+        .arg(&flash)
+        .env_remove("CPTRA_IMGTOOL_TOOL_DIR")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("missing xtask"), "{}", stderr);
+}
+
+#[test]
+fn forked_magic_is_found_with_magic_or_cfg() {
+    const FORKED_MAGIC: u32 = 0x4154_4d58;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    let entries = IMAGES
+        .iter()
+        .map(|(id, file)| (*id, image_digest(file)))
+        .collect::<Vec<_>>();
+    let mut recorded = recorded_caliptra_manifest(&entries);
+    recorded[..4].copy_from_slice(&FORKED_MAGIC.to_le_bytes());
+    write_tool_dir(&tool_dir, &recorded);
+
+    let cfg = golden_cfg_with_images(tmp.path(), IMAGES.len());
+    let text = fs::read_to_string(&cfg).unwrap().replace(
+        "[manifest_config]\n",
+        "[manifest_config]\nexpected_magic = \"ATMX\"\n",
+    );
+    fs::write(&cfg, text).unwrap();
+
+    let flash = tmp.path().join("out").join("flash.bin");
+    let status = imgtool_with_cfg("create-auth-flash", &cfg)
+        .arg("--flash")
+        .arg(&flash)
+        .arg("--tool-dir")
+        .arg(&tool_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "create-auth-flash failed: {}", status);
+
+    let extract = |out: &str, args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cptra-imgtool"))
+            .arg("extract-manifest")
+            .arg("--flash")
+            .arg(&flash)
+            .arg("--out")
+            .arg(tmp.path().join(out))
+            .args(args)
+            .status()
+            .unwrap()
+            .success()
+    };
+    let cfg_arg = cfg.to_str().unwrap();
+    let prebuilt = fixture_dir().join("prebuilt");
+    let prebuilt_arg = prebuilt.to_str().unwrap();
+    assert!(!extract("caliptra.bin", &[]));
+    assert!(extract(
+        "cfg.bin",
+        &["--cfg", cfg_arg, "--prebuilt-dir", prebuilt_arg]
+    ));
+    assert!(extract("magic.bin", &["--magic", "ATMX"]));
+    assert_eq!(
+        read_u32(&fs::read(tmp.path().join("magic.bin")).unwrap(), 0),
+        FORKED_MAGIC
+    );
+
+    let diff = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cptra-imgtool"))
+            .arg("diff")
+            .arg("--man")
+            .arg(tmp.path().join("cfg.bin"))
+            .arg(tmp.path().join("magic.bin"))
+            .args(args)
+            .status()
+            .unwrap()
+            .success()
+    };
+    assert!(!diff(&[]));
+    assert!(diff(&["--magic", "0x41544d58"]));
+    assert!(diff(&["--cfg", cfg_arg, "--prebuilt-dir", prebuilt_arg]));
+}
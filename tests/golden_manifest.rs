@@ -0,0 +1,145 @@
+/*++
+
+Licensed under the Apache-2.0 license.
+
+File Name:
+
+   golden_manifest.rs
+
+Abstract:
+
+    Regression tests running create-auth-man on fixed inputs with stub tools.
+    The manifest and its JSON description are compared against committed golden
+    files, set
+    CPTRA_IMGTOOL_UPDATE_GOLDEN=1 to regenerate them.
+
+--*/
+
+#![cfg(unix)]
+
+mod common;
+
+use common::{create_auth_man, fixture_dir, pattern, recorded_caliptra_manifest};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const UPDATE_GOLDEN_ENV: &str = "CPTRA_IMGTOOL_UPDATE_GOLDEN";
+
+/* Stub tools returning a manifest with made up digests */
+fn write_tool_dir(dir: &Path) {
+    let entries = [1u32, 2, 3, 4]
+        .into_iter()
+        .map(|id| (id, pattern(48, id as u8)))
+        .collect::<Vec<_>>();
+    common::write_tool_dir(dir, &recorded_caliptra_manifest(&entries));
+}
+
+fn check_golden(name: &str, actual: &[u8]) {
+    let golden = fixture_dir().join(name);
+
+    if std::env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        fs::write(&golden, actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read(&golden).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read {:?}: {}, run with {}=1 to create it",
+            golden, e, UPDATE_GOLDEN_ENV
+        )
+    });
+    if let Some(offset) = expected.iter().zip(actual).position(|(e, a)| e != a) {
+        panic!(
+            "{} differs from the golden file at offset 0x{:x}, run with {}=1 to update it",
+            name, offset, UPDATE_GOLDEN_ENV
+        );
+    }
+    assert_eq!(
+        expected.len(),
+        actual.len(),
+        "{} length differs from the golden file",
+        name
+    );
+}
+
+#[test]
+fn manifest_matches_golden() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    let out_dir = tmp.path().join("out");
+    write_tool_dir(&tool_dir);
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let manifest = out_dir.join("golden-auth-manifest.bin");
+    let status = create_auth_man(&tool_dir, &manifest).status().unwrap();
+    assert!(status.success(), "create-auth-man failed: {}", status);
+
+    let caliptra_cfg = fs::read(out_dir.join("golden-caliptra-manifest.toml")).unwrap();
+    check_golden("golden-caliptra-manifest.toml", &caliptra_cfg);
+    check_golden("golden-auth-manifest.bin", &fs::read(&manifest).unwrap());
+}
+
+#[test]
+fn missing_output_dirs_are_created() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tool_dir = tmp.path().join("tools");
+    write_tool_dir(&tool_dir);
+
+    /* Derived from --man */
+    let out_dir = tmp.path().join("fresh").join("out");
+    let manifest = out_dir.join("golden-auth-manifest.bin");
+    let status = create_auth_man(&tool_dir, &manifest).status().unwrap();
+    assert!(status.success(), "create-auth-man failed: {}", status);
+    assert!(manifest.is_file());
+    assert!(out_dir.join("golden-caliptra-manifest.toml").is_file());
+
+    /* Given with --out-dir */
+    let work_dir = tmp.path().join("fresh").join("work");
+    let manifest = tmp.path().join("other").join("golden-auth-manifest.bin");
+    let status = create_auth_man(&tool_dir, &manifest)
+        .arg("--out-dir")
+        .arg(&work_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "create-auth-man failed: {}", status);
+    assert!(manifest.is_file());
+    assert!(work_dir.join("golden-caliptra-manifest.toml").is_file());
+}
+
+#[test]
+fn reproducible_builds_are_identical() {
+    let build = || {
+        let tmp = tempfile::tempdir().unwrap();
+        let tool_dir = tmp.path().join("tools");
+        write_tool_dir(&tool_dir);
+
+        let manifest = tmp.path().join("out").join("golden-auth-manifest.bin");
+        let status = create_auth_man(&tool_dir, &manifest)
+            .arg("--reproducible")
+            .status()
+            .unwrap();
+        assert!(status.success(), "create-auth-man failed: {}", status);
+        fs::read(&manifest).unwrap()
+    };
+
+    assert_eq!(build(), build());
+}
+
+#[test]
+fn manifest_info_json_matches_golden() {
+    let output = Command::new(env!("CARGO_BIN_EXE_cptra-imgtool"))
+        .arg("dump-auth-man")
+        .arg("--man")
+        .arg(fixture_dir().join("golden-auth-manifest.bin"))
+        .args(["--format", "json"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "dump-auth-man failed: {:?}",
+        output
+    );
+
+    check_golden("golden-manifest-info.json", &output.stdout);
+}